@@ -9,7 +9,7 @@ use crate::apu::noise::Noise;
 use crate::apu::queue::AudioQueue;
 use crate::apu::square::SquareWave;
 use crate::apu::wave::WaveChannel;
-use crate::cpu::EmulationMode;
+use crate::cpu::Model;
 
 const SAMPLE_RATE: usize = 95;
 const SEQUENCER_PERIOD: usize = 8192;
@@ -18,6 +18,11 @@ const NR50: u16 = 0xFF24;
 const NR51: u16 = 0xFF25;
 const NR52: u16 = 0xFF26;
 
+const DMG_WAVE_RAM: [u8; 16] = [
+    0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA,
+];
+
+#[derive(Clone)]
 pub struct AudioRegisters {
     nrx0: u8,
     nrx1: u8,
@@ -38,6 +43,7 @@ impl Default for AudioRegisters {
     }
 }
 
+#[derive(Clone)]
 pub struct Apu {
     clocks: usize,
     sample_clocks: usize,
@@ -53,12 +59,11 @@ pub struct Apu {
     master_vol_right: f32,
     nr50: u8,
     nr51: u8,
-    mode: EmulationMode,
 }
 
 impl Apu {
-    pub fn new(mode: EmulationMode) -> Self {
-        Apu {
+    pub fn new(model: Model) -> Self {
+        let mut apu = Apu {
             clocks: 0,
             sample_clocks: 0,
             channel1: SquareWave::new(),
@@ -73,8 +78,18 @@ impl Apu {
             master_vol_right: 1.0,
             nr50: 0,
             nr51: 0,
-            mode,
+        };
+
+        // Wave RAM isn't cleared at power on. CGBs come up with a fixed
+        // pattern, the contents on older models vary between units; this is a
+        // typical DMG one.
+        if !model.is_cgb() {
+            for (addr, value) in (0xFF30..).zip(DMG_WAVE_RAM.iter()) {
+                apu.channel3.set_byte(addr, *value);
+            }
         }
+
+        apu
     }
 
     pub fn tick(&mut self, cycles: usize) {
@@ -243,6 +258,11 @@ impl Apu {
     pub fn get_next_buffer(&mut self) -> (Option<Vec<f32>>, Option<Vec<f32>>) {
         self.samples.dequeue()
     }
+
+    /// Replaces the samples waiting to be played, returning the old ones.
+    pub fn swap_samples(&mut self, samples: AudioQueue) -> AudioQueue {
+        std::mem::replace(&mut self.samples, samples)
+    }
 }
 
 #[cfg(test)]
@@ -250,7 +270,7 @@ mod tests {
     use super::*;
 
     fn test_registers_with(d: u8) {
-        let mut apu = Apu::new(EmulationMode::Cgb);
+        let mut apu = Apu::new(Model::Cgb);
         apu.set_byte(NR50, 0x77);
 
         let targets = [
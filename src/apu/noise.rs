@@ -2,6 +2,7 @@ use crate::apu::AudioRegisters;
 
 const DIVISORS: [usize; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 
+#[derive(Clone)]
 pub struct Noise {
     counter: usize,
     clock_shift: u8,
@@ -5,6 +5,7 @@ pub const BUFFER_SIZE: usize = 735;
 
 const GAIN: f32 = 0.5;
 
+#[derive(Clone)]
 pub struct AudioQueue {
     pub queue_left: VecDeque<Vec<f32>>,
     pub queue_right: VecDeque<Vec<f32>>,
@@ -12,6 +13,12 @@ pub struct AudioQueue {
     current_right: Vec<f32>,
 }
 
+impl Default for AudioQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AudioQueue {
     pub fn new() -> Self {
         Self {
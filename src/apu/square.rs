@@ -7,6 +7,7 @@ const DUTY_TABLE: [[bool; 8]; 4] = [
     [false, true, true, true, true, true, true, false],
 ];
 
+#[derive(Clone)]
 pub struct LengthCounter {
     pub counter: usize,
     pub enabled: bool,
@@ -23,6 +24,7 @@ impl Default for LengthCounter {
 
 // ----------------------------------------------------------------------------------------------------
 
+#[derive(Clone)]
 pub struct SquareWave {
     pub output_volume: u8,
     registers: AudioRegisters,
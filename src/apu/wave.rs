@@ -1,5 +1,6 @@
 use crate::apu::AudioRegisters;
 
+#[derive(Clone)]
 pub struct WaveChannel {
     pub table: [u8; 32],
     wave_ram: [u8; 16],
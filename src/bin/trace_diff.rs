@@ -0,0 +1,39 @@
+// Compares an instruction trace against a gameboy-doctor reference log and reports
+// the first line at which they diverge.
+//
+// Usage: trace_diff <trace> <reference>
+
+use gbemu::cpu::trace::diff_traces;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} <trace> <reference>", args[0]);
+        process::exit(2);
+    }
+
+    let open = |path: &str| match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Could not open {}: {}", path, e);
+            process::exit(2);
+        }
+    };
+
+    match diff_traces(open(&args[1]), open(&args[2])) {
+        Ok(None) => println!("Traces match."),
+        Ok(Some(divergence)) => {
+            println!("{}", divergence);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error reading traces: {}", e);
+            process::exit(2);
+        }
+    }
+}
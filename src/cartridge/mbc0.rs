@@ -1,18 +1,25 @@
 use crate::cartridge::Mbc;
+use std::rc::Rc;
 
-const RAM_SIZE: usize = 0x2000;
 const RAM_OFFSET: usize = 0xA000;
 
+#[derive(Clone)]
 pub struct Mbc0 {
-    rom: Vec<u8>,
+    rom: Rc<[u8]>,
     ram: Vec<u8>,
 }
 
 impl Mbc0 {
     pub fn new(rom: Vec<u8>) -> Self {
+        let ram_size = match rom[0x0149] {
+            1 => 0x800,
+            2 => 0x2000,
+            _ => 0,
+        };
+
         Mbc0 {
-            rom,
-            ram: vec![0; RAM_SIZE],
+            rom: Rc::from(rom),
+            ram: vec![0; ram_size],
         }
     }
 }
@@ -21,7 +28,9 @@ impl Mbc for Mbc0 {
     fn get_byte(&mut self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x7FFF => self.rom[addr as usize],
-            0xA000..=0xBFFF => self.ram[addr as usize - RAM_OFFSET],
+            0xA000..=0xBFFF => self
+                .ram_offset(addr)
+                .map_or(0xFF, |offset| self.ram[offset]),
             _ => panic!(format!("Address {:#X} out of bounds.", addr)),
         }
     }
@@ -29,8 +38,36 @@ impl Mbc for Mbc0 {
     fn set_byte(&mut self, addr: u16, value: u8) {
         match addr {
             0x0000..=0x7FFF => (),
-            0xA000..=0xBFFF => self.ram[addr as usize - RAM_OFFSET] = value,
+            0xA000..=0xBFFF => {
+                if let Some(offset) = self.ram_offset(addr) {
+                    self.ram[offset] = value;
+                }
+            }
             _ => panic!(format!("Address {:#X} out of bounds.", addr)),
         }
     }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => 1,
+            _ => 0,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF if !self.ram.is_empty() => {
+                Some((addr as usize - RAM_OFFSET) % self.ram.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
 }
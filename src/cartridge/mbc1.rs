@@ -1,18 +1,20 @@
 use crate::cartridge::Mbc;
+use std::rc::Rc;
 
 const RAM_OFFSET: usize = 0xA000;
 const ROM_OFFSET: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 const ROM_BANK_SIZE: usize = 0x4000;
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum Mode {
     Mode0,
     Mode1,
 }
 
+#[derive(Clone)]
 pub struct Mbc1 {
-    rom: Vec<u8>,
+    rom: Rc<[u8]>,
     ram: Vec<u8>,
     ram_enabled: bool,
     mode: Mode,
@@ -39,7 +41,7 @@ impl Mbc1 {
         };
 
         Mbc1 {
-            rom: data,
+            rom: Rc::from(data),
             ram: vec![0; ram_size],
             ram_enabled: false,
             mode: Mode::Mode0,
@@ -120,4 +122,36 @@ impl Mbc for Mbc1 {
             _ => panic!("Address out of bounds."),
         }
     }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x3FFF => match self.mode {
+                Mode::Mode0 => 0,
+                Mode::Mode1 => (self.bank2 << 5) as u16,
+            },
+            0x4000..=0x7FFF => ((self.bank2 << 5) | self.bank1) as u16,
+            _ => 0,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF if self.ram_enabled => {
+                let bank = match self.mode {
+                    Mode::Mode0 => 0x0,
+                    Mode::Mode1 => self.bank2,
+                };
+                Some(bank as usize * RAM_BANK_SIZE + (addr as usize - RAM_OFFSET))
+            }
+            _ => None,
+        }
+    }
+
+    fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
 }
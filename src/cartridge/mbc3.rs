@@ -1,4 +1,5 @@
 use crate::cartridge::Mbc;
+use std::rc::Rc;
 use std::time;
 
 const RAM_OFFSET: usize = 0xA000;
@@ -6,11 +7,13 @@ const ROM_OFFSET: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 const ROM_BANK_SIZE: usize = 0x4000;
 
+#[derive(Clone)]
 enum Mode {
     Ram,
     Rtc,
 }
 
+#[derive(Clone)]
 struct Rtc {
     seconds: u8,
     minutes: u8,
@@ -72,8 +75,9 @@ impl Rtc {
     }
 }
 
+#[derive(Clone)]
 pub struct Mbc3 {
-    rom: Vec<u8>,
+    rom: Rc<[u8]>,
     ram: Vec<u8>,
     rom_bank: u8,
     ram_bank: u8,
@@ -96,7 +100,7 @@ impl Mbc3 {
         };
 
         Mbc3 {
-            rom: data,
+            rom: Rc::from(data),
             ram: vec![0xFF; ram_size],
             rom_bank: 1,
             ram_bank: 0,
@@ -183,4 +187,28 @@ impl Mbc for Mbc3 {
             _ => panic!("Address out of bounds {:#X}.", addr),
         }
     }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => self.rom_bank as u16,
+            _ => 0,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        match (addr, &self.mode) {
+            (0xA000..=0xBFFF, Mode::Ram) if self.ram_or_rtc_enabled => {
+                Some(self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - RAM_OFFSET))
+            }
+            _ => None,
+        }
+    }
+
+    fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
 }
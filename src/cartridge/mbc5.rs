@@ -1,12 +1,14 @@
 use crate::cartridge::Mbc;
+use std::rc::Rc;
 
 const RAM_OFFSET: usize = 0xA000;
 const ROM_OFFSET: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 const ROM_BANK_SIZE: usize = 0x4000;
 
+#[derive(Clone)]
 pub struct Mbc5 {
-    rom: Vec<u8>,
+    rom: Rc<[u8]>,
     ram: Vec<u8>,
     rom_bank: u16,
     ram_bank: u8,
@@ -25,8 +27,8 @@ impl Mbc5 {
         };
 
         Mbc5 {
-            rom: data,
-            ram: vec![0xFF; RAM_BANK_SIZE * 16],
+            rom: Rc::from(data),
+            ram: vec![0xFF; ram_size],
             rom_bank: 1,
             ram_bank: 0,
             ram_enabled: false,
@@ -46,8 +48,8 @@ impl Mbc for Mbc5 {
                 if !self.ram_enabled {
                     return 0x00;
                 }
-                let addr = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - RAM_OFFSET);
-                self.ram[addr]
+                self.ram_offset(addr)
+                    .map_or(0xFF, |offset| self.ram[offset])
             }
             _ => panic!("Address out of bounds."),
         }
@@ -69,13 +71,37 @@ impl Mbc for Mbc5 {
             }
             0x6000..=0x7FFF => (),
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    let addr =
-                        self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - RAM_OFFSET);
-                    self.ram[addr] = value;
+                if let Some(offset) = self.ram_offset(addr) {
+                    self.ram[offset] = value;
                 }
             }
             _ => panic!("Address out of bounds. {:#X}", addr),
         }
     }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => self.rom_bank,
+            _ => 0,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                let offset = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - RAM_OFFSET);
+                // Bank bits beyond the RAM that is fitted are ignored.
+                Some(offset % self.ram.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
 }
@@ -6,6 +6,12 @@ pub mod mbc5;
 pub trait Mbc {
     fn get_byte(&mut self, addr: u16) -> u8;
     fn set_byte(&mut self, addr: u16, value: u8);
+    /// The ROM bank currently mapped at `addr`.
+    fn rom_bank(&self, addr: u16) -> u16;
+    /// The offset into cartridge RAM that `addr` currently maps to, if any.
+    fn ram_offset(&self, addr: u16) -> Option<usize>;
+    fn ram_size(&self) -> usize;
+    fn box_clone(&self) -> Box<dyn Mbc>;
 }
 
 use crate::cartridge::mbc0::Mbc0;
@@ -15,10 +21,21 @@ use crate::cartridge::mbc5::Mbc5;
 
 pub struct Cartridge {
     mbc: Box<dyn Mbc>,
+    rom_size: usize,
+}
+
+impl Clone for Cartridge {
+    fn clone(&self) -> Self {
+        Self {
+            mbc: self.mbc.box_clone(),
+            rom_size: self.rom_size,
+        }
+    }
 }
 
 impl Cartridge {
     pub fn new(data: Vec<u8>) -> Self {
+        let rom_size = data.len();
         let mbc: Box<dyn Mbc> = match data[0x147] {
             0x00 | 0x08 | 0x09 => Box::from(Mbc0::new(data)),
             0x01..=0x03 => Box::from(Mbc1::new(data)),
@@ -27,7 +44,7 @@ impl Cartridge {
             _ => panic!("Unsupported MBC type."),
         };
 
-        Self { mbc }
+        Self { mbc, rom_size }
     }
 
     pub fn get_byte(&mut self, addr: u16) -> u8 {
@@ -37,4 +54,26 @@ impl Cartridge {
     pub fn set_byte(&mut self, addr: u16, value: u8) {
         self.mbc.set_byte(addr, value);
     }
+
+    pub fn rom_bank(&self, addr: u16) -> u16 {
+        self.mbc.rom_bank(addr)
+    }
+
+    /// The offset into the ROM image that `addr` currently maps to.
+    pub fn rom_offset(&self, addr: u16) -> usize {
+        let offset = self.rom_bank(addr) as usize * 0x4000 + (addr as usize & 0x3FFF);
+        offset % self.rom_size
+    }
+
+    pub fn ram_offset(&self, addr: u16) -> Option<usize> {
+        self.mbc.ram_offset(addr)
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom_size
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.mbc.ram_size()
+    }
 }
@@ -0,0 +1,529 @@
+// Shadow call stack.
+//
+// Every CALL, RST and interrupt dispatch pushes a frame that remembers where the
+// return address was written (the stack pointer after the push). A RET only pops
+// a frame when it reads its return address from that same slot. Anything else
+// means the program moved the stack behind our back (`LD SP`, `POP` of a return
+// address, `PUSH` + `RET` jump tables, ...), which is reported as a desync
+// instead of silently producing a wrong backtrace.
+//
+// Symbols are read from `.sym` files as produced by RGBDS and used by BGB and
+// no$gmb, one `BB:AAAA Label` entry per line.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameKind::Call => write!(f, "call"),
+            FrameKind::Rst => write!(f, "rst"),
+            FrameKind::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+/// A bank-qualified code address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub bank: u16,
+    pub addr: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Where control was transferred to.
+    pub target: Location,
+    /// The address execution resumes at once the frame returns.
+    pub return_to: Location,
+    /// Where the return address lives on the stack.
+    pub sp: u16,
+}
+
+/// How the shadow stack and the real stack went out of step.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Desync {
+    /// A RET popped frames that were still on the shadow stack, e.g. after `LD SP`.
+    Unwound { pc: u16, frames: usize },
+    /// The return address of the innermost frame was overwritten before the RET.
+    ReturnAddressChanged { pc: u16, expected: u16, actual: u16 },
+    /// A CALL reused stack space of frames that never returned.
+    Overwritten { pc: u16, frames: usize },
+    /// The shadow stack grew beyond its maximum depth and lost its oldest frames.
+    Truncated { frames: usize },
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Desync::Unwound { pc, frames } => {
+                write!(f, "RET at {:04X} discarded {} frame(s)", pc, frames)
+            }
+            Desync::ReturnAddressChanged {
+                pc,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "RET at {:04X} returned to {:04X} instead of {:04X}",
+                pc, actual, expected
+            ),
+            Desync::Overwritten { pc, frames } => write!(
+                f,
+                "call from {:04X} overwrote {} unreturned frame(s)",
+                pc, frames
+            ),
+            Desync::Truncated { frames } => write!(f, "dropped {} oldest frame(s)", frames),
+        }
+    }
+}
+
+/// Labels loaded from a `.sym` file.
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: BTreeMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    /// Parses the contents of a `.sym` file. Lines that don't look like
+    /// `BB:AAAA Label` are skipped, as are comments starting with `;`.
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = BTreeMap::new();
+
+        for line in text.lines() {
+            let line = match line.find(';') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+
+            let mut location = location.splitn(2, ':');
+            let bank = location
+                .next()
+                .and_then(|b| u16::from_str_radix(b, 16).ok());
+            let addr = location
+                .next()
+                .and_then(|a| u16::from_str_radix(a, 16).ok());
+
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.insert((bank, addr), name.to_string());
+            }
+        }
+
+        Self { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The closest label at or before `location` in the same bank, together
+    /// with the offset from it.
+    pub fn lookup(&self, location: Location) -> Option<(&str, u16)> {
+        self.symbols
+            .range((location.bank, 0)..=(location.bank, location.addr))
+            .next_back()
+            .map(|((_, addr), name)| (name.as_str(), location.addr - addr))
+    }
+
+    /// Formats `location` as `Label` or `Label+$offset`.
+    pub fn describe(&self, location: Location) -> Option<String> {
+        self.lookup(location).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+${:X}", name, offset),
+        })
+    }
+}
+
+pub struct CallStack {
+    frames: Vec<Frame>,
+    pub symbols: SymbolTable,
+    pub max_depth: usize,
+    /// Total number of desyncs detected so far.
+    pub desync_count: usize,
+    /// The most recent desyncs, oldest first.
+    desyncs: VecDeque<Desync>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self {
+            frames: vec![],
+            symbols: SymbolTable::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            desync_count: 0,
+            desyncs: VecDeque::new(),
+        }
+    }
+
+    pub fn with_symbols(symbols: SymbolTable) -> Self {
+        Self {
+            symbols,
+            ..Self::new()
+        }
+    }
+
+    /// The frames on the shadow stack, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Replaces the frames, e.g. when the machine state is restored.
+    pub fn set_frames(&mut self, frames: Vec<Frame>) {
+        self.frames = frames;
+    }
+
+    pub fn desyncs(&self) -> impl Iterator<Item = &Desync> {
+        self.desyncs.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.desyncs.clear();
+        self.desync_count = 0;
+    }
+
+    fn desync(&mut self, desync: Desync) {
+        if self.desyncs.len() == 16 {
+            self.desyncs.pop_front();
+        }
+        self.desyncs.push_back(desync);
+        self.desync_count += 1;
+    }
+
+    /// Records a frame right after its return address has been pushed.
+    pub fn enter(&mut self, frame: Frame) {
+        // The stack grows downwards, so frames at or below the new return address
+        // were abandoned without returning.
+        let live = self.frames.iter().take_while(|f| f.sp > frame.sp).count();
+        if live < self.frames.len() {
+            let frames = self.frames.len() - live;
+            self.frames.truncate(live);
+            self.desync(Desync::Overwritten {
+                pc: frame.return_to.addr,
+                frames,
+            });
+        }
+
+        if self.frames.len() == self.max_depth {
+            let frames = self.max_depth / 4 + 1;
+            self.frames.drain(..frames);
+            self.desync(Desync::Truncated { frames });
+        }
+
+        self.frames.push(frame);
+    }
+
+    /// Records a RET at `pc` that read `return_addr` from `sp`.
+    pub fn leave(&mut self, pc: u16, sp: u16, return_addr: u16) {
+        let top = match self.frames.last() {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        // Returning through a value pushed by the program itself is a jump, not
+        // the end of a frame.
+        if sp < top.sp {
+            return;
+        }
+
+        if sp == top.sp {
+            let expected = top.return_to.addr;
+            self.frames.pop();
+            if expected != return_addr {
+                self.desync(Desync::ReturnAddressChanged {
+                    pc,
+                    expected,
+                    actual: return_addr,
+                });
+            }
+            return;
+        }
+
+        let live = self.frames.iter().take_while(|f| f.sp > sp).count();
+        let matched = live < self.frames.len() && self.frames[live].sp == sp;
+        let frames = self.frames.len() - live - matched as usize;
+        self.frames.truncate(live);
+        self.desync(Desync::Unwound { pc, frames });
+    }
+
+    /// Builds a backtrace for code currently executing at `pc` with the stack
+    /// pointer at `sp`.
+    pub fn backtrace(&self, pc: Location, sp: u16) -> Backtrace {
+        // Frames below the stack pointer have already been popped by the program.
+        let live = self.frames.iter().take_while(|f| f.sp >= sp).count();
+
+        let mut entries = vec![BacktraceEntry {
+            location: pc,
+            kind: None,
+            symbol: self.symbols.describe(pc),
+        }];
+
+        for frame in self.frames[..live].iter().rev() {
+            entries.push(BacktraceEntry {
+                location: frame.return_to,
+                kind: Some(frame.kind),
+                symbol: self.symbols.describe(frame.return_to),
+            });
+        }
+
+        Backtrace {
+            entries,
+            stale_frames: self.frames.len() - live,
+            desync_count: self.desync_count,
+            last_desync: self.desyncs.back().cloned(),
+        }
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct BacktraceEntry {
+    pub location: Location,
+    /// How the frame below this one was entered. `None` for the current position.
+    pub kind: Option<FrameKind>,
+    pub symbol: Option<String>,
+}
+
+/// The innermost position first, followed by the return address of every frame.
+pub struct Backtrace {
+    pub entries: Vec<BacktraceEntry>,
+    /// Frames whose return address was popped without a RET.
+    pub stale_frames: usize,
+    pub desync_count: usize,
+    pub last_desync: Option<Desync>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            write!(
+                f,
+                "#{:<3} {:02X}:{:04X}",
+                i, entry.location.bank, entry.location.addr
+            )?;
+            if let Some(symbol) = &entry.symbol {
+                write!(f, " {}", symbol)?;
+            }
+            if let Some(kind) = entry.kind {
+                write!(f, " ({})", kind)?;
+            }
+            writeln!(f)?;
+        }
+
+        if self.stale_frames > 0 {
+            writeln!(
+                f,
+                "{} frame(s) were popped without returning",
+                self.stale_frames
+            )?;
+        }
+
+        if let Some(desync) = &self.last_desync {
+            writeln!(
+                f,
+                "Shadow stack desynced {} time(s), last: {}",
+                self.desync_count, desync
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn loc(addr: u16) -> Location {
+        Location { bank: 0, addr }
+    }
+
+    fn frame(kind: FrameKind, return_to: u16, sp: u16) -> Frame {
+        Frame {
+            kind,
+            target: loc(0),
+            return_to: loc(return_to),
+            sp,
+        }
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = SymbolTable::parse(
+            "; File generated by rgblink\n00:0150 Main\n01:4000 Bank1Func ; comment\nbogus\n00:0200 Sub\n",
+        );
+
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.describe(loc(0x150)), Some("Main".to_string()));
+        assert_eq!(symbols.describe(loc(0x1A0)), Some("Main+$50".to_string()));
+        assert_eq!(symbols.describe(loc(0x100)), None);
+        assert_eq!(
+            symbols.describe(Location {
+                bank: 1,
+                addr: 0x4010
+            }),
+            Some("Bank1Func+$10".to_string())
+        );
+        assert_eq!(
+            symbols.describe(Location {
+                bank: 2,
+                addr: 0x4010
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_desync_detection() {
+        let mut stack = CallStack::new();
+
+        // PUSH + RET jump tables don't touch the frames.
+        stack.enter(frame(FrameKind::Call, 0x0106, 0xDFFC));
+        stack.leave(0x0300, 0xDFFA, 0x1234);
+        assert_eq!(stack.frames().len(), 1);
+        assert_eq!(stack.desync_count, 0);
+
+        // An overwritten return address still pops the frame.
+        stack.leave(0x0300, 0xDFFC, 0x4321);
+        assert!(stack.frames().is_empty());
+        assert_eq!(
+            stack.desyncs().last(),
+            Some(&Desync::ReturnAddressChanged {
+                pc: 0x0300,
+                expected: 0x0106,
+                actual: 0x4321
+            })
+        );
+
+        // Returning from a frame further up discards the ones in between.
+        stack.enter(frame(FrameKind::Call, 0x0106, 0xDFFC));
+        stack.enter(frame(FrameKind::Rst, 0x0201, 0xDFFA));
+        stack.enter(frame(FrameKind::Interrupt, 0x0039, 0xDFF8));
+        stack.leave(0x0300, 0xDFFC, 0x0106);
+        assert!(stack.frames().is_empty());
+        assert_eq!(
+            stack.desyncs().last(),
+            Some(&Desync::Unwound {
+                pc: 0x0300,
+                frames: 2
+            })
+        );
+
+        // Resetting SP and calling again overwrites abandoned frames.
+        stack.enter(frame(FrameKind::Call, 0x0106, 0xDFFC));
+        stack.enter(frame(FrameKind::Call, 0x0206, 0xDFFA));
+        stack.enter(frame(FrameKind::Call, 0x0150, 0xDFFC));
+        assert_eq!(stack.frames().len(), 1);
+        assert_eq!(stack.desync_count, 3);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut stack = CallStack::new();
+        stack.max_depth = 8;
+
+        for i in 0..20 {
+            stack.enter(frame(FrameKind::Rst, 0x39, 0xFFFE - 2 * i));
+        }
+
+        assert!(stack.frames().len() <= 8);
+        assert_eq!(stack.frames().last().unwrap().sp, 0xFFFE - 38);
+        assert!(matches!(
+            stack.desyncs().last(),
+            Some(Desync::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cpu_backtrace() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD SP,$DFFE; CALL $0200; JR -2
+        rom[0x100..0x108].copy_from_slice(&[0x31, 0xFE, 0xDF, 0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        // 0200: RST $38; RET
+        rom[0x200..0x202].copy_from_slice(&[0xFF, 0xC9]);
+        // 0038: RET
+        rom[0x38] = 0xC9;
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.set_callstack(Some(CallStack::with_symbols(SymbolTable::parse(
+            "00:0038 Rst38\n00:0100 Main\n00:0200 Sub\n",
+        ))));
+
+        while cpu.pc != 0x38 {
+            cpu.tick();
+        }
+
+        let backtrace = cpu.backtrace().unwrap();
+        let locations: Vec<u16> = backtrace.entries.iter().map(|e| e.location.addr).collect();
+        assert_eq!(locations, vec![0x38, 0x201, 0x106]);
+        assert_eq!(
+            backtrace.to_string(),
+            "#0   00:0038 Rst38\n#1   00:0201 Sub+$1 (rst)\n#2   00:0106 Main+$6 (call)\n"
+        );
+
+        while cpu.pc != 0x106 {
+            cpu.tick();
+        }
+
+        let backtrace = cpu.backtrace().unwrap();
+        assert_eq!(backtrace.entries.len(), 1);
+        assert_eq!(backtrace.desync_count, 0);
+    }
+
+    #[test]
+    fn test_ram_code_banks() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        // 0100: LD SP,$DFFE; LD A,$02; LDH ($70),A; CALL $D000
+        rom[0x100..0x10A]
+            .copy_from_slice(&[0x31, 0xFE, 0xDF, 0x3E, 0x02, 0xE0, 0x70, 0xCD, 0x00, 0xD0]);
+        // D000 in WRAM bank 2: CALL $FF80; FF80: RET
+        let mut cpu = Cpu::new(rom);
+        cpu.mmu.set_byte(0xFF70, 0x02);
+        for (i, &byte) in [0xCD, 0x80, 0xFF].iter().enumerate() {
+            cpu.mmu.set_byte(0xD000 + i as u16, byte);
+        }
+        cpu.mmu.set_byte(0xFF70, 0x01);
+        cpu.mmu.set_byte(0xFF80, 0xC9);
+
+        cpu.pc = 0x100;
+        cpu.set_callstack(Some(CallStack::with_symbols(SymbolTable::parse(
+            "00:0100 Main\n01:D000 Bank1Func\n02:D000 Bank2Func\n00:FF80 HramFunc\n",
+        ))));
+
+        while cpu.pc != 0xFF80 {
+            cpu.tick();
+        }
+
+        assert_eq!(
+            cpu.backtrace().unwrap().to_string(),
+            "#0   00:FF80 HramFunc\n#1   02:D003 Bank2Func+$3 (call)\n#2   00:010A Main+$A (call)\n"
+        );
+    }
+}
@@ -1,10 +1,24 @@
 // References: https://github.com/LIJI32/SameBoy/blob/master/Core/sm83_cpu.c
 
+pub mod callstack;
 pub mod opcodes;
-
+pub mod profiler;
+pub mod rewind;
+pub mod trace;
+
+use crate::cpu::callstack::{Backtrace, CallStack, Frame, FrameKind, Location};
+use crate::cpu::profiler::{Profiler, PseudoFrame};
+use crate::cpu::rewind::History;
+use crate::cpu::trace::{TraceEntry, Tracer};
 use crate::events::Event;
+use crate::gpu::compat::{self, PaletteCombo};
 use crate::joypad::Key;
+use crate::memory::bootrom::Bootrom;
+use crate::memory::bus::Bus;
+use crate::memory::cdl::Access;
 use crate::memory::mmu::{HdmaType, Mmu};
+use crate::utils::named_enum;
+use std::collections::HashSet;
 
 const MAX_CYCLES: usize = 69905;
 
@@ -14,13 +28,62 @@ pub enum EmulationMode {
     Cgb,
 }
 
+/// The hardware being emulated. `EmulationMode` is the feature set the
+/// components expose, which for a CGB or AGB running a DMG game is `Dmg`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Model {
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    /// The model a cartridge asks for: CGB if it supports it, DMG otherwise.
+    pub fn from_header(data: &[u8]) -> Self {
+        if (data[0x0143] & 0x80) != 0 {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    pub fn emu_mode(self) -> EmulationMode {
+        if self.is_cgb() {
+            EmulationMode::Cgb
+        } else {
+            EmulationMode::Dmg
+        }
+    }
+}
+
+named_enum!(Model, "model", {
+    Dmg => "dmg",
+    Mgb => "mgb",
+    Sgb => "sgb",
+    Sgb2 => "sgb2",
+    Cgb => "cgb",
+    Agb => "agb",
+});
+
 #[derive(Debug, Clone)]
 pub enum CgbSpeed {
     Normal,
     Double,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CgbMode {
     pub speed: CgbSpeed,
     pub prepare_speed_switch: u8,
@@ -88,76 +151,270 @@ pub struct Cpu {
     sp: u16,
     pub mmu: Mmu,
     pub cycles: usize,
+    pub total_cycles: u64,
     ime: bool,
     halted: bool,
+    model: Model,
     emu_mode: EmulationMode,
     stopped: bool,
+    /// Set while in STOP mode proper, when the system clock is halted. Not set
+    /// during a CGB speed switch.
+    clock_stopped: bool,
+    locked: bool,
+    lock_event: bool,
     halt_bug: bool,
     ime_set_pending: bool,
     just_halted: bool,
 
     event_cycles: usize,
     audio_flag: bool,
+
+    tracer: Option<Tracer>,
+    callstack: Option<CallStack>,
+    profiler: Option<Profiler>,
+    history: Option<History>,
+    breakpoints: HashSet<u16>,
+    bus: Option<Box<dyn Bus>>,
 }
 
 impl Cpu {
     pub fn new(data: Vec<u8>) -> Self {
-        let emu_mode = if (data[0x0143] & 0x80) != 0 {
-            EmulationMode::Cgb
-        } else {
-            EmulationMode::Dmg
-        };
+        let model = Model::from_header(&data);
+        Self::with_model(data, model)
+    }
+
+    /// Powers on with `bootrom` mapped and the CPU at 0000, so that the boot
+    /// ROM sets up the hardware and hands over to the cartridge by writing
+    /// FF50. The model is the one the boot ROM was dumped from.
+    pub fn with_bootrom(data: Vec<u8>, mut bootrom: Bootrom) -> Self {
+        let mut cpu = Self::with_model(data, bootrom.model());
+        bootrom.activate();
+        cpu.mmu.bootrom = bootrom;
+        cpu.mmu.timer.divider.counter = 0;
+        cpu.ime = false;
+        cpu
+    }
+
+    /// Emulates `model` regardless of what the cartridge asks for. A CGB or AGB
+    /// runs DMG games in compatibility mode.
+    pub fn with_model(data: Vec<u8>, model: Model) -> Self {
+        let emu_mode = model.emu_mode();
 
         Cpu {
             r: [0; 8],
             pc: 0,
             sp: 0,
-            mmu: Mmu::new(data, emu_mode.clone()),
+            mmu: Mmu::new(data, model),
             cycles: 0,
+            total_cycles: 0,
             ime: true,
             halted: false,
+            model,
             emu_mode,
             stopped: false,
+            clock_stopped: false,
+            locked: false,
+            lock_event: false,
             halt_bug: false,
             ime_set_pending: false,
             just_halted: false,
             event_cycles: 0,
             audio_flag: true,
+            tracer: None,
+            callstack: None,
+            profiler: None,
+            history: None,
+            breakpoints: HashSet::new(),
+            bus: None,
         }
     }
 
-    pub fn keydown(&mut self, key: usize) {
-        match key {
-            0 => self.mmu.joypad.press_key(Key::Right),
-            1 => self.mmu.joypad.press_key(Key::Left),
-            2 => self.mmu.joypad.press_key(Key::Up),
-            3 => self.mmu.joypad.press_key(Key::Down),
-            4 => self.mmu.joypad.press_key(Key::BtnA),
-            5 => self.mmu.joypad.press_key(Key::BtnB),
-            6 => self.mmu.joypad.press_key(Key::Select),
-            7 => self.mmu.joypad.press_key(Key::Start),
-            _ => panic!("Unknown key."),
+    /// Routes every memory access through `bus` instead of the `Mmu`. The other
+    /// components are no longer ticked while a bus is attached.
+    pub fn attach_bus(&mut self, bus: Box<dyn Bus>) {
+        self.bus = Some(bus);
+    }
+
+    pub fn detach_bus(&mut self) -> Option<Box<dyn Bus>> {
+        self.bus.take()
+    }
+
+    /// Enables (or with `None`, disables) instruction tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// Enables (or with `None`, disables) shadow call-stack tracking.
+    pub fn set_callstack(&mut self, callstack: Option<CallStack>) {
+        self.callstack = callstack;
+    }
+
+    pub fn callstack(&self) -> Option<&CallStack> {
+        self.callstack.as_ref()
+    }
+
+    pub fn callstack_mut(&mut self) -> Option<&mut CallStack> {
+        self.callstack.as_mut()
+    }
+
+    /// Enables (or with `None`, disables) the cycle profiler. Per-function
+    /// accounting relies on the shadow call stack, so call-stack tracking is
+    /// switched on as well if it isn't already.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        if profiler.is_some() && self.callstack.is_none() {
+            self.callstack = Some(CallStack::new());
         }
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    /// Enables (or with `None`, disables) the history needed for `step_back` and
+    /// `continue_back`.
+    pub fn set_history(&mut self, history: Option<History>) {
+        self.history = history;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn breakpoints(&self) -> &HashSet<u16> {
+        &self.breakpoints
+    }
+
+    /// Runs until the next instruction to be executed is at a breakpoint, for at
+    /// most `max_steps` steps. Returns true if a breakpoint was reached.
+    pub fn continue_forward(&mut self, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            self.tick();
+            if !self.halted && !self.stopped && self.breakpoints.contains(&self.pc) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The current position followed by every frame on the shadow call stack,
+    /// innermost first. `None` if call-stack tracking is disabled.
+    pub fn backtrace(&self) -> Option<Backtrace> {
+        let pc = self.location(self.pc);
+        self.callstack
+            .as_ref()
+            .map(|callstack| callstack.backtrace(pc, self.sp))
+    }
+
+    fn location(&self, addr: u16) -> Location {
+        Location {
+            bank: self.mmu.bank(addr),
+            addr,
+        }
+    }
+
+    /// Records a frame on the shadow call stack once `return_to` has been pushed.
+    fn enter_frame(&mut self, kind: FrameKind, return_to: u16) {
+        if self.callstack.is_none() {
+            return;
+        }
+
+        let frame = Frame {
+            kind,
+            target: self.location(self.pc),
+            return_to: self.location(return_to),
+            sp: self.sp,
+        };
+
+        if let Some(callstack) = &mut self.callstack {
+            callstack.enter(frame);
+        }
+    }
+
+    /// Presses the key with the given index in `Key::ALL`. Unknown indices
+    /// are ignored.
+    pub fn keydown(&mut self, key: usize) {
+        self.player_keydown(0, key);
     }
 
     pub fn keyup(&mut self, key: usize) {
-        match key {
-            0 => self.mmu.joypad.release_key(Key::Right),
-            1 => self.mmu.joypad.release_key(Key::Left),
-            2 => self.mmu.joypad.release_key(Key::Up),
-            3 => self.mmu.joypad.release_key(Key::Down),
-            4 => self.mmu.joypad.release_key(Key::BtnA),
-            5 => self.mmu.joypad.release_key(Key::BtnB),
-            6 => self.mmu.joypad.release_key(Key::Select),
-            7 => self.mmu.joypad.release_key(Key::Start),
-            _ => panic!("Unknown key."),
+        self.player_keyup(0, key);
+    }
+
+    /// Presses a key on one of up to four controllers, for SGB games that
+    /// request several with MLT_REQ.
+    pub fn player_keydown(&mut self, player: usize, key: usize) {
+        if let Some(key) = Key::from_index(key) {
+            self.press(player, key);
         }
     }
 
+    pub fn player_keyup(&mut self, player: usize, key: usize) {
+        if let Some(key) = Key::from_index(key) {
+            self.release(player, key);
+        }
+    }
+
+    pub fn press(&mut self, player: usize, key: Key) {
+        self.history_input(player, key.index(), true);
+        self.mmu.joypad.press_player_key(player, key);
+    }
+
+    pub fn release(&mut self, player: usize, key: Key) {
+        self.history_input(player, key.index(), false);
+        self.mmu.joypad.release_player_key(player, key);
+    }
+
+    /// Sets the full button state of a controller, for frontends that poll
+    /// their input once per frame. See `Joypad::set_buttons` for the format.
+    pub fn set_buttons(&mut self, player: usize, state: u8) {
+        let changed = self.mmu.joypad.buttons(player) ^ state;
+        for key in Key::ALL
+            .iter()
+            .copied()
+            .filter(|key| changed & key.mask() != 0)
+        {
+            if state & key.mask() != 0 {
+                self.press(player, key);
+            } else {
+                self.release(player, key);
+            }
+        }
+    }
+
+    pub fn buttons(&mut self, player: usize) -> u8 {
+        self.mmu.joypad.buttons(player)
+    }
+
     pub fn screen(&self) -> *const u8 {
         self.mmu.screen()
     }
 
+    /// The 256x224 picture with the SGB border, when emulating an SGB.
+    pub fn sgb_screen(&self) -> Option<*const u8> {
+        self.mmu.sgb.as_ref().map(|sgb| sgb.screen())
+    }
+
     pub fn run_till_event(&mut self, max_cycles: usize) -> Event {
         let max_cycles = match self.mmu.cgb_mode.speed {
             CgbSpeed::Normal => max_cycles,
@@ -172,6 +429,11 @@ impl Cpu {
                 return Event::VBlank;
             }
 
+            if self.lock_event {
+                self.lock_event = false;
+                return Event::CpuLocked;
+            }
+
             if let (Some(left), Some(right)) = self.mmu.apu.get_next_buffer() {
                 return Event::AudioBufferFull(left, right);
             }
@@ -189,10 +451,23 @@ impl Cpu {
     }
 
     pub fn tick(&mut self) -> usize {
+        if self.history.is_some() {
+            self.history_tick();
+        }
+
         self.cycles = 0;
+        let start = self.total_cycles;
+
+        if self.locked {
+            self.cycle_idle();
+            self.charge(PseudoFrame::Halt, start);
+            return self.cycles;
+        }
 
         if self.stopped {
-            return self.stop_tick();
+            self.stop_tick();
+            self.charge(PseudoFrame::Halt, start);
+            return self.cycles;
         }
 
         if self.halted {
@@ -200,8 +475,14 @@ impl Cpu {
         }
 
         match self.mmu.hdma.hdma_type {
-            HdmaType::GPDma => self.gdma_tick(),
-            HdmaType::HBlankDma if self.mmu.gpu.hdma_flag => self.hdma_tick(),
+            HdmaType::GPDma => {
+                self.gdma_tick();
+                self.charge(PseudoFrame::Dma, start);
+            }
+            HdmaType::HBlankDma if self.mmu.gpu.hdma_flag => {
+                self.hdma_tick();
+                self.charge(PseudoFrame::Dma, start);
+            }
             _ => self.cpu_tick(),
         }
 
@@ -211,10 +492,7 @@ impl Cpu {
     fn cpu_tick(&mut self) {
         self.just_halted = false;
 
-        let ie = self.mmu.ie;
-        let irr = self.mmu.get_byte(0xFF0F);
-
-        let ints_pending = ie & irr & 0x1F;
+        let ints_pending = self.pending_interrupts();
 
         let ime = self.ime;
         if self.ime_set_pending {
@@ -223,11 +501,25 @@ impl Cpu {
         }
 
         if ime && ints_pending != 0 {
+            let start = self.total_cycles;
             self.service_pending_interrupts();
+            self.charge(PseudoFrame::Irq, start);
             return;
         }
 
-        let opcode = self.fetch();
+        if self.tracer.is_some() {
+            self.trace_instruction();
+        }
+
+        if let Some(history) = &mut self.history {
+            history.executed = Some(self.pc);
+        }
+
+        if self.profiler.is_some() {
+            self.begin_profiling();
+        }
+
+        let opcode = self.fetch_opcode();
 
         if self.halt_bug {
             self.halt_bug = false;
@@ -235,16 +527,52 @@ impl Cpu {
         }
 
         self.decode_exec(opcode);
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end(self.total_cycles);
+        }
+    }
+
+    fn trace_instruction(&mut self) {
+        let bank = self.mmu.cartridge.rom_bank(self.pc);
+
+        match &self.tracer {
+            Some(tracer) if tracer.wants(self.pc, bank) => (),
+            _ => return,
+        }
+
+        let mut pcmem = [0; 4];
+        for (i, byte) in pcmem.iter_mut().enumerate() {
+            *byte = self.peek_byte(self.pc.wrapping_add(i as u16));
+        }
+
+        let entry = TraceEntry {
+            a: self.r[0],
+            f: self.r[1],
+            b: self.r[2],
+            c: self.r[3],
+            d: self.r[4],
+            e: self.r[5],
+            h: self.r[6],
+            l: self.r[7],
+            sp: self.sp,
+            pc: self.pc,
+            pcmem,
+            cycles: self.total_cycles,
+        };
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&entry);
+        }
     }
 
     fn halt_tick(&mut self) -> usize {
+        let start = self.total_cycles;
         if self.emu_mode != EmulationMode::Cgb && !self.just_halted {
             self.add_cycles(2);
         }
 
-        let irr = self.mmu.get_byte(0xFF0F);
-
-        let ints_pending = self.mmu.ie & irr & 0x1F;
+        let ints_pending = self.pending_interrupts();
 
         self.add_cycles(if self.emu_mode == EmulationMode::Cgb || self.just_halted {
             4
@@ -253,6 +581,7 @@ impl Cpu {
         });
 
         self.just_halted = false;
+        self.charge(PseudoFrame::Halt, start);
 
         let ime = self.ime;
         if self.ime_set_pending {
@@ -264,38 +593,63 @@ impl Cpu {
             if !ime {
                 self.halted = false;
             } else {
+                let start = self.total_cycles;
                 self.service_pending_interrupts();
+                self.charge(PseudoFrame::Irq, start);
             }
         }
 
         self.cycles
     }
 
+    fn begin_profiling(&mut self) {
+        let pc = self.location(self.pc);
+        if let Some(profiler) = &mut self.profiler {
+            let frames = match &self.callstack {
+                Some(callstack) => callstack.frames(),
+                None => &[],
+            };
+            profiler.begin(pc, frames, self.total_cycles);
+        }
+    }
+
+    /// Charges the cycles since `start` to a pseudo-frame of the profiler.
+    fn charge(&mut self, frame: PseudoFrame, start: u64) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.charge(frame, self.total_cycles - start);
+        }
+    }
+
     fn stop_tick(&mut self) -> usize {
-        self.add_cycles(4);
-        if self.mmu.get_byte(0xFF00) & 0xF != 0xF {
+        self.cycle_idle();
+        if self.buttons_held() {
+            self.clock_stopped = false;
             self.leave_stop_mode();
-            self.add_cycles(8);
+            self.cycle_idle();
+            self.cycle_idle();
         }
         self.cycles
     }
 
+    // The CPU is paused while a HDMA block is copied. Each M-cycle moves two bytes in
+    // normal speed and one byte in double speed, so a 16 byte block always takes
+    // 32 clocks of the other components.
     fn gdma_tick(&mut self) {
-        self.mmu.gdma_tick();
-
-        self.add_cycles(match self.mmu.cgb_mode.speed {
-            CgbSpeed::Normal => 32,
-            CgbSpeed::Double => 64,
-        });
+        self.mmu.gdma_tick(self.hdma_bytes_per_cycle());
+        self.cycle_idle();
     }
 
     fn hdma_tick(&mut self) {
-        self.mmu.hdma_tick();
+        self.mmu.hdma_tick(self.hdma_bytes_per_cycle());
+        self.cycle_idle();
+    }
 
-        self.add_cycles(match self.mmu.cgb_mode.speed {
-            CgbSpeed::Normal => 32,
-            CgbSpeed::Double => 64,
-        });
+    #[inline]
+    fn hdma_bytes_per_cycle(&self) -> u16 {
+        match self.mmu.cgb_mode.speed {
+            CgbSpeed::Normal => 2,
+            CgbSpeed::Double => 1,
+        }
     }
 
     fn service_pending_interrupts(&mut self) {
@@ -305,30 +659,34 @@ impl Cpu {
         // other branches of the repository.
         self.halted = false;
 
+        // M1-M3: the aborted opcode fetch and two internal cycles.
+        self.cycle_idle();
+        self.cycle_idle();
+        self.cycle_idle();
+
+        // M4: push the upper byte of PC. This push can overwrite IE and cancel the dispatch.
         self.sp = self.sp.wrapping_sub(1);
+        self.write_byte(self.sp, (self.pc >> 8) as u8);
 
-        self.add_cycles(12);
+        let mut ints = self.mmu.ie;
 
-        self.mmu.set_byte(self.sp, (self.pc >> 8) as u8);
+        self.cycle_idle();
 
-        let mut ints = self.mmu.ie;
+        // M5: push the lower byte of PC.
+        self.sp = self.sp.wrapping_sub(1);
 
-        if self.sp == 0xFF0F + 1 {
-            self.sp = self.sp.wrapping_sub(1);
-            self.add_cycles(4);
-            let old_irr = self.mmu.get_byte(0xFF0F);
-            self.mmu.set_byte(0xFF0F, (self.pc & 0xFF) as u8);
+        if self.sp == 0xFF0F {
+            let old_irr = self.mmu.interrupt_flags();
+            self.write_byte(0xFF0F, (self.pc & 0xFF) as u8);
 
             ints &= old_irr & 0x1F;
         } else {
-            self.sp = self.sp.wrapping_sub(1);
-            self.add_cycles(4);
-            self.mmu.set_byte(self.sp, (self.pc & 0xFF) as u8);
+            self.write_byte(self.sp, (self.pc & 0xFF) as u8);
 
-            ints &= self.mmu.get_byte(0xFF0F) & 0x1F;
+            ints &= self.mmu.interrupt_flags() & 0x1F;
         }
 
-        self.add_cycles(4);
+        self.cycle_idle();
 
         if ints == 0 {
             self.pc = 0;
@@ -346,55 +704,37 @@ impl Cpu {
     fn handle_interrupt(&mut self, i: u16) {
         let mask = 1u8 << i;
         self.ime = false;
-        let irr = self.mmu.get_byte(0xFF0F);
-        self.mmu.set_byte(0xFF0F, irr & !mask);
+        let irr = self.mmu.interrupt_flags();
+        self.mmu.set_interrupt_flags(irr & !mask);
+
+        let return_to = self.pc;
         self.pc = 0x40 + 8 * i;
+        self.enter_frame(FrameKind::Interrupt, return_to);
     }
 
     fn leave_stop_mode(&mut self) {
-        for _ in 0..0x200 {
-            self.add_cycles(0x10);
+        for _ in 0..0x800 {
+            self.cycle_idle();
         }
 
         self.stopped = false;
     }
 
-    #[allow(dead_code)]
-    pub fn emulate_bootrom(&mut self) {
-        self.mmu.bootrom.activate();
-    }
-
+    /// Sets up the registers like the boot ROM of the emulated model leaves
+    /// them, and jumps straight to the cartridge.
     pub fn simulate_bootrom(&mut self) {
-        match self.emu_mode {
-            EmulationMode::Dmg => {
-                // AF = 0x01B0
-                self.r[0] = 0x01;
-                self.r[1] = 0xB0;
-                // BC = 0x0013
-                self.r[2] = 0x00;
-                self.r[3] = 0x13;
-                // DE = 0x00D8
-                self.r[4] = 0x00;
-                self.r[5] = 0xD8;
-                // HL = 0x014D
-                self.r[6] = 0x01;
-                self.r[7] = 0x4D;
-            }
-            EmulationMode::Cgb => {
-                // AF = 0x1180;
-                self.r[0] = 0x11;
-                self.r[1] = 0x80;
-                // BC = 0x0000;
-                self.r[2] = 0x00;
-                self.r[3] = 0x00;
-                // DE = 0xFF56;
-                self.r[4] = 0xFF;
-                self.r[5] = 0x56;
-                // HL = 0x000D;
-                self.r[6] = 0x00;
-                self.r[7] = 0x0D;
-            }
-        }
+        self.r = match self.model {
+            // AF = 0x01B0, BC = 0x0013, DE = 0x00D8, HL = 0x014D
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            // AF = 0xFFB0, BC = 0x0013, DE = 0x00D8, HL = 0x014D
+            Model::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            // AF = 0x0100, BC = 0x0014, DE = 0x0000, HL = 0xC060
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            // AF = 0xFF00, BC = 0x0014, DE = 0x0000, HL = 0xC060
+            Model::Sgb2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            // AF = 0x1180, BC = 0x0000, DE = 0xFF56, HL = 0x000D
+            Model::Cgb | Model::Agb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
 
         self.sp = 0xFFFE;
 
@@ -432,26 +772,68 @@ impl Cpu {
         self.mmu.set_byte(0xFF4B, 0x00);
         self.mmu.set_byte(0xFFFF, 0x00);
 
+        if self.emu_mode == EmulationMode::Cgb && !self.mmu.cgb_cartridge() {
+            // DE = 0x0008
+            self.r[4] = 0x00;
+            self.r[5] = 0x08;
+            // HL = 0x007C
+            self.r[6] = 0x00;
+            self.r[7] = 0x7C;
+
+            let palette = compat::title_palette(&self.mmu.header());
+            self.mmu.gpu.load_compat_palette(&palette);
+            self.mmu.enter_compat_mode();
+
+            // The boot ROM takes longer to colourise DMG games.
+            self.mmu.timer.divider.counter = 0x267C;
+        }
+
+        // The AGB boot ROM ends with an extra INC B, which games use to detect
+        // a GBA. The carry flag is left alone.
+        if self.model == Model::Agb {
+            let b = self.r[2].wrapping_add(1);
+            let z = if b == 0 { 0x80 } else { 0 };
+            let h = if b & 0x0F == 0 { 0x20 } else { 0 };
+            self.r[2] = b;
+            self.r[1] = (self.r[1] & 0x10) | z | h;
+        }
+
         self.pc = 0x100;
     }
 
+    /// Picks the DMG compatibility palette as if `combo` had been held while
+    /// the boot logo was shown. Does nothing outside compatibility mode.
+    pub fn select_compat_palette(&mut self, combo: PaletteCombo) {
+        if self.mmu.gpu.compat() {
+            self.mmu.gpu.load_compat_palette(&combo.palette());
+        }
+    }
+
     // -------------------------------------------------------------
     //  Restarts & Returns
     // -------------------------------------------------------------
 
     pub fn rst(&mut self, value: u8) {
-        self.add_cycles(4);
+        let return_to = self.pc;
+        self.cycle_idle();
         self.call_addr(value as u16);
+        self.enter_frame(FrameKind::Rst, return_to);
     }
 
     pub fn ret(&mut self) {
+        let pc = self.pc.wrapping_sub(1);
+        let sp = self.sp;
         self.pc = self.pop() as u16;
         self.pc |= (self.pop() as u16) << 8;
-        self.add_cycles(4);
+        self.cycle_idle();
+
+        if let Some(callstack) = &mut self.callstack {
+            callstack.leave(pc, sp, self.pc);
+        }
     }
 
     pub fn ret_cc(&mut self, flag: Flag, set: bool) {
-        self.add_cycles(4);
+        self.cycle_idle();
         if self.get_flag(flag) == set as u8 {
             self.ret();
         }
@@ -474,15 +856,19 @@ impl Cpu {
 
     pub fn call(&mut self) {
         let addr = self.get_imm16();
-        self.add_cycles(4);
+        let return_to = self.pc;
+        self.cycle_idle();
         self.call_addr(addr);
+        self.enter_frame(FrameKind::Call, return_to);
     }
 
     pub fn call_cc_nn(&mut self, flag: Flag, set: bool) {
         let addr = self.get_imm16();
         if self.get_flag(flag) == set as u8 {
-            self.add_cycles(4);
+            let return_to = self.pc;
+            self.cycle_idle();
             self.call_addr(addr);
+            self.enter_frame(FrameKind::Call, return_to);
         }
     }
 
@@ -492,27 +878,27 @@ impl Cpu {
 
     pub fn jp_nn(&mut self) {
         self.pc = self.get_imm16();
-        self.add_cycles(4);
+        self.cycle_idle();
     }
 
     pub fn jp_cc_nn(&mut self, flag: Flag, set: bool) {
         let addr = self.get_imm16();
         if self.get_flag(flag) == set as u8 {
-            self.add_cycles(4);
+            self.cycle_idle();
             self.pc = addr;
         }
     }
 
     pub fn jr_n(&mut self) {
         let n = self.get_imm8();
-        self.add_cycles(4);
+        self.cycle_idle();
         self.pc = self.pc.wrapping_add(n as i8 as i16 as u16);
     }
 
     pub fn jr_cc_n(&mut self, flag: Flag, set: bool) {
         let n = self.get_imm8();
         if self.get_flag(flag) == set as u8 {
-            self.add_cycles(4);
+            self.cycle_idle();
             self.pc = self.pc.wrapping_add(n as i8 as i16 as u16);
         }
     }
@@ -764,7 +1150,28 @@ impl Cpu {
         self.ime = false;
     }
 
+    // Reference: https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
     pub fn stop(&mut self) {
+        let buttons_held = self.buttons_held();
+        let ints_pending = self.pending_interrupts() != 0;
+
+        if buttons_held {
+            // STOP mode would be left right away. Instead, the CPU either carries on
+            // or enters HALT mode, and DIV keeps running.
+            if !ints_pending {
+                self.pc = self.pc.wrapping_add(1);
+                self.halted = true;
+                self.just_halted = true;
+            }
+            return;
+        }
+
+        // Without a pending interrupt the byte after STOP is skipped.
+        if !ints_pending {
+            self.pc = self.pc.wrapping_add(1);
+        }
+
+        self.mmu.timer.set_byte(0xFF04, 0);
         self.stopped = true;
 
         if self.mmu.cgb_mode.prepare_speed_switch != 0x0 {
@@ -774,16 +1181,34 @@ impl Cpu {
             };
             self.mmu.cgb_mode.prepare_speed_switch = 0x0;
             self.leave_stop_mode();
+            return;
         }
+
+        // The system clock is halted until a button is pressed. The LCD goes blank.
+        self.clock_stopped = true;
+        self.mmu.gpu.blank();
+    }
+
+    /// Executing one of the unused opcodes hangs the CPU for good. Interrupts are no
+    /// longer serviced, but the rest of the system keeps running.
+    pub fn lock_up(&mut self) {
+        self.locked = true;
+        self.lock_event = true;
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Whether the CPU has hung after executing an unused opcode.
+    pub fn locked(&self) -> bool {
+        self.locked
     }
 
     pub fn halt(&mut self) {
         self.halted = true;
 
-        let ie = self.mmu.ie;
-        let irr = self.mmu.get_byte(0xFF0F);
-
-        if ie & irr & 0x1F != 0 {
+        if self.pending_interrupts() != 0 {
             if self.ime {
                 self.halted = false;
                 self.pc -= 1;
@@ -876,7 +1301,7 @@ impl Cpu {
 
     pub fn add_sp_imm(&mut self) {
         let value = self.get_imm8() as i8 as u16;
-        self.add_cycles(4);
+        self.cycle_idle();
         let n = self.get_r16(&R16::SP);
         let res = n.wrapping_add(value);
         self.reset_flag(Flag::Z);
@@ -1125,7 +1550,7 @@ impl Cpu {
     }
 
     pub fn push_r16(&mut self, r: R16) {
-        self.add_cycles(4);
+        self.cycle_idle();
         match r {
             R16::AF => {
                 let ms = self.get_r8(&R8::A) as u8;
@@ -1274,32 +1699,106 @@ impl Cpu {
     //  Atomic operations
     // -------------------------------------------------------------
 
+    // Every memory access takes exactly one M-cycle. The access happens at the start
+    // of the M-cycle, after which the timer, OAM DMA, GPU and APU are advanced by one
+    // M-cycle before the CPU touches the bus again. Internal delays are modelled as
+    // M-cycles without a bus access.
+
+    /// Advances all other components by `cycles` clocks. Only HALT samples the bus
+    /// in the middle of an M-cycle, everything else goes through the M-cycle helpers.
     fn add_cycles(&mut self, cycles: usize) {
         self.cycles += cycles;
+        self.total_cycles += cycles as u64;
+
+        if let Some(bus) = &mut self.bus {
+            bus.tick(cycles);
+            return;
+        }
+
+        if self.clock_stopped {
+            return;
+        }
 
         self.mmu.timer_tick(cycles);
+        self.mmu.serial_tick(cycles);
 
-        if !self.stopped && !self.halted && self.mmu.oam_dma.active {
+        if !self.stopped && self.mmu.oam_dma.active {
             self.mmu.oam_dma_tick(cycles);
         }
 
-        match self.mmu.cgb_mode.speed {
-            CgbSpeed::Normal => {
-                self.mmu.gpu_tick(cycles);
-                self.mmu.apu_tick(cycles);
-            }
-            CgbSpeed::Double => {
-                self.mmu.gpu_tick(cycles >> 1);
-                self.mmu.apu_tick(cycles >> 1);
+        let cycles = match self.mmu.cgb_mode.speed {
+            CgbSpeed::Normal => cycles,
+            CgbSpeed::Double => cycles >> 1,
+        };
+        let frame_finished = self.mmu.gpu_tick(cycles);
+        self.mmu.apu_tick(cycles);
+
+        if frame_finished {
+            if let Some(profiler) = &mut self.profiler {
+                profiler.end_frame();
             }
         }
     }
 
+    #[inline]
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        match &mut self.bus {
+            Some(bus) => bus.get_byte(addr),
+            None => self.mmu.get_byte(addr),
+        }
+    }
+
+    #[inline]
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match &mut self.bus {
+            Some(bus) => bus.set_byte(addr, value),
+            None => self.mmu.set_byte(addr, value),
+        }
+    }
+
+    /// Reads `addr` without side effects on the debugging instrumentation.
+    fn peek_byte(&mut self, addr: u16) -> u8 {
+        match &mut self.bus {
+            Some(bus) => bus.get_byte(addr),
+            None => self.mmu.peek(addr),
+        }
+    }
+
+    /// The interrupts that are both requested and enabled. The interrupt logic
+    /// sees IE and IF directly rather than over the bus, so this neither goes
+    /// through an attached `Bus` nor counts as a read in the instrumentation.
+    fn pending_interrupts(&self) -> u8 {
+        self.mmu.ie & self.mmu.interrupt_flags() & 0x1F
+    }
+
+    /// Whether a selected button line of P1 is low, which wakes the CPU from STOP.
+    fn buttons_held(&mut self) -> bool {
+        self.mmu.peek(0xFF00) & 0x0F != 0x0F
+    }
+
+    /// An M-cycle without a bus access.
+    #[inline]
+    fn cycle_idle(&mut self) {
+        self.add_cycles(4);
+    }
+
+    /// Fetch the first byte of an instruction at pc and increment pc.
+    pub fn fetch_opcode(&mut self) -> u8 {
+        self.fetch_as(Access::Opcode)
+    }
+
     /// Fetch next byte at pc from memory and increment pc.
     pub fn fetch(&mut self) -> u8 {
-        let byte = self.mmu.get_byte(self.pc);
+        self.fetch_as(Access::Operand)
+    }
+
+    fn fetch_as(&mut self, access: Access) -> u8 {
+        let byte = match &mut self.bus {
+            Some(bus) => bus.get_byte(self.pc),
+            None => self.mmu.read(self.pc, access),
+        };
+        self.cycle_idle();
         self.pc = self.pc.wrapping_add(1);
-        self.add_cycles(4);
         byte
     }
 
@@ -1342,7 +1841,7 @@ impl Cpu {
 
     /// Sets values of combined 16bit register.
     pub fn set_r16(&mut self, r: R16, value: u16) {
-        self.add_cycles(4);
+        self.cycle_idle();
         let ms = ((0xFF00 & value) >> 8) as u8;
         let ls = (0x00FF & value) as u8;
         match r {
@@ -1395,23 +1894,212 @@ impl Cpu {
         }
     }
 
+    /// Writes `value` to `addr` and takes one M-cycle.
     pub fn memory_set(&mut self, addr: u16, value: u8) {
-        self.mmu.set_byte(addr, value);
-        self.add_cycles(4);
+        self.write_byte(addr, value);
+        self.cycle_idle();
     }
 
+    /// Reads from `addr` and takes one M-cycle.
     pub fn memory_get(&mut self, addr: u16) -> u8 {
-        let value = self.mmu.get_byte(addr);
-        self.add_cycles(4);
+        let value = self.read_byte(addr);
+        self.cycle_idle();
         value
     }
 }
 
+#[cfg(test)]
+mod sm83_tests;
+
+#[cfg(test)]
+mod timing_tests;
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
 
+    fn cpu(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+        let mut cpu = Cpu::new(rom);
+        cpu.simulate_bootrom();
+        cpu.pc = 0x100;
+        cpu
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up() {
+        // EI; LD A,$04; LDH ($FF),A; $D3
+        let mut cpu = cpu(&[0xFB, 0x3E, 0x04, 0xE0, 0xFF, 0xD3]);
+        for _ in 0..4 {
+            cpu.tick();
+        }
+
+        assert!(cpu.locked());
+        assert_eq!(cpu.pc, 0x106);
+        assert!(matches!(cpu.run_till_event(100), Event::CpuLocked));
+
+        let cycles = cpu.total_cycles;
+        // Interrupts are never serviced once locked.
+        cpu.mmu.set_byte(0xFF0F, 0x04);
+        cpu.run_till_event(10_000);
+        assert_eq!(cpu.pc, 0x106);
+        assert!(cpu.total_cycles > cycles);
+    }
+
+    #[test]
+    fn test_stop() {
+        // STOP; $00; INC A
+        let mut cpu = cpu(&[0x10, 0x00, 0x3C]);
+        cpu.mmu.set_byte(0xFFFF, 0);
+        cpu.mmu.timer.tick(0x1000);
+
+        cpu.tick();
+        assert!(cpu.stopped);
+        assert_eq!(cpu.pc, 0x102);
+        assert_eq!(cpu.mmu.get_byte(0xFF04), 0);
+
+        // DIV and the LCD are frozen while stopped.
+        let ly = cpu.mmu.get_byte(0xFF44);
+        for _ in 0..1000 {
+            cpu.tick();
+        }
+        assert!(cpu.stopped);
+        assert_eq!(cpu.mmu.get_byte(0xFF04), 0);
+        assert_eq!(cpu.mmu.get_byte(0xFF44), ly);
+
+        // Selecting the buttons and pressing one wakes the CPU up.
+        cpu.mmu.set_byte(0xFF00, 0x10);
+        cpu.keydown(4);
+        while cpu.stopped {
+            cpu.tick();
+        }
+        cpu.tick();
+        assert_eq!(cpu.pc, 0x103);
+    }
+
+    #[test]
+    fn test_stop_with_button_held() {
+        // STOP; $00; INC A
+        let mut cpu = cpu(&[0x10, 0x00, 0x3C]);
+        cpu.mmu.set_byte(0xFF00, 0x10);
+        cpu.keydown(4);
+        let div = cpu.mmu.get_byte(0xFF04);
+
+        cpu.tick();
+        assert!(!cpu.stopped);
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x102);
+        assert!(cpu.mmu.get_byte(0xFF04) >= div);
+    }
+
+    #[test]
+    fn test_set_buttons() {
+        let mut cpu = cpu(&[0x00]);
+        cpu.mmu.set_byte(0xFF00, 0x20);
+
+        cpu.set_buttons(0, Key::Down.mask() | Key::Start.mask());
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x07);
+        cpu.set_buttons(0, Key::Left.mask());
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0D);
+        assert_eq!(cpu.buttons(0), Key::Left.mask());
+
+        // Unknown keys are ignored rather than crashing the emulator.
+        cpu.keydown(42);
+        assert_eq!(cpu.buttons(0), Key::Left.mask());
+    }
+
+    #[test]
+    fn test_bootrom_handover() {
+        let mut rom = vec![0xAA; 0x8000];
+        rom[0x143] = 0x00;
+        rom[0x147] = 0x00;
+        rom[0x100] = 0x3C; // INC A
+
+        // JP $0200, then at 0200: LD A,$11; JP $00FC, and at 00FC: LDH ($50),A
+        // falling through to the cartridge at 0100.
+        let mut image = vec![0; 0x900];
+        image[0x00..0x03].copy_from_slice(&[0xC3, 0x00, 0x02]);
+        image[0x200..0x205].copy_from_slice(&[0x3E, 0x11, 0xC3, 0xFC, 0x00]);
+        image[0xFC..0xFE].copy_from_slice(&[0xE0, 0x50]);
+
+        let mut cpu = Cpu::with_bootrom(rom, Bootrom::new(image, Model::Cgb).unwrap());
+        assert_eq!(cpu.emu_mode, EmulationMode::Cgb);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.mmu.peek(0x0000), 0xC3);
+        assert_eq!(cpu.mmu.peek(0x0100), 0x3C);
+        assert_eq!(cpu.mmu.peek(0x0200), 0x3E);
+
+        while cpu.pc != 0x100 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.mmu.peek(0x0000), 0xAA);
+        assert_eq!(cpu.mmu.peek(0x0200), 0xAA);
+
+        cpu.tick();
+        assert_eq!(cpu.r[0], 0x12);
+    }
+
+    #[test]
+    fn test_compat_mode() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x13F].copy_from_slice(b"POKEMON RED");
+        rom[0x14B] = 0x01;
+
+        let mut cpu = Cpu::with_model(rom.clone(), Model::Cgb);
+        cpu.simulate_bootrom();
+        assert!(cpu.mmu.gpu.compat());
+        assert_eq!(cpu.mmu.gpu.bgp_ram[2..4], [0x1F, 0x42]);
+        // The CGB registers are locked away.
+        assert_eq!(cpu.mmu.get_byte(0xFF4F), 0xFF);
+
+        cpu.select_compat_palette(PaletteCombo::LeftB);
+        assert_eq!(cpu.mmu.gpu.bgp_ram[2..4], [0x94, 0x52]);
+
+        let mut cpu = Cpu::new(rom.clone());
+        cpu.simulate_bootrom();
+        assert!(!cpu.mmu.gpu.compat());
+
+        // LD A,$04; LDH ($4C),A; LD A,$01; LDH ($50),A
+        let mut image = vec![0; 0x900];
+        image[..8].copy_from_slice(&[0x3E, 0x04, 0xE0, 0x4C, 0x3E, 0x01, 0xE0, 0x50]);
+        let mut cpu = Cpu::with_bootrom(rom, Bootrom::new(image, Model::Agb).unwrap());
+        assert_eq!(cpu.model, Model::Agb);
+        for _ in 0..100 {
+            cpu.tick();
+        }
+        assert!(!cpu.mmu.bootrom.is_active);
+        assert!(cpu.mmu.gpu.compat());
+    }
+
+    #[test]
+    fn test_models() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+
+        let registers = |model| {
+            let mut cpu = Cpu::with_model(rom.clone(), model);
+            cpu.simulate_bootrom();
+            (
+                cpu.get_r16(&R16::AF),
+                cpu.get_r16(&R16::BC),
+                cpu.mmu.get_byte(0xFF04),
+                cpu.mmu.get_byte(0xFF4D),
+            )
+        };
+
+        assert_eq!(registers(Model::Dmg), (0x01B0, 0x0013, 0xAB, 0xFF));
+        assert_eq!(registers(Model::Mgb), (0xFFB0, 0x0013, 0xAB, 0xFF));
+        assert_eq!(registers(Model::Sgb2), (0xFF00, 0x0014, 0xAB, 0xFF));
+        assert_eq!(registers(Model::Cgb), (0x1180, 0x0000, 0x1E, 0x7E));
+        assert_eq!(registers(Model::Agb), (0x1100, 0x0100, 0x1E, 0x7E));
+
+        assert_eq!("SGB2".parse(), Ok(Model::Sgb2));
+        assert!("gbc".parse::<Model>().is_err());
+    }
+
     #[test]
     fn test_rom() {
         let rom = fs::read("roms/<example_rom>").unwrap();
@@ -507,7 +507,7 @@ def main(f):
     custom_match_arm("0x76", "self.halt();", f)
 
     # 8.
-    custom_match_arm("0x10", ["self.stop();", "self.fetch();"], f)
+    custom_match_arm("0x10", "self.stop();", f)
 
     # 9.
     custom_match_arm("0xF3", "self.di();", f)
@@ -585,7 +585,7 @@ def main(f):
     # invalid opcodes
     for op in ["0xD3", "0xE3", "0xE4", "0xF4", "0xDB", "0xEB", "0xEC", "0xFC", "0xDD", "0xED", "0xFD"]:
         custom_match_arm(op,
-                        ['panic!("Invalid opcode {}", opcode);'],
+                        ['self.lock_up();'],
                         f)
 
     # default
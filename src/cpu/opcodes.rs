@@ -738,7 +738,6 @@ impl Cpu {
 			}
 			0x10 => {
 				self.stop();
-				// self.fetch();
 			}
 			0xF3 => {
 				self.di();
@@ -1619,37 +1618,37 @@ impl Cpu {
 				}
 			},
 			0xD3 => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xE3 => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xE4 => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xF4 => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xDB => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xEB => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xEC => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xFC => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xDD => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xED => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 			0xFD => {
-				panic!("Invalid opcode {}", opcode);
+				self.lock_up();
 			}
 		}
 	}
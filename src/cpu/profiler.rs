@@ -0,0 +1,392 @@
+// Cycle profiler.
+//
+// Every executed instruction is charged to its bank:PC and to the shadow call
+// stack that was active when it started, so a CALL is charged to the caller and
+// the matching RET to the callee. Per-function inclusive and exclusive cycles are
+// derived from those stacks, which are also what the folded-stack export (as
+// consumed by flamegraph.pl and inferno) is made of.
+//
+// Cycles spent outside of instructions are charged to pseudo-frames at the
+// root instead: `[halt]` while the CPU is halted, stopped or locked up, `[irq]`
+// for interrupt dispatch and `[dma]` while HDMA holds the CPU. Together they add
+// up to all the time that passed.
+
+use crate::cpu::callstack::{Frame, Location, SymbolTable};
+use std::collections::HashMap;
+
+const ROOT: &str = "(root)";
+
+/// Where cycles go that no instruction took.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PseudoFrame {
+    Halt,
+    Irq,
+    Dma,
+}
+
+impl PseudoFrame {
+    pub fn name(self) -> &'static str {
+        match self {
+            PseudoFrame::Halt => "[halt]",
+            PseudoFrame::Irq => "[irq]",
+            PseudoFrame::Dma => "[dma]",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Hotspot {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FunctionCycles {
+    /// Cycles spent in the function and everything it called.
+    pub inclusive: u64,
+    /// Cycles spent in the function itself.
+    pub exclusive: u64,
+}
+
+/// The counters gathered over some stretch of time.
+#[derive(Clone, Default)]
+pub struct Profile {
+    hotspots: HashMap<Location, Hotspot>,
+    /// Cycles per call stack, keyed by the entry points of its frames
+    /// (outermost first). An empty stack is the code outside of any call.
+    stacks: HashMap<Vec<Location>, u64>,
+    pseudo_frames: HashMap<PseudoFrame, u64>,
+}
+
+impl Profile {
+    pub fn total_cycles(&self) -> u64 {
+        self.stacks.values().sum::<u64>() + self.pseudo_frames.values().sum::<u64>()
+    }
+
+    pub fn pseudo_frame(&self, frame: PseudoFrame) -> u64 {
+        self.pseudo_frames.get(&frame).cloned().unwrap_or_default()
+    }
+
+    pub fn hotspot(&self, location: Location) -> Hotspot {
+        self.hotspots.get(&location).cloned().unwrap_or_default()
+    }
+
+    /// The `n` addresses that took up most cycles, most expensive first.
+    pub fn hotspots(&self, n: usize) -> Vec<(Location, Hotspot)> {
+        let mut hotspots: Vec<_> = self.hotspots.iter().map(|(l, h)| (*l, *h)).collect();
+        hotspots.sort_by(|a, b| {
+            b.1.cycles
+                .cmp(&a.1.cycles)
+                .then((a.0.bank, a.0.addr).cmp(&(b.0.bank, b.0.addr)))
+        });
+        hotspots.truncate(n);
+        hotspots
+    }
+
+    /// Cycles per function, identified by its entry point. Code outside of any
+    /// tracked call is reported under `None`, whose inclusive cycles also
+    /// cover the pseudo-frames.
+    pub fn functions(&self) -> HashMap<Option<Location>, FunctionCycles> {
+        let mut functions: HashMap<Option<Location>, FunctionCycles> = HashMap::new();
+        functions.entry(None).or_default().inclusive += self.pseudo_frames.values().sum::<u64>();
+
+        for (stack, &cycles) in &self.stacks {
+            functions
+                .entry(stack.last().cloned())
+                .or_default()
+                .exclusive += cycles;
+
+            // Recursive functions appear several times, but only count once.
+            functions.entry(None).or_default().inclusive += cycles;
+            for (i, location) in stack.iter().enumerate() {
+                if !stack[..i].contains(location) {
+                    functions.entry(Some(*location)).or_default().inclusive += cycles;
+                }
+            }
+        }
+
+        functions
+    }
+
+    /// Renders the profile as folded stacks, one `outer;inner cycles` line per
+    /// distinct call stack, sorted for stable output.
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let name = |location: &Location| {
+            symbols
+                .describe(*location)
+                .unwrap_or_else(|| format!("{:02X}:{:04X}", location.bank, location.addr))
+        };
+
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let mut line = String::from(ROOT);
+                for location in stack {
+                    line.push(';');
+                    line.push_str(&name(location));
+                }
+                format!("{} {}", line, cycles)
+            })
+            .chain(
+                self.pseudo_frames
+                    .iter()
+                    .map(|(frame, cycles)| format!("{};{} {}", ROOT, frame.name(), cycles)),
+            )
+            .collect();
+
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+pub struct Profiler {
+    current: Profile,
+    last_frame: Option<Profile>,
+    /// When set, the counters are reset at every VBlank and the profile of the
+    /// frame that just finished is kept in `last_frame`.
+    pub per_frame: bool,
+
+    pc: Location,
+    stack: Vec<Location>,
+    start_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            current: Profile::default(),
+            last_frame: None,
+            per_frame: false,
+            pc: Location { bank: 0, addr: 0 },
+            stack: vec![],
+            start_cycles: 0,
+        }
+    }
+
+    /// The counters gathered since the last reset.
+    pub fn profile(&self) -> &Profile {
+        &self.current
+    }
+
+    /// The profile of the last complete frame in per-frame mode.
+    pub fn last_frame(&self) -> Option<&Profile> {
+        self.last_frame.as_ref()
+    }
+
+    pub fn reset(&mut self) {
+        self.current = Profile::default();
+        self.last_frame = None;
+    }
+
+    /// Marks the start of an instruction at `pc` running under `frames`.
+    pub fn begin(&mut self, pc: Location, frames: &[Frame], cycles: u64) {
+        self.pc = pc;
+        self.start_cycles = cycles;
+        self.stack.clear();
+        self.stack.extend(frames.iter().map(|frame| frame.target));
+    }
+
+    /// Charges the cycles since `begin` to the instruction.
+    pub fn end(&mut self, cycles: u64) {
+        let cycles = cycles - self.start_cycles;
+
+        let hotspot = self.current.hotspots.entry(self.pc).or_default();
+        hotspot.instructions += 1;
+        hotspot.cycles += cycles;
+
+        match self.current.stacks.get_mut(self.stack.as_slice()) {
+            Some(total) => *total += cycles,
+            None => {
+                self.current.stacks.insert(self.stack.clone(), cycles);
+            }
+        }
+    }
+
+    /// Charges `cycles` spent outside of any instruction to `frame`.
+    pub fn charge(&mut self, frame: PseudoFrame, cycles: u64) {
+        *self.current.pseudo_frames.entry(frame).or_default() += cycles;
+    }
+
+    pub fn end_frame(&mut self) {
+        if self.per_frame {
+            self.last_frame = Some(std::mem::take(&mut self.current));
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::callstack::{CallStack, FrameKind};
+    use crate::cpu::Cpu;
+
+    fn loc(addr: u16) -> Location {
+        Location { bank: 0, addr }
+    }
+
+    fn frame(target: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            target: loc(target),
+            return_to: loc(0),
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn test_function_cycles() {
+        let mut profiler = Profiler::new();
+        let mut cycles = 0;
+        let mut run = |profiler: &mut Profiler, pc: u16, frames: &[Frame], n: u64| {
+            profiler.begin(loc(pc), frames, cycles);
+            cycles += n;
+            profiler.end(cycles);
+        };
+
+        run(&mut profiler, 0x100, &[], 12);
+        run(&mut profiler, 0x200, &[frame(0x200)], 4);
+        run(&mut profiler, 0x300, &[frame(0x200), frame(0x300)], 8);
+        run(&mut profiler, 0x300, &[frame(0x200), frame(0x300)], 8);
+        run(
+            &mut profiler,
+            0x200,
+            &[frame(0x200), frame(0x300), frame(0x200)],
+            16,
+        );
+
+        let profile = profiler.profile();
+        assert_eq!(profile.total_cycles(), 48);
+        assert_eq!(
+            profile.hotspot(loc(0x300)),
+            Hotspot {
+                instructions: 2,
+                cycles: 16
+            }
+        );
+        assert_eq!(profile.hotspots(1)[0].0, loc(0x200));
+
+        let functions = profile.functions();
+        assert_eq!(
+            functions[&None],
+            FunctionCycles {
+                inclusive: 48,
+                exclusive: 12
+            }
+        );
+        assert_eq!(
+            functions[&Some(loc(0x200))],
+            FunctionCycles {
+                inclusive: 36,
+                exclusive: 20
+            }
+        );
+        assert_eq!(
+            functions[&Some(loc(0x300))],
+            FunctionCycles {
+                inclusive: 32,
+                exclusive: 16
+            }
+        );
+
+        // Unlabelled entry points are named after the closest label before them.
+        let symbols = SymbolTable::parse("00:0200 Outer\n");
+        assert_eq!(
+            profile.folded(&symbols),
+            "(root) 12\n(root);Outer 4\n(root);Outer;Outer+$100 16\n(root);Outer;Outer+$100;Outer 16\n"
+        );
+    }
+
+    #[test]
+    fn test_per_frame_reset() {
+        let mut profiler = Profiler::new();
+        profiler.begin(loc(0x100), &[], 0);
+        profiler.end(4);
+
+        profiler.end_frame();
+        assert!(profiler.last_frame().is_none());
+        assert_eq!(profiler.profile().total_cycles(), 4);
+
+        profiler.per_frame = true;
+        profiler.end_frame();
+        assert_eq!(profiler.last_frame().unwrap().total_cycles(), 4);
+        assert_eq!(profiler.profile().total_cycles(), 0);
+
+        profiler.reset();
+        assert!(profiler.last_frame().is_none());
+    }
+
+    #[test]
+    fn test_cpu_profile() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD SP,$DFFE; CALL $0200; HALT
+        rom[0x100..0x107].copy_from_slice(&[0x31, 0xFE, 0xDF, 0xCD, 0x00, 0x02, 0x76]);
+        // 0200: NOP; RET
+        rom[0x200..0x202].copy_from_slice(&[0x00, 0xC9]);
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.set_callstack(Some(CallStack::new()));
+        cpu.set_profiler(Some(Profiler::new()));
+
+        while cpu.pc != 0x106 {
+            cpu.tick();
+        }
+
+        let profile = cpu.profiler().unwrap().profile();
+        // LD SP,nn and CALL nn are charged to the caller, NOP and RET to the callee.
+        assert_eq!(profile.hotspot(loc(0x103)).cycles, 24);
+        assert_eq!(
+            profile.functions()[&Some(loc(0x200))],
+            FunctionCycles {
+                inclusive: 20,
+                exclusive: 20
+            }
+        );
+        assert_eq!(profile.total_cycles(), 12 + 24 + 20);
+    }
+
+    #[test]
+    fn test_cycles_outside_instructions() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: EI; HALT; NOP
+        rom[0x100..0x103].copy_from_slice(&[0xFB, 0x76, 0x00]);
+        // 0050: RETI
+        rom[0x50] = 0xD9;
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.sp = 0xDFFE;
+        cpu.mmu.ie = 0x04;
+        cpu.set_callstack(Some(CallStack::new()));
+        cpu.set_profiler(Some(Profiler::new()));
+        let start = cpu.total_cycles;
+
+        for _ in 0..10 {
+            cpu.tick();
+        }
+        cpu.mmu.set_interrupt_flags(0x04);
+        // Into the handler and back out of it.
+        while cpu.pc != 0x50 {
+            cpu.tick();
+        }
+        while cpu.pc != 0x102 {
+            cpu.tick();
+        }
+
+        let profile = cpu.profiler().unwrap().profile();
+        assert_eq!(profile.total_cycles(), cpu.total_cycles - start);
+        assert!(profile.pseudo_frame(PseudoFrame::Halt) > 0);
+        assert_eq!(profile.pseudo_frame(PseudoFrame::Irq), 20);
+        assert_eq!(profile.functions()[&None].inclusive, profile.total_cycles());
+        assert!(profile
+            .folded(&SymbolTable::default())
+            .contains("(root);[irq] 20\n"));
+    }
+}
@@ -0,0 +1,591 @@
+// Reverse execution.
+//
+// While a history is attached, a full snapshot of the machine is taken every
+// `interval` steps (calls to `Cpu::tick`) and every joypad event is recorded
+// together with the step it happened at. Going back to an earlier step restores
+// the closest snapshot before it and re-executes the steps in between, replaying
+// the recorded input. The emulation is deterministic, so this lands in exactly
+// the state the machine was in the first time around. The MBC3 real-time clock
+// reads the host clock and is one exception.
+//
+// Devices plugged into the link or IR port are the other. They live outside
+// the machine and are shared by the snapshots rather than copied, so
+// re-executing would drive them a second time: a printer would print the page
+// again and a link cable or IR link would signal the other side again. Going
+// back is refused while the history covers any step during which a device was
+// plugged in.
+//
+// The tracer, profiler, code/data log and heatmap are suspended while steps are
+// re-executed so that nothing is recorded twice. Neither they nor the audio
+// waiting to be played and the LCD ghosting buffers are part of the snapshots.
+// The shadow call stack is part of the snapshots and stays in sync. Memory
+// behind an attached `Bus` is not captured.
+
+use crate::cpu::callstack::Frame;
+use crate::cpu::{Cpu, EmulationMode, Model};
+use crate::memory::mmu::{Mmu, Output};
+use std::collections::VecDeque;
+
+const DEFAULT_INTERVAL: u64 = 50_000;
+const DEFAULT_WINDOW: u64 = 1_000_000;
+
+/// Everything needed to resume emulation at a given step.
+#[derive(Clone)]
+struct CpuState {
+    r: [u8; 8],
+    pc: u16,
+    sp: u16,
+    mmu: Mmu,
+    cycles: usize,
+    total_cycles: u64,
+    ime: bool,
+    halted: bool,
+    model: Model,
+    emu_mode: EmulationMode,
+    stopped: bool,
+    clock_stopped: bool,
+    locked: bool,
+    lock_event: bool,
+    halt_bug: bool,
+    ime_set_pending: bool,
+    just_halted: bool,
+    event_cycles: usize,
+    audio_flag: bool,
+    frames: Option<Vec<Frame>>,
+}
+
+struct Snapshot {
+    step: u64,
+    state: Box<CpuState>,
+}
+
+struct InputEvent {
+    step: u64,
+    player: usize,
+    key: usize,
+    pressed: bool,
+}
+
+pub struct History {
+    /// The number of steps between two snapshots. Larger intervals use less
+    /// memory, smaller ones make stepping back faster.
+    pub interval: u64,
+    /// How many steps back in time the history reaches.
+    pub window: u64,
+
+    step: u64,
+    snapshots: VecDeque<Snapshot>,
+    inputs: VecDeque<InputEvent>,
+    replaying: bool,
+    /// The last step executed with a device plugged in.
+    device_step: Option<u64>,
+    /// The address of the instruction started by the current step, if any.
+    pub(super) executed: Option<u16>,
+}
+
+impl History {
+    pub fn new(interval: u64, window: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+            window,
+            step: 0,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            replaying: false,
+            device_step: None,
+            executed: None,
+        }
+    }
+
+    /// The number of steps executed since the history was attached.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// The earliest step that can still be returned to.
+    pub fn oldest_step(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.step)
+    }
+
+    pub fn snapshot_count(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Drops everything after the current step, which is no longer reachable once
+    /// execution continues from here.
+    fn truncate(&mut self) {
+        let step = self.step;
+        while matches!(self.snapshots.back(), Some(s) if s.step > step) {
+            self.snapshots.pop_back();
+        }
+        while matches!(self.inputs.back(), Some(i) if i.step >= step) {
+            self.inputs.pop_back();
+        }
+    }
+
+    fn snapshot_due(&self) -> bool {
+        match self.snapshots.back() {
+            Some(snapshot) => self.step - snapshot.step >= self.interval,
+            None => true,
+        }
+    }
+
+    fn push(&mut self, state: CpuState) {
+        self.snapshots.push_back(Snapshot {
+            step: self.step,
+            state: Box::new(state),
+        });
+
+        let oldest = self.step.saturating_sub(self.window);
+        while self.snapshots.len() > 1 && self.snapshots[1].step <= oldest {
+            self.snapshots.pop_front();
+        }
+
+        let first = self.snapshots[0].step;
+        while matches!(self.inputs.front(), Some(i) if i.step < first) {
+            self.inputs.pop_front();
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL, DEFAULT_WINDOW)
+    }
+}
+
+impl Cpu {
+    /// Goes back to right before the previously executed instruction. Returns
+    /// false if the history doesn't reach back that far, in which case the CPU
+    /// is left at the oldest step in the history, or if a device was plugged
+    /// in during the history, in which case the CPU is left alone.
+    pub fn step_back(&mut self) -> bool {
+        self.rewind(|_| true)
+    }
+
+    /// Runs backwards until right before the last instruction that was executed at
+    /// a breakpoint. Returns false if there was none within the history, in which
+    /// case the CPU is left at the oldest step in the history, or like
+    /// `step_back` if a device was plugged in during the history.
+    pub fn continue_back(&mut self) -> bool {
+        let breakpoints = self.breakpoints.clone();
+        self.rewind(|pc| breakpoints.contains(&pc))
+    }
+
+    pub(super) fn history_tick(&mut self) {
+        let due = match &mut self.history {
+            Some(history) => {
+                history.executed = None;
+                !history.replaying && history.snapshot_due()
+            }
+            None => return,
+        };
+
+        let devices = self.devices_connected();
+        if let Some(history) = &mut self.history {
+            if devices && !history.replaying {
+                history.device_step = Some(history.step);
+            }
+        }
+
+        if due {
+            let state = self.save_state();
+            if let Some(history) = &mut self.history {
+                history.push(state);
+            }
+        }
+
+        if let Some(history) = &mut self.history {
+            history.step += 1;
+        }
+    }
+
+    pub(super) fn history_input(&mut self, player: usize, key: usize, pressed: bool) {
+        if let Some(history) = &mut self.history {
+            if !history.replaying {
+                history.inputs.push_back(InputEvent {
+                    step: history.step,
+                    player,
+                    key,
+                    pressed,
+                });
+            }
+        }
+    }
+
+    fn devices_connected(&self) -> bool {
+        self.mmu.serial.connected() || self.mmu.infrared.connected()
+    }
+
+    /// Whether any step the history covers was executed with a device plugged
+    /// in, so that re-executing it would drive the device again.
+    fn devices_in_history(&self) -> bool {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return false,
+        };
+
+        self.devices_connected()
+            || matches!(
+                (history.device_step, history.oldest_step()),
+                (Some(device), Some(oldest)) if device >= oldest
+            )
+    }
+
+    /// Finds the last step before the current one that starts an instruction for
+    /// which `matches` holds and goes back to it.
+    fn rewind<F: Fn(u16) -> bool>(&mut self, matches: F) -> bool {
+        if self.devices_in_history() {
+            return false;
+        }
+
+        let (current, steps) = match &self.history {
+            Some(history) if !history.snapshots.is_empty() => (
+                history.step,
+                history
+                    .snapshots
+                    .iter()
+                    .map(|snapshot| snapshot.step)
+                    .collect::<Vec<_>>(),
+            ),
+            _ => return false,
+        };
+
+        for i in (0..steps.len()).rev() {
+            if steps[i] >= current {
+                continue;
+            }
+
+            let until = match steps.get(i + 1) {
+                Some(&next) => next.min(current),
+                None => current,
+            };
+
+            let mut hit = None;
+            self.replay(i, until, &mut |step, pc| {
+                if matches(pc) {
+                    hit = Some(step);
+                }
+            });
+
+            if let Some(target) = hit {
+                self.replay(i, target, &mut |_, _| ());
+                self.history.as_mut().unwrap().truncate();
+                return true;
+            }
+        }
+
+        self.replay(0, steps[0], &mut |_, _| ());
+        self.history.as_mut().unwrap().truncate();
+        false
+    }
+
+    /// Restores snapshot `index` and re-executes steps until `until`. `visit` is
+    /// called with the step and address of every instruction that is started.
+    fn replay(&mut self, index: usize, until: u64, visit: &mut dyn FnMut(u64, u16)) {
+        let history = self.history.as_mut().unwrap();
+        let snapshot = &history.snapshots[index];
+        let state = (*snapshot.state).clone();
+        history.step = snapshot.step;
+        history.replaying = true;
+        self.load_state(state);
+
+        let tracer = self.tracer.take();
+        let profiler = self.profiler.take();
+        let cdl = self.mmu.cdl.take();
+        let heatmap = self.mmu.heatmap.take();
+
+        loop {
+            let history = self.history.as_ref().unwrap();
+            let step = history.step;
+            if step >= until {
+                break;
+            }
+
+            let keys: Vec<(usize, usize, bool)> = history
+                .inputs
+                .iter()
+                .filter(|input| input.step == step)
+                .map(|input| (input.player, input.key, input.pressed))
+                .collect();
+            for (player, key, pressed) in keys {
+                match pressed {
+                    true => self.player_keydown(player, key),
+                    false => self.player_keyup(player, key),
+                }
+            }
+
+            self.tick();
+
+            if let Some(pc) = self.history.as_ref().unwrap().executed {
+                visit(step, pc);
+            }
+        }
+
+        self.tracer = tracer;
+        self.profiler = profiler;
+        self.mmu.cdl = cdl;
+        self.mmu.heatmap = heatmap;
+        self.history.as_mut().unwrap().replaying = false;
+    }
+
+    fn save_state(&mut self) -> CpuState {
+        // The instrumentation and output aren't part of the machine and can be
+        // large.
+        let cdl = self.mmu.cdl.take();
+        let heatmap = self.mmu.heatmap.take();
+        let output = self.mmu.swap_output(Output::default());
+
+        let state = CpuState {
+            r: self.r,
+            pc: self.pc,
+            sp: self.sp,
+            mmu: self.mmu.clone(),
+            cycles: self.cycles,
+            total_cycles: self.total_cycles,
+            ime: self.ime,
+            halted: self.halted,
+            model: self.model,
+            emu_mode: self.emu_mode.clone(),
+            stopped: self.stopped,
+            clock_stopped: self.clock_stopped,
+            locked: self.locked,
+            lock_event: self.lock_event,
+            halt_bug: self.halt_bug,
+            ime_set_pending: self.ime_set_pending,
+            just_halted: self.just_halted,
+            event_cycles: self.event_cycles,
+            audio_flag: self.audio_flag,
+            frames: self.callstack.as_ref().map(|c| c.frames().to_vec()),
+        };
+
+        self.mmu.cdl = cdl;
+        self.mmu.heatmap = heatmap;
+        self.mmu.swap_output(output);
+        state
+    }
+
+    fn load_state(&mut self, state: CpuState) {
+        let cdl = self.mmu.cdl.take();
+        let heatmap = self.mmu.heatmap.take();
+        let output = self.mmu.swap_output(Output::default());
+
+        self.r = state.r;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.mmu = state.mmu;
+        self.cycles = state.cycles;
+        self.total_cycles = state.total_cycles;
+        self.ime = state.ime;
+        self.halted = state.halted;
+        self.model = state.model;
+        self.emu_mode = state.emu_mode;
+        self.stopped = state.stopped;
+        self.clock_stopped = state.clock_stopped;
+        self.locked = state.locked;
+        self.lock_event = state.lock_event;
+        self.halt_bug = state.halt_bug;
+        self.ime_set_pending = state.ime_set_pending;
+        self.just_halted = state.just_halted;
+        self.event_cycles = state.event_cycles;
+        self.audio_flag = state.audio_flag;
+
+        if let (Some(callstack), Some(frames)) = (&mut self.callstack, state.frames) {
+            callstack.set_frames(frames);
+        }
+
+        self.mmu.cdl = cdl;
+        self.mmu.heatmap = heatmap;
+        self.mmu.swap_output(output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::callstack::CallStack;
+    use crate::cpu::Event;
+    use crate::gpu::blend::{BlendMode, FrameBlend};
+    use crate::infrared::NoSignal;
+    use crate::serial::SerialDevice;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu() -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD SP,$DFFE; XOR A
+        // 0104: INC A; LD ($C000),A; CALL $0200; JR -9
+        rom[0x100..0x10F].copy_from_slice(&[
+            0x31, 0xFE, 0xDF, 0xAF, 0x3C, 0xEA, 0x00, 0xC0, 0xCD, 0x00, 0x02, 0x18, 0xF7, 0x00,
+            0x00,
+        ]);
+        // 0200: LD B,A; RET
+        rom[0x200..0x202].copy_from_slice(&[0x47, 0xC9]);
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu
+    }
+
+    fn position(cpu: &mut Cpu) -> (u16, u8, u8, u64) {
+        (
+            cpu.pc,
+            cpu.r[0],
+            cpu.mmu.get_byte(0xC000),
+            cpu.history().unwrap().step(),
+        )
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = cpu();
+        cpu.set_history(Some(History::new(7, 1000)));
+
+        let mut positions = vec![];
+        for _ in 0..40 {
+            positions.push(position(&mut cpu));
+            cpu.tick();
+        }
+
+        for expected in positions.iter().rev().take(25) {
+            assert!(cpu.step_back());
+            assert_eq!(position(&mut cpu), *expected);
+        }
+
+        // Execution continues normally from the rewound position.
+        let (_, _, _, step) = position(&mut cpu);
+        cpu.tick();
+        assert_eq!(position(&mut cpu), positions[step as usize + 1]);
+    }
+
+    #[test]
+    fn test_continue_back() {
+        let mut cpu = cpu();
+        cpu.set_callstack(Some(CallStack::new()));
+        cpu.set_history(Some(History::new(5, 1000)));
+        cpu.add_breakpoint(0x201);
+
+        for _ in 0..50 {
+            cpu.tick();
+        }
+        let a = cpu.r[0];
+
+        assert!(cpu.continue_back());
+        assert_eq!(cpu.pc, 0x201);
+        assert_eq!(cpu.r[0], a);
+        assert_eq!(cpu.backtrace().unwrap().entries.len(), 2);
+
+        assert!(cpu.continue_back());
+        assert_eq!(cpu.pc, 0x201);
+        assert_eq!(cpu.r[0], a - 1);
+    }
+
+    #[test]
+    fn test_lock_up() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: NOP; NOP; an unused opcode
+        rom[0x102] = 0xD3;
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.set_history(Some(History::new(1, 1000)));
+
+        // Lock up without reporting it, then go back to before the lock-up.
+        for _ in 0..6 {
+            cpu.tick();
+        }
+        assert!(cpu.locked);
+        while cpu.pc != 0x100 {
+            assert!(cpu.step_back());
+        }
+        assert!(!cpu.locked);
+
+        // The event is reported once, when the lock-up happens again.
+        assert!(matches!(cpu.run_till_event(4), Event::MaxCycles));
+        assert!(matches!(cpu.run_till_event(4), Event::MaxCycles));
+        assert!(matches!(cpu.run_till_event(4), Event::CpuLocked));
+        assert!(matches!(cpu.run_till_event(4), Event::MaxCycles));
+    }
+
+    #[test]
+    fn test_output_is_not_snapshotted() {
+        let mut cpu = cpu();
+        cpu.mmu.gpu.set_frame_blend(BlendMode::Mix, 50);
+        cpu.set_history(Some(History::new(100, 1000)));
+
+        for _ in 0..100000 {
+            cpu.tick();
+        }
+        let history = cpu.history.as_mut().unwrap();
+        for snapshot in history.snapshots.iter_mut() {
+            assert_eq!(snapshot.state.mmu.apu.get_next_buffer(), (None, None));
+        }
+
+        // Going back keeps the live output and its settings.
+        assert!(cpu.step_back());
+        assert!(cpu.mmu.apu.get_next_buffer().0.is_some());
+        let blend = cpu.mmu.gpu.swap_frame_blend(FrameBlend::default());
+        assert!(blend.enabled());
+    }
+
+    #[test]
+    fn test_window() {
+        let mut cpu = cpu();
+        cpu.set_history(Some(History::new(4, 20)));
+
+        for _ in 0..100 {
+            cpu.tick();
+        }
+
+        let history = cpu.history().unwrap();
+        assert!(history.snapshot_count() <= 7);
+        assert!(history.oldest_step().unwrap() <= 80);
+
+        cpu.add_breakpoint(0x100);
+        assert!(!cpu.continue_back());
+        let oldest = cpu.history().unwrap().oldest_step();
+        assert_eq!(Some(cpu.history().unwrap().step()), oldest);
+
+        assert!(!cpu.step_back());
+        assert_eq!(Some(cpu.history().unwrap().step()), oldest);
+    }
+
+    #[test]
+    fn test_devices_block_rewinding() {
+        struct Counter(Rc<RefCell<usize>>);
+
+        impl SerialDevice for Counter {
+            fn exchange(&mut self, _byte: u8) -> u8 {
+                *self.0.borrow_mut() += 1;
+                0xFF
+            }
+        }
+
+        let mut cpu = cpu();
+        cpu.set_history(Some(History::new(4, 20)));
+        for _ in 0..10 {
+            cpu.tick();
+        }
+
+        cpu.mmu
+            .serial
+            .connect(Rc::new(RefCell::new(Counter(Rc::new(RefCell::new(0))))));
+        let before = position(&mut cpu);
+        assert!(!cpu.step_back());
+        assert_eq!(position(&mut cpu), before);
+
+        // Still refused once unplugged, as long as the steps with the device
+        // plugged in are within the history.
+        cpu.tick();
+        cpu.mmu.serial.disconnect();
+        cpu.tick();
+        assert!(!cpu.step_back());
+
+        for _ in 0..40 {
+            cpu.tick();
+        }
+        assert!(cpu.step_back());
+
+        cpu.mmu.infrared.connect(Rc::new(RefCell::new(NoSignal)));
+        assert!(!cpu.step_back());
+    }
+}
@@ -0,0 +1,416 @@
+// References: https://github.com/SingleStepTests/sm83
+//
+// Harness for the community sm83 per-opcode JSON test vectors. Every file holds
+// a list of tests for a single opcode. A test lists the initial registers and
+// memory, the expected final state and the bus activity of every M-cycle.
+//
+// The vectors are not checked in. Point `SM83_TESTS_DIR` at a local copy of the
+// `v1` directory (or place it at tests/sm83/v1) to run them. The CPU is attached
+// to a flat 64 KiB memory instead of the `Mmu` and executes one instruction.
+
+use super::*;
+use crate::memory::bus::Bus;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const DEFAULT_TESTS_DIR: &str = "tests/sm83/v1";
+const MAX_REPORTED_FAILURES: usize = 20;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BusCycle {
+    Idle,
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+struct FlatMemory {
+    mem: Vec<u8>,
+    pending: Vec<BusCycle>,
+    cycles: Vec<BusCycle>,
+}
+
+impl FlatMemory {
+    fn new() -> Self {
+        Self {
+            mem: vec![0; 0x10000],
+            pending: vec![],
+            cycles: vec![],
+        }
+    }
+}
+
+/// Logs every access and assigns it to the M-cycle during which it happened.
+struct FlatBus(Rc<RefCell<FlatMemory>>);
+
+impl Bus for FlatBus {
+    fn get_byte(&mut self, addr: u16) -> u8 {
+        let mut memory = self.0.borrow_mut();
+        let value = memory.mem[addr as usize];
+        memory.pending.push(BusCycle::Read(addr, value));
+        value
+    }
+
+    fn set_byte(&mut self, addr: u16, value: u8) {
+        let mut memory = self.0.borrow_mut();
+        memory.mem[addr as usize] = value;
+        memory.pending.push(BusCycle::Write(addr, value));
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        let mut memory = self.0.borrow_mut();
+
+        for _ in 0..cycles / 4 {
+            let cycle = if memory.pending.is_empty() {
+                BusCycle::Idle
+            } else {
+                memory.pending.remove(0)
+            };
+            memory.cycles.push(cycle);
+        }
+    }
+}
+
+struct State {
+    r: [u8; 8],
+    pc: u16,
+    sp: u16,
+    ime: Option<bool>,
+    ram: Vec<(u16, u8)>,
+}
+
+fn field(v: &Value, name: &str) -> Result<u64, String> {
+    v[name]
+        .as_u64()
+        .ok_or_else(|| format!("missing field `{}`", name))
+}
+
+fn parse_state(v: &Value) -> Result<State, String> {
+    let mut r = [0; 8];
+    for (i, name) in ["a", "f", "b", "c", "d", "e", "h", "l"].iter().enumerate() {
+        r[i] = field(v, name)? as u8;
+    }
+
+    let ram = v["ram"]
+        .as_array()
+        .ok_or("missing field `ram`")?
+        .iter()
+        .map(|entry| match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(addr), Some(value)) => Ok((addr as u16, value as u8)),
+            _ => Err(format!("bad ram entry {}", entry)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(State {
+        r,
+        pc: field(v, "pc")? as u16,
+        sp: field(v, "sp")? as u16,
+        ime: v["ime"].as_u64().map(|ime| ime != 0),
+        ram,
+    })
+}
+
+fn parse_cycles(v: &Value) -> Result<Vec<BusCycle>, String> {
+    v.as_array()
+        .ok_or("missing field `cycles`")?
+        .iter()
+        .map(|entry| {
+            if entry.is_null() {
+                return Ok(BusCycle::Idle);
+            }
+
+            let kind = entry[2].as_str().unwrap_or("---");
+            match (entry[0].as_u64(), entry[1].as_u64()) {
+                (Some(addr), Some(value)) if kind.contains('r') => {
+                    Ok(BusCycle::Read(addr as u16, value as u8))
+                }
+                (Some(addr), Some(value)) if kind.contains('w') => {
+                    Ok(BusCycle::Write(addr as u16, value as u8))
+                }
+                _ => Ok(BusCycle::Idle),
+            }
+        })
+        .collect()
+}
+
+fn run_test(test: &Value) -> Result<(), String> {
+    let initial = parse_state(&test["initial"])?;
+    let expected = parse_state(&test["final"])?;
+    let expected_cycles = parse_cycles(&test["cycles"])?;
+
+    let memory = Rc::new(RefCell::new(FlatMemory::new()));
+    for (addr, value) in initial.ram.iter() {
+        memory.borrow_mut().mem[*addr as usize] = *value;
+    }
+
+    let mut cpu = Cpu::new(vec![0; 0x8000]);
+    cpu.attach_bus(Box::new(FlatBus(memory.clone())));
+    cpu.r = initial.r;
+    cpu.pc = initial.pc;
+    cpu.sp = initial.sp;
+    cpu.ime = initial.ime.unwrap_or(false);
+
+    let opcode = cpu.fetch_opcode();
+    cpu.decode_exec(opcode);
+
+    let mut errors = vec![];
+
+    for (i, name) in ["A", "F", "B", "C", "D", "E", "H", "L"].iter().enumerate() {
+        if cpu.r[i] != expected.r[i] {
+            errors.push(format!(
+                "{}: {:#04X} != {:#04X}",
+                name, cpu.r[i], expected.r[i]
+            ));
+        }
+    }
+    if cpu.pc != expected.pc {
+        errors.push(format!("PC: {:#06X} != {:#06X}", cpu.pc, expected.pc));
+    }
+    if cpu.sp != expected.sp {
+        errors.push(format!("SP: {:#06X} != {:#06X}", cpu.sp, expected.sp));
+    }
+    if let Some(ime) = expected.ime {
+        // A pending EI takes effect at the next instruction boundary, which is
+        // where the test vectors sample IME.
+        let effective_ime = cpu.ime != cpu.ime_set_pending;
+        if effective_ime != ime {
+            errors.push(format!("IME: {} != {}", effective_ime, ime));
+        }
+    }
+
+    let mut memory = memory.borrow_mut();
+    for (addr, value) in expected.ram.iter() {
+        let actual = memory.mem[*addr as usize];
+        if actual != *value {
+            errors.push(format!(
+                "[{:#06X}]: {:#04X} != {:#04X}",
+                addr, actual, value
+            ));
+        }
+    }
+
+    let pending = std::mem::take(&mut memory.pending);
+    memory.cycles.extend(pending);
+    if memory.cycles != expected_cycles {
+        errors.push(format!(
+            "cycles: {:?} != {:?}",
+            memory.cycles, expected_cycles
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn run_file(path: &Path) -> Result<(usize, Vec<String>), String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let tests: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let tests = tests.as_array().ok_or("expected a list of tests")?;
+
+    let failures = tests
+        .iter()
+        .filter_map(|test| {
+            run_test(test).err().map(|e| {
+                format!(
+                    "{} ({}): {}",
+                    test["name"].as_str().unwrap_or("?"),
+                    path.display(),
+                    e
+                )
+            })
+        })
+        .collect();
+
+    Ok((tests.len(), failures))
+}
+
+#[test]
+fn test_harness_with_inline_vectors() {
+    let tests = r#"[
+        {
+            "name": "00 nop",
+            "initial": {"pc": 49152, "sp": 65534, "a": 1, "f": 176, "b": 0, "c": 19, "d": 0,
+                        "e": 216, "h": 1, "l": 77, "ime": 0, "ram": [[49152, 0]]},
+            "final": {"pc": 49153, "sp": 65534, "a": 1, "f": 176, "b": 0, "c": 19, "d": 0,
+                      "e": 216, "h": 1, "l": 77, "ime": 0, "ram": [[49152, 0]]},
+            "cycles": [[49152, 0, "r-m"]]
+        },
+        {
+            "name": "77 ld (hl), a",
+            "initial": {"pc": 49152, "sp": 65534, "a": 66, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 208, "l": 16, "ime": 0, "ram": [[49152, 119], [53264, 0]]},
+            "final": {"pc": 49153, "sp": 65534, "a": 66, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 208, "l": 16, "ime": 0, "ram": [[53264, 66]]},
+            "cycles": [[49152, 119, "r-m"], [53264, 66, "-wm"]]
+        },
+        {
+            "name": "c5 push bc",
+            "initial": {"pc": 49152, "sp": 53248, "a": 0, "f": 0, "b": 18, "c": 52, "d": 0,
+                        "e": 0, "h": 0, "l": 0, "ime": 0, "ram": [[49152, 197]]},
+            "final": {"pc": 49153, "sp": 53246, "a": 0, "f": 0, "b": 18, "c": 52, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ime": 0, "ram": [[53247, 18], [53246, 52]]},
+            "cycles": [[49152, 197, "r-m"], null, [53247, 18, "-wm"], [53246, 52, "-wm"]]
+        },
+        {
+            "name": "cd call nn",
+            "initial": {"pc": 49152, "sp": 53248, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0, "ime": 0,
+                        "ram": [[49152, 205], [49153, 52], [49154, 18]]},
+            "final": {"pc": 4660, "sp": 53246, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ime": 0, "ram": [[53247, 192], [53246, 3]]},
+            "cycles": [[49152, 205, "r-m"], [49153, 52, "r-m"], [49154, 18, "r-m"], null,
+                       [53247, 192, "-wm"], [53246, 3, "-wm"]]
+        },
+        {
+            "name": "fb ei",
+            "initial": {"pc": 49152, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0, "ime": 0, "ram": [[49152, 251]]},
+            "final": {"pc": 49153, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ime": 1, "ram": []},
+            "cycles": [[49152, 251, "r-m"]]
+        },
+        {
+            "name": "76 halt",
+            "initial": {"pc": 49152, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0, "ime": 0,
+                        "ram": [[49152, 118], [65295, 1], [65535, 1]]},
+            "final": {"pc": 49153, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ime": 0, "ram": [[65295, 1], [65535, 1]]},
+            "cycles": [[49152, 118, "r-m"]]
+        }
+    ]"#;
+
+    run_inline_vectors(tests);
+}
+
+/// Read-modify-write and multi-byte instructions must touch the bus in the same
+/// M-cycles as the hardware does. These are spot checks, Blargg's mem_timing
+/// ROMs in `timing_tests` cover every instruction.
+#[test]
+fn test_memory_access_timing() {
+    let tests = r#"[
+        {
+            "name": "34 inc (hl)",
+            "initial": {"pc": 49152, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 208, "l": 0, "ram": [[49152, 52], [53248, 15]]},
+            "final": {"pc": 49153, "sp": 65534, "a": 0, "f": 32, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 208, "l": 0, "ram": [[53248, 16]]},
+            "cycles": [[49152, 52, "r-m"], [53248, 15, "r-m"], [53248, 16, "-wm"]]
+        },
+        {
+            "name": "cb 06 rlc (hl)",
+            "initial": {"pc": 49152, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 208, "l": 0,
+                        "ram": [[49152, 203], [49153, 6], [53248, 128]]},
+            "final": {"pc": 49154, "sp": 65534, "a": 0, "f": 16, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 208, "l": 0, "ram": [[53248, 1]]},
+            "cycles": [[49152, 203, "r-m"], [49153, 6, "r-m"], [53248, 128, "r-m"],
+                       [53248, 1, "-wm"]]
+        },
+        {
+            "name": "cb 46 bit 0, (hl)",
+            "initial": {"pc": 49152, "sp": 65534, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 208, "l": 0,
+                        "ram": [[49152, 203], [49153, 70], [53248, 1]]},
+            "final": {"pc": 49154, "sp": 65534, "a": 0, "f": 32, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 208, "l": 0, "ram": [[53248, 1]]},
+            "cycles": [[49152, 203, "r-m"], [49153, 70, "r-m"], [53248, 1, "r-m"]]
+        },
+        {
+            "name": "08 ld (nn), sp",
+            "initial": {"pc": 49152, "sp": 48879, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0,
+                        "ram": [[49152, 8], [49153, 0], [49154, 208]]},
+            "final": {"pc": 49155, "sp": 48879, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ram": [[53248, 239], [53249, 190]]},
+            "cycles": [[49152, 8, "r-m"], [49153, 0, "r-m"], [49154, 208, "r-m"],
+                       [53248, 239, "-wm"], [53249, 190, "-wm"]]
+        },
+        {
+            "name": "c9 ret",
+            "initial": {"pc": 49152, "sp": 53248, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0,
+                        "ram": [[49152, 201], [53248, 52], [53249, 18]]},
+            "final": {"pc": 4660, "sp": 53250, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ram": []},
+            "cycles": [[49152, 201, "r-m"], [53248, 52, "r-m"], [53249, 18, "r-m"], null]
+        },
+        {
+            "name": "c1 pop bc",
+            "initial": {"pc": 49152, "sp": 53248, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0,
+                        "ram": [[49152, 193], [53248, 52], [53249, 18]]},
+            "final": {"pc": 49153, "sp": 53250, "a": 0, "f": 0, "b": 18, "c": 52, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ram": []},
+            "cycles": [[49152, 193, "r-m"], [53248, 52, "r-m"], [53249, 18, "r-m"]]
+        },
+        {
+            "name": "e0 ldh (n), a",
+            "initial": {"pc": 49152, "sp": 65534, "a": 90, "f": 0, "b": 0, "c": 0, "d": 0,
+                        "e": 0, "h": 0, "l": 0, "ram": [[49152, 224], [49153, 128]]},
+            "final": {"pc": 49154, "sp": 65534, "a": 90, "f": 0, "b": 0, "c": 0, "d": 0,
+                      "e": 0, "h": 0, "l": 0, "ram": [[65408, 90]]},
+            "cycles": [[49152, 224, "r-m"], [49153, 128, "r-m"], [65408, 90, "-wm"]]
+        }
+    ]"#;
+
+    run_inline_vectors(tests);
+}
+
+fn run_inline_vectors(tests: &str) {
+    let tests: Value = serde_json::from_str(tests).unwrap();
+
+    for test in tests.as_array().unwrap() {
+        if let Err(e) = run_test(test) {
+            panic!("{}: {}", test["name"], e);
+        }
+    }
+}
+
+#[test]
+fn test_sm83_vectors() {
+    let dir = env::var("SM83_TESTS_DIR").unwrap_or_else(|_| DEFAULT_TESTS_DIR.to_string());
+    let dir = Path::new(&dir);
+
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+            .collect::<Vec<_>>(),
+        Err(_) => {
+            println!("Skipping sm83 tests: {} not found.", dir.display());
+            return;
+        }
+    };
+    paths.sort();
+
+    let mut total = 0;
+    let mut failures = vec![];
+
+    for path in paths.iter() {
+        match run_file(path) {
+            Ok((count, file_failures)) => {
+                total += count;
+                failures.extend(file_failures);
+            }
+            Err(e) => failures.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    for failure in failures.iter().take(MAX_REPORTED_FAILURES) {
+        println!("{}", failure);
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} sm83 tests failed",
+        failures.len(),
+        total
+    );
+}
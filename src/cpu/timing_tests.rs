@@ -0,0 +1,265 @@
+// References:
+// - https://github.com/retrio/gb-test-roms (Blargg's instr_timing and mem_timing)
+// - https://github.com/Gekkio/mooneye-test-suite
+//
+// Checks in which M-cycle the CPU touches the bus, and how OAM DMA, HDMA and
+// interrupt dispatch line up with it.
+//
+// The test ROMs are not checked in, so running them is opt-in: point
+// `TIMING_ROMS_DIR` at a directory laid out like `ROMS` below (or place it at
+// tests/roms) and run `cargo test -- --ignored`. ROMs that are missing are
+// skipped, but at least one has to be there. The other tests here pin down
+// the DMA and interrupt timing without any ROMs.
+//
+// Both suites report over the serial port: Blargg's print text ending in
+// "Passed" or "Failed", Mooneye's send the Fibonacci numbers 3 5 8 13 21 34 on
+// success and 0x42 six times on failure.
+
+use super::*;
+use crate::serial::SerialDevice;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const DEFAULT_ROMS_DIR: &str = "tests/roms";
+/// Enough for the slowest of the ROMs, which finish within a few seconds.
+const MAX_CYCLES: u64 = 30 * 4_194_304;
+
+const ROMS: &[&str] = &[
+    "blargg/instr_timing/instr_timing.gb",
+    "blargg/mem_timing/individual/01-read_timing.gb",
+    "blargg/mem_timing/individual/02-write_timing.gb",
+    "blargg/mem_timing/individual/03-modify_timing.gb",
+    "mooneye/acceptance/add_sp_e_timing.gb",
+    "mooneye/acceptance/call_cc_timing.gb",
+    "mooneye/acceptance/call_timing.gb",
+    "mooneye/acceptance/di_timing-GS.gb",
+    "mooneye/acceptance/ei_timing.gb",
+    "mooneye/acceptance/halt_ime0_nointr_timing.gb",
+    "mooneye/acceptance/halt_ime1_timing.gb",
+    "mooneye/acceptance/intr_timing.gb",
+    "mooneye/acceptance/jp_cc_timing.gb",
+    "mooneye/acceptance/jp_timing.gb",
+    "mooneye/acceptance/ld_hl_sp_e_timing.gb",
+    "mooneye/acceptance/oam_dma_timing.gb",
+    "mooneye/acceptance/pop_timing.gb",
+    "mooneye/acceptance/push_timing.gb",
+    "mooneye/acceptance/ret_cc_timing.gb",
+    "mooneye/acceptance/ret_timing.gb",
+    "mooneye/acceptance/reti_timing.gb",
+    "mooneye/acceptance/rst_timing.gb",
+];
+
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+/// Keeps every byte the ROM sends.
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl SerialDevice for Capture {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.0.borrow_mut().push(byte);
+        0xFF
+    }
+}
+
+fn verdict(output: &[u8]) -> Option<Result<(), String>> {
+    if output.ends_with(&MOONEYE_PASS) {
+        return Some(Ok(()));
+    }
+    if output.ends_with(&MOONEYE_FAIL) {
+        return Some(Err("failed".to_string()));
+    }
+
+    let text = String::from_utf8_lossy(output);
+    if text.contains("Passed") {
+        Some(Ok(()))
+    } else if text.contains("Failed") {
+        Some(Err(text.trim().to_string()))
+    } else {
+        None
+    }
+}
+
+fn run_rom(path: &Path) -> Result<(), String> {
+    let rom = fs::read(path).map_err(|e| e.to_string())?;
+    let output = Rc::new(RefCell::new(vec![]));
+
+    let mut cpu = Cpu::new(rom);
+    cpu.simulate_bootrom();
+    cpu.mmu
+        .serial
+        .connect(Rc::new(RefCell::new(Capture(output.clone()))));
+
+    while cpu.total_cycles < MAX_CYCLES {
+        cpu.tick();
+        if let Some(result) = verdict(&output.borrow()) {
+            return result;
+        }
+    }
+
+    Err(format!(
+        "timed out, output: {:?}",
+        String::from_utf8_lossy(&output.borrow())
+    ))
+}
+
+#[test]
+#[ignore = "needs the Blargg and Mooneye timing ROMs, see TIMING_ROMS_DIR"]
+fn test_timing_suites() {
+    let dir = env::var("TIMING_ROMS_DIR").unwrap_or_else(|_| DEFAULT_ROMS_DIR.to_string());
+    let dir = Path::new(&dir);
+
+    let paths: Vec<_> = ROMS
+        .iter()
+        .map(|rom| dir.join(rom))
+        .filter(|path| path.exists())
+        .collect();
+    assert!(
+        !paths.is_empty(),
+        "no timing test ROMs found in {}",
+        dir.display()
+    );
+
+    let failures: Vec<String> = paths
+        .iter()
+        .filter_map(|path| {
+            run_rom(path)
+                .err()
+                .map(|e| format!("{}: {}", path.display(), e))
+        })
+        .collect();
+
+    for failure in failures.iter() {
+        println!("{}", failure);
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} timing test ROMs failed",
+        failures.len(),
+        paths.len()
+    );
+}
+
+#[test]
+fn test_verdict() {
+    assert_eq!(verdict(b"instr_timing\n\n"), None);
+    assert_eq!(verdict(b"instr_timing\n\nPassed\n"), Some(Ok(())));
+    assert!(verdict(b"01-read_timing\n\nFailed #2\n").unwrap().is_err());
+    assert_eq!(verdict(&MOONEYE_PASS), Some(Ok(())));
+    assert!(verdict(&MOONEYE_FAIL).unwrap().is_err());
+}
+
+/// A CPU at 0100 with the LCD off, so that the PPU doesn't lock OAM or VRAM.
+fn idle_cpu(model: Model, code: &[u8]) -> Cpu {
+    let mut rom = vec![0; 0x8000];
+    if model.is_cgb() {
+        rom[0x143] = 0x80;
+    }
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+
+    let mut cpu = Cpu::with_model(rom, model);
+    cpu.simulate_bootrom();
+    cpu.mmu.set_byte(0xFF40, 0x00);
+    cpu
+}
+
+#[test]
+fn test_interrupt_dispatch_timing() {
+    let mut cpu = idle_cpu(Model::Dmg, &[]);
+    cpu.ime = true;
+    cpu.mmu.ie = 0x05;
+    cpu.mmu.set_interrupt_flags(0x04);
+
+    // Two wait states, the two pushes and the jump.
+    assert_eq!(cpu.tick(), 20);
+    assert_eq!(cpu.pc, 0x50);
+    assert_eq!(cpu.sp, 0xFFFC);
+    assert_eq!(cpu.mmu.get_byte(0xFFFD), 0x01);
+    assert_eq!(cpu.mmu.get_byte(0xFFFC), 0x00);
+    assert_eq!(cpu.mmu.interrupt_flags() & 0x1F, 0x00);
+
+    // Pushing the upper byte of PC to IE clears the timer bit before the
+    // vector is chosen, which cancels the dispatch but takes as long.
+    let mut cpu = idle_cpu(Model::Dmg, &[]);
+    cpu.ime = true;
+    cpu.pc = 0x0200;
+    cpu.sp = 0x0000;
+    cpu.mmu.ie = 0x04;
+    cpu.mmu.set_interrupt_flags(0x04);
+
+    assert_eq!(cpu.tick(), 20);
+    assert_eq!(cpu.pc, 0x0000);
+    assert_eq!(cpu.mmu.ie, 0x02);
+    assert_eq!(cpu.mmu.interrupt_flags() & 0x1F, 0x04);
+}
+
+#[test]
+fn test_oam_dma_timing() {
+    // LD A,$C0; LDH ($46),A
+    let mut cpu = idle_cpu(Model::Dmg, &[0x3E, 0xC0, 0xE0, 0x46]);
+    for i in 0..160 {
+        cpu.mmu.set_byte(0xC000 + i, i as u8 + 1);
+    }
+    cpu.tick();
+    assert_eq!(cpu.tick(), 12);
+
+    // After the M-cycle following the write, which sets up the transfer, OAM
+    // reads as FF for the 160 M-cycles of copying, one byte each. Reading here
+    // stands in for the next instruction's first M-cycle.
+    for _ in 0..160 {
+        cpu.tick();
+        assert_eq!(cpu.mmu.get_byte(0xFE00), 0xFF);
+    }
+    cpu.tick();
+    assert_eq!(cpu.mmu.get_byte(0xFE00), 0x01);
+    assert_eq!(cpu.mmu.get_byte(0xFE9F), 0xA0);
+    assert!(!cpu.mmu.oam_dma.active);
+}
+
+#[test]
+fn test_hdma_timing() {
+    let mut cpu = idle_cpu(Model::Cgb, &[]);
+    for i in 0..0x30 {
+        cpu.mmu.set_byte(0xC000 + i, i as u8 + 1);
+    }
+    for (addr, value) in [
+        (0xFF51, 0xC0),
+        (0xFF52, 0x00),
+        (0xFF53, 0x00),
+        (0xFF54, 0x00),
+    ] {
+        cpu.mmu.set_byte(addr, value);
+    }
+
+    // A general purpose DMA of two blocks halts the CPU for 8 M-cycles a
+    // block, two bytes each.
+    cpu.mmu.set_byte(0xFF55, 0x01);
+    for _ in 0..15 {
+        assert_eq!(cpu.tick(), 4);
+        assert_eq!(cpu.pc, 0x100);
+    }
+    assert_eq!(cpu.mmu.get_byte(0x801F), 0x00);
+    cpu.tick();
+    assert_eq!(cpu.mmu.get_byte(0x801F), 0x20);
+    assert_eq!(cpu.mmu.get_byte(0xFF55), 0xFF);
+    cpu.tick();
+    assert_eq!(cpu.pc, 0x101);
+
+    // A HBlank DMA copies one block per HBlank, taking as long.
+    cpu.mmu.set_byte(0xFF55, 0x80);
+    assert_eq!(cpu.mmu.get_byte(0xFF55), 0x00);
+    cpu.mmu.gpu.hdma_flag = true;
+    for _ in 0..8 {
+        assert_eq!(cpu.pc, 0x101);
+        cpu.tick();
+    }
+    assert!(!cpu.mmu.gpu.hdma_flag);
+    assert_eq!(cpu.mmu.get_byte(0x802F), 0x30);
+    assert_eq!(cpu.mmu.get_byte(0xFF55), 0xFF);
+    cpu.tick();
+    assert_eq!(cpu.pc, 0x102);
+}
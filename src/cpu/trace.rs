@@ -0,0 +1,344 @@
+// References: https://github.com/robert/gameboy-doctor
+//
+// Per-instruction trace logging in the gameboy-doctor line format:
+//
+//   A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD
+//
+// Each line describes the CPU state right before the instruction at PC is executed.
+// An optional CYC column holding the total number of elapsed cycles can be appended.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// A snapshot of the CPU state right before an instruction is executed.
+pub struct TraceEntry {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub pcmem: [u8; 4],
+    pub cycles: u64,
+}
+
+impl TraceEntry {
+    pub fn format(&self, cycles_column: bool) -> String {
+        let mut line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.a,
+            self.f,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.sp,
+            self.pc,
+            self.pcmem[0],
+            self.pcmem[1],
+            self.pcmem[2],
+            self.pcmem[3],
+        );
+
+        if cycles_column {
+            line.push_str(&format!(" CYC:{}", self.cycles));
+        }
+
+        line
+    }
+}
+
+/// Restricts which instructions get logged.
+#[derive(Default)]
+pub struct TraceFilter {
+    /// Only log instructions whose address lies in this range.
+    pub pc_range: Option<RangeInclusive<u16>>,
+    /// Only log instructions executed from this ROM bank.
+    pub bank: Option<u16>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, bank: u16) -> bool {
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&pc) {
+                return false;
+            }
+        }
+
+        match self.bank {
+            Some(b) => b == bank,
+            None => true,
+        }
+    }
+}
+
+pub enum TraceSink {
+    /// Keeps the last `capacity` lines in memory.
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+    },
+    /// Streams every line to a writer, usually a file.
+    Writer(Box<dyn Write>),
+}
+
+impl TraceSink {
+    pub fn ring(capacity: usize) -> Self {
+        TraceSink::Ring {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(TraceSink::Writer(Box::new(BufWriter::new(file))))
+    }
+}
+
+pub struct Tracer {
+    pub sink: TraceSink,
+    pub filter: TraceFilter,
+    pub cycles_column: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(sink: TraceSink) -> Self {
+        Self {
+            sink,
+            filter: TraceFilter::default(),
+            cycles_column: false,
+            error: None,
+        }
+    }
+
+    pub fn wants(&self, pc: u16, bank: u16) -> bool {
+        self.error.is_none() && self.filter.matches(pc, bank)
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) {
+        let line = entry.format(self.cycles_column);
+
+        match &mut self.sink {
+            TraceSink::Ring { lines, capacity } => {
+                if *capacity == 0 {
+                    return;
+                }
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+            TraceSink::Writer(writer) => {
+                if let Err(e) = writeln!(writer, "{}", line) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// The lines currently held by a ring sink, oldest first.
+    pub fn lines(&self) -> Vec<&str> {
+        match &self.sink {
+            TraceSink::Ring { lines, .. } => lines.iter().map(|l| l.as_str()).collect(),
+            TraceSink::Writer(_) => vec![],
+        }
+    }
+
+    /// Flushes the sink and reports the first write error, if any.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        match &mut self.sink {
+            TraceSink::Writer(writer) => writer.flush(),
+            TraceSink::Ring { .. } => Ok(()),
+        }
+    }
+}
+
+/// The first line at which two traces disagree.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// 1-based line number.
+    pub line: usize,
+    pub ours: Option<String>,
+    pub reference: Option<String>,
+    /// Names of the columns that differ, e.g. `["A", "F"]`.
+    pub fields: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Traces diverge at line {}", self.line)?;
+        if !self.fields.is_empty() {
+            writeln!(f, "Mismatched fields: {}", self.fields.join(", "))?;
+        }
+        writeln!(
+            f,
+            "  ours:      {}",
+            self.ours.as_deref().unwrap_or("<end of trace>")
+        )?;
+        write!(
+            f,
+            "  reference: {}",
+            self.reference.as_deref().unwrap_or("<end of trace>")
+        )
+    }
+}
+
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split_whitespace()
+        .filter_map(|field| {
+            let mut parts = field.splitn(2, ':');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect()
+}
+
+/// Compares two trace lines column by column. Columns that only appear in one of
+/// the lines (such as CYC) are ignored. Returns the names of mismatched columns.
+pub fn compare_lines(ours: &str, reference: &str) -> Vec<String> {
+    let ours = fields(ours);
+    let reference = fields(reference);
+
+    reference
+        .iter()
+        .filter_map(|(key, value)| {
+            let (_, our_value) = ours.iter().find(|(k, _)| k == key)?;
+            if our_value.eq_ignore_ascii_case(value) {
+                None
+            } else {
+                Some(key.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Walks two traces in lockstep and reports the first line that differs. A trace
+/// ending early also counts as a divergence.
+pub fn diff_traces<A: BufRead, B: BufRead>(
+    ours: A,
+    reference: B,
+) -> io::Result<Option<Divergence>> {
+    let mut ours = ours.lines();
+    let mut reference = reference.lines();
+    let mut line = 0;
+
+    loop {
+        line += 1;
+
+        match (ours.next().transpose()?, reference.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (Some(o), Some(r)) => {
+                let fields = compare_lines(&o, &r);
+                if !fields.is_empty() {
+                    return Ok(Some(Divergence {
+                        line,
+                        ours: Some(o),
+                        reference: Some(r),
+                        fields,
+                    }));
+                }
+            }
+            (o, r) => {
+                return Ok(Some(Divergence {
+                    line,
+                    ours: o,
+                    reference: r,
+                    fields: vec![],
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pc: u16) -> TraceEntry {
+        TraceEntry {
+            a: 0x01,
+            f: 0xB0,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            h: 0x01,
+            l: 0x4D,
+            sp: 0xFFFE,
+            pc,
+            pcmem: [0x00, 0xC3, 0x13, 0x02],
+            cycles: 1234,
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            entry(0x100).format(false),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+        assert!(entry(0x100).format(true).ends_with(" CYC:1234"));
+    }
+
+    #[test]
+    fn test_ring_and_filter() {
+        let mut tracer = Tracer::new(TraceSink::ring(2));
+        tracer.filter.pc_range = Some(0x100..=0x1FF);
+
+        for pc in [0x100, 0x101, 0x300, 0x102].iter() {
+            if tracer.wants(*pc, 0) {
+                tracer.record(&entry(*pc));
+            }
+        }
+
+        let lines = tracer.lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("PC:0101"));
+        assert!(lines[1].contains("PC:0102"));
+
+        tracer.filter.bank = Some(3);
+        assert!(!tracer.wants(0x100, 1));
+        assert!(tracer.wants(0x100, 3));
+    }
+
+    #[test]
+    fn test_diff_traces() {
+        let a = entry(0x100).format(true);
+        let b = entry(0x101).format(false);
+        let ours = format!("{}\n{}\n", a, b);
+        let reference = format!(
+            "{}\n{}\n",
+            entry(0x100).format(false),
+            entry(0x102).format(false)
+        );
+
+        let divergence = diff_traces(ours.as_bytes(), reference.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.fields, vec!["PC".to_string()]);
+
+        let same = diff_traces(a.as_bytes(), entry(0x100).format(false).as_bytes()).unwrap();
+        assert_eq!(same, None);
+
+        let short = diff_traces("".as_bytes(), b.as_bytes()).unwrap().unwrap();
+        assert_eq!(short.line, 1);
+        assert_eq!(short.ours, None);
+    }
+}
@@ -1,6 +1,29 @@
 use crate::apu::queue::BUFFER_SIZE;
+use crate::cpu::callstack::{CallStack, SymbolTable};
+use crate::cpu::profiler::Profiler;
+use crate::cpu::rewind::History;
 use crate::cpu::Cpu;
 use crate::events::Event;
+use crate::gpu::blend::BlendMode;
+use crate::gpu::color::ColorCorrection;
+use crate::gpu::compat::PaletteCombo;
+use crate::gpu::palette::{DmgPalette, PRESETS};
+use crate::infrared::InfraredLink;
+use crate::input::keymap::KeyMap;
+use crate::input::Input;
+use crate::joypad::{Key, MAX_PLAYERS};
+use crate::memory::bootrom::Bootrom;
+use crate::memory::cdl::CodeDataLog;
+use crate::memory::heatmap::{Counters, Heatmap};
+use crate::serial::barcode::BarcodeBoy;
+use crate::serial::link::{self, Lockstep};
+use crate::serial::mobile::{LocalServer, MobileAdapter};
+use crate::serial::printer::{PrintedImage, Printer};
+use crate::serial::SerialDevice;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use web_sys::AudioContext;
 
@@ -10,9 +33,23 @@ const NUM_AUDIO_CHANNELS: u32 = 2;
 const SAMPLE_DURATION: f64 = BUFFER_SIZE as f64 / AUDIO_SAMPLE_RATE as f64;
 const LATENCY: f64 = 0.000;
 
+/// What is plugged into the link port.
+enum Peripheral {
+    /// A link cable to another emulator.
+    Link,
+    Printer(Rc<RefCell<Printer>>),
+    BarcodeBoy(Rc<RefCell<BarcodeBoy>>),
+    MobileAdapter(Rc<RefCell<LocalServer>>),
+}
+
 #[wasm_bindgen]
 pub struct Emulator {
     cpu: Cpu,
+    keymap: KeyMap,
+    input: Input,
+    lockstep: Lockstep,
+    peripheral: Option<Peripheral>,
+    printed: VecDeque<PrintedImage>,
     ctx: AudioContext,
     next_start_time: Option<f64>,
     left_audio: Vec<f32>,
@@ -23,14 +60,56 @@ pub struct Emulator {
 impl Emulator {
     pub fn new(data: Vec<u8>) -> Self {
         let mut cpu = Cpu::new(data);
+        cpu.simulate_bootrom();
 
-        let ctx = AudioContext::new().unwrap();
+        Self::with_cpu(cpu)
+    }
 
+    /// Emulates one of "dmg", "mgb", "sgb", "sgb2", "cgb" or "agb" instead of
+    /// the model the cartridge asks for.
+    pub fn with_model(data: Vec<u8>, model: &str) -> Result<Emulator, JsValue> {
+        let model = model.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let mut cpu = Cpu::with_model(data, model);
         cpu.simulate_bootrom();
-        // cpu.emulate_bootrom();
+
+        Ok(Self::with_cpu(cpu))
+    }
+
+    /// Starts by running a user supplied boot ROM dump instead of skipping
+    /// straight to the cartridge. `model` is the model it was dumped from, as
+    /// for `with_model`, and the dump has to be a known one for that model.
+    pub fn with_bootrom(data: Vec<u8>, bootrom: Vec<u8>, model: &str) -> Result<Emulator, JsValue> {
+        let model = model.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let bootrom =
+            Bootrom::verified(bootrom, model).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        Ok(Self::with_cpu(Cpu::with_bootrom(data, bootrom)))
+    }
+
+    /// Like `with_bootrom`, but also runs modified or homebrew boot ROMs. Only
+    /// the size of the image is checked.
+    pub fn with_custom_bootrom(
+        data: Vec<u8>,
+        bootrom: Vec<u8>,
+        model: &str,
+    ) -> Result<Emulator, JsValue> {
+        let model = model.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let bootrom =
+            Bootrom::new(bootrom, model).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        Ok(Self::with_cpu(Cpu::with_bootrom(data, bootrom)))
+    }
+
+    fn with_cpu(cpu: Cpu) -> Self {
+        let ctx = AudioContext::new().unwrap();
 
         Emulator {
             cpu,
+            keymap: KeyMap::default(),
+            input: Input::new(),
+            lockstep: Lockstep::new(),
+            peripheral: None,
+            printed: VecDeque::new(),
             ctx,
             next_start_time: None,
             left_audio: vec![0.0; BUFFER_SIZE],
@@ -39,8 +118,171 @@ impl Emulator {
     }
 
     pub fn run_till_event(&mut self, max_cycles: usize) -> f64 {
-        match self.cpu.run_till_event(max_cycles) {
-            Event::VBlank => 0.0,
+        let event = self.cpu.run_till_event(max_cycles);
+        self.handle_event(event)
+    }
+
+    /// Plugs a link cable between this emulator and `other`. Both then have
+    /// to be run with `run_linked`.
+    pub fn link(&mut self, other: &mut Emulator) {
+        self.unplug();
+        other.unplug();
+        link::connect(&mut self.cpu, &mut other.cpu);
+        self.peripheral = Some(Peripheral::Link);
+        other.peripheral = Some(Peripheral::Link);
+    }
+
+    /// Points the IR ports of this emulator and `other` at each other. Both
+    /// then have to be run with `run_linked`.
+    pub fn link_infrared(&mut self, other: &mut Emulator) {
+        let (a, b) = InfraredLink::pair();
+        self.cpu.mmu.infrared.connect(a);
+        other.cpu.mmu.infrared.connect(b);
+    }
+
+    pub fn unlink_infrared(&mut self, other: &mut Emulator) {
+        self.cpu.mmu.infrared.disconnect();
+        other.cpu.mmu.infrared.disconnect();
+    }
+
+    pub fn unlink(&mut self, other: &mut Emulator) {
+        for emulator in [self, other] {
+            if let Some(Peripheral::Link) = emulator.peripheral {
+                emulator.unplug();
+            }
+        }
+    }
+
+    /// Plugs `device` into the link port in place of whatever was there.
+    fn plug(&mut self, device: Rc<RefCell<dyn SerialDevice>>, peripheral: Peripheral) {
+        self.unplug();
+        self.cpu.mmu.serial.connect(device);
+        self.peripheral = Some(peripheral);
+    }
+
+    fn unplug(&mut self) {
+        if let Some(Peripheral::Printer(printer)) = &self.peripheral {
+            // Pages still in the printer can be taken after unplugging it.
+            self.printed.extend(printer.borrow_mut().take_pages());
+        }
+        self.cpu.mmu.serial.disconnect();
+        self.peripheral = None;
+    }
+
+    /// Plugs a Game Boy Printer into the link port.
+    pub fn connect_printer(&mut self) {
+        let printer = Rc::new(RefCell::new(Printer::new()));
+        self.plug(printer.clone(), Peripheral::Printer(printer));
+    }
+
+    pub fn disconnect_printer(&mut self) {
+        if let Some(Peripheral::Printer(_)) = self.peripheral {
+            self.unplug();
+        }
+    }
+
+    /// Takes the oldest page the printer finished, as 160 pixel wide rows of
+    /// one grey byte per pixel. Empty if there is none.
+    pub fn take_printed_page(&mut self) -> Vec<u8> {
+        if let Some(Peripheral::Printer(printer)) = &self.peripheral {
+            self.printed.extend(printer.borrow_mut().take_pages());
+        }
+        self.printed
+            .pop_front()
+            .map(|page| page.pixels)
+            .unwrap_or_default()
+    }
+
+    /// Plugs a Barcode Boy into the link port.
+    pub fn connect_barcode_boy(&mut self) {
+        let reader = Rc::new(RefCell::new(BarcodeBoy::new()));
+        self.plug(reader.clone(), Peripheral::BarcodeBoy(reader));
+    }
+
+    /// Swipes a card with a 13 digit barcode through the Barcode Boy.
+    pub fn scan_barcode(&mut self, code: &str) -> Result<(), JsValue> {
+        let reader = match &self.peripheral {
+            Some(Peripheral::BarcodeBoy(reader)) => reader,
+            _ => return Err(JsValue::from_str("no Barcode Boy connected")),
+        };
+        reader
+            .borrow_mut()
+            .scan(code)
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    /// Plugs in a Mobile Adapter GB that answers from a local fake network.
+    /// `hosts` lists "name=a.b.c.d" entries, one per line, the game can
+    /// look up and connect to.
+    pub fn connect_mobile_adapter(&mut self, hosts: &str) -> Result<(), JsValue> {
+        let mut server = LocalServer::new();
+        for line in hosts.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (name, ip) = line
+                .split_once('=')
+                .and_then(|(name, ip)| Some((name.trim(), ip.trim().parse::<Ipv4Addr>().ok()?)))
+                .ok_or_else(|| JsValue::from_str(&format!("invalid host {:?}", line)))?;
+            server.add_host(name, ip.octets());
+        }
+
+        let server = Rc::new(RefCell::new(server));
+        let adapter = MobileAdapter::new(server.clone());
+        self.plug(
+            Rc::new(RefCell::new(adapter)),
+            Peripheral::MobileAdapter(server),
+        );
+        Ok(())
+    }
+
+    /// Queues the reply to the next transfer the game sends through the
+    /// Mobile Adapter.
+    pub fn queue_mobile_response(&mut self, data: Vec<u8>) -> Result<(), JsValue> {
+        match &self.peripheral {
+            Some(Peripheral::MobileAdapter(server)) => {
+                server.borrow_mut().queue_response(&data);
+                Ok(())
+            }
+            _ => Err(JsValue::from_str("no Mobile Adapter connected")),
+        }
+    }
+
+    /// Takes the oldest transfer the game sent through the Mobile Adapter.
+    /// Empty if there is none.
+    pub fn take_mobile_request(&mut self) -> Vec<u8> {
+        match &self.peripheral {
+            Some(Peripheral::MobileAdapter(server)) => {
+                server.borrow_mut().take_request().unwrap_or_default()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Like `run_till_event`, running `other` in lockstep. Only this
+    /// emulator's events are returned; `other`'s audio is dropped and its
+    /// screen can be drawn whenever this one's is.
+    pub fn run_linked(&mut self, other: &mut Emulator, max_cycles: usize) -> f64 {
+        let input = &mut other.input;
+        let event = self.lockstep.run_till_event(
+            &mut self.cpu,
+            &mut other.cpu,
+            max_cycles,
+            |cpu, event| {
+                if let Event::VBlank = event {
+                    input.advance();
+                    apply_input(cpu, input);
+                }
+            },
+        );
+        self.handle_event(event)
+    }
+
+    fn handle_event(&mut self, event: Event) -> f64 {
+        match event {
+            Event::VBlank => {
+                self.input.advance();
+                self.apply_input();
+
+                0.0
+            }
             Event::AudioBufferFull(left, right) => {
                 for i in 0..BUFFER_SIZE {
                     self.left_audio[i] = left[i];
@@ -50,9 +292,62 @@ impl Emulator {
                 1.0
             }
             Event::MaxCycles => 2.0,
+            Event::CpuLocked => 3.0,
         }
     }
 
+    /// Selects one of the 12 DMG compatibility palettes, in the order Up, Up+A,
+    /// Up+B, Left, Left+A, Left+B, Down, Down+A, Down+B, Right, Right+A, Right+B.
+    pub fn select_compat_palette(&mut self, combo: usize) {
+        if let Some(combo) = PaletteCombo::ALL.get(combo) {
+            self.cpu.select_compat_palette(*combo);
+        }
+    }
+
+    /// The names of the built in DMG palettes, one per line.
+    pub fn dmg_palette_presets() -> String {
+        let names: Vec<_> = PRESETS.iter().map(|(name, _)| *name).collect();
+        names.join("\n")
+    }
+
+    /// Shows DMG games in one of the built in palettes, e.g. "pocket".
+    pub fn set_dmg_palette_preset(&mut self, name: &str) -> Result<(), JsValue> {
+        let palette = DmgPalette::preset(name)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown palette {:?}", name)))?;
+        self.cpu.mmu.gpu.set_dmg_palette(palette);
+        Ok(())
+    }
+
+    /// Shows DMG games in the colours of a JASC-PAL file or hex colour list,
+    /// with 4 colours for everything or 12 for BG, OBJ0 and OBJ1.
+    pub fn load_dmg_palette(&mut self, text: &str) -> Result<(), JsValue> {
+        let palette = DmgPalette::parse(text).map_err(|e| JsValue::from_str(&e))?;
+        self.cpu.mmu.gpu.set_dmg_palette(palette);
+        Ok(())
+    }
+
+    /// Selects how CGB colours are shown: "raw", "cgb", "reduce-contrast" or
+    /// "agb".
+    pub fn set_color_correction(&mut self, mode: &str) -> Result<(), JsValue> {
+        let mode: ColorCorrection = mode.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.cpu.mmu.gpu.set_color_correction(mode);
+        Ok(())
+    }
+
+    /// Simulates LCD ghosting: "off", "mix" to blend each frame with the last
+    /// one, or "decay" to fade every frame out exponentially. `strength` is the
+    /// weight of the previous frames, from 0 to 255.
+    pub fn set_frame_blend(&mut self, mode: &str, strength: u8) -> Result<(), JsValue> {
+        let mode: BlendMode = mode.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.cpu.mmu.gpu.set_frame_blend(mode, strength);
+        Ok(())
+    }
+
+    /// Whether the game crashed by executing an unused opcode.
+    pub fn cpu_locked(&self) -> bool {
+        self.cpu.locked()
+    }
+
     pub fn audio_buffer_left(&self) -> *const f32 {
         self.left_audio.as_ptr()
     }
@@ -65,11 +360,287 @@ impl Emulator {
         self.cpu.screen()
     }
 
+    /// Whether `sgb_screen` should be shown instead of `screen`.
+    pub fn has_sgb(&self) -> bool {
+        self.cpu.sgb_screen().is_some()
+    }
+
+    /// The 256x224 RGBA picture with the border, or null without an SGB.
+    pub fn sgb_screen(&self) -> *const u8 {
+        self.cpu.sgb_screen().unwrap_or(std::ptr::null())
+    }
+
     pub fn keyup(&mut self, key: usize) {
-        self.cpu.keyup(key);
+        self.player_keyup(0, key);
     }
 
     pub fn keydown(&mut self, key: usize) {
-        self.cpu.keydown(key);
+        self.player_keydown(0, key);
+    }
+
+    /// Like `keyup`, for controllers 2-4 of an SGB multiplayer game.
+    pub fn player_keyup(&mut self, player: usize, key: usize) {
+        if let Some(key) = Key::from_index(key) {
+            self.input.release(player, key);
+            self.apply_input();
+        }
+    }
+
+    pub fn player_keydown(&mut self, player: usize, key: usize) {
+        if let Some(key) = Key::from_index(key) {
+            self.input.press(player, key);
+            self.apply_input();
+        }
+    }
+
+    /// Sets every button of a controller at once: bits 0-7 are right, left,
+    /// up, down, A, B, select and start (1=Pressed).
+    pub fn set_buttons(&mut self, player: usize, state: u8) {
+        self.input.set_held(player, state);
+        self.apply_input();
+    }
+
+    /// Presses the button bound to the host input `host` in the keymap, or
+    /// starts the macro bound to it. Returns false if nothing is bound to it.
+    pub fn host_keydown(&mut self, host: &str) -> bool {
+        if self.input.trigger(host) {
+            self.apply_input();
+            return true;
+        }
+
+        match self.keymap.lookup(host) {
+            Some((player, key)) => {
+                self.input.press(player, key);
+                self.apply_input();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn host_keyup(&mut self, host: &str) -> bool {
+        match self.keymap.lookup(host) {
+            Some((player, key)) => {
+                self.input.release(player, key);
+                self.apply_input();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes `button` fire on and off every `period` frames while it is held,
+    /// or stops it if `period` is 0.
+    pub fn set_turbo(&mut self, player: usize, button: &str, period: u32) -> Result<(), JsValue> {
+        let key = button.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.input.turbo.set(player, key, period);
+        Ok(())
+    }
+
+    /// Plays `script` on controller `player` whenever `host` is pressed. See
+    /// `input::macros` for the script format, e.g. "down*8, a+b*2, _*4, start".
+    pub fn bind_macro(&mut self, host: &str, player: usize, script: &str) -> Result<(), JsValue> {
+        let script = script.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.input.bind_macro(host, player, script);
+        Ok(())
+    }
+
+    pub fn unbind_macro(&mut self, host: &str) {
+        self.input.unbind_macro(host);
+    }
+
+    /// Whether a macro is still being played back.
+    pub fn macro_playing(&self) -> bool {
+        self.input.playing()
+    }
+
+    pub fn stop_macros(&mut self) {
+        self.input.stop_macros();
+        self.apply_input();
+    }
+
+    fn apply_input(&mut self) {
+        apply_input(&mut self.cpu, &self.input);
+    }
+
+    /// Binds `host` to `button` ("up", "a", "start", ...) on controller
+    /// `player`, counting from 0.
+    pub fn bind_key(&mut self, host: &str, player: usize, button: &str) -> Result<(), JsValue> {
+        let key = button.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        self.keymap
+            .bind(host, player, key)
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    pub fn unbind_key(&mut self, host: &str) {
+        self.keymap.unbind(host);
+    }
+
+    /// The keymap in its text format, for saving it.
+    pub fn keymap(&self) -> String {
+        self.keymap.to_string()
+    }
+
+    pub fn set_keymap(&mut self, keymap: &str) -> Result<(), JsValue> {
+        self.keymap = keymap.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        Ok(())
+    }
+
+    /// Starts tracking calls so that `backtrace` has something to show. `symbols`
+    /// holds the contents of a `.sym` file and may be empty.
+    pub fn enable_backtrace(&mut self, symbols: &str) {
+        let symbols = SymbolTable::parse(symbols);
+        self.cpu
+            .set_callstack(Some(CallStack::with_symbols(symbols)));
+    }
+
+    pub fn disable_backtrace(&mut self) {
+        self.cpu.set_callstack(None);
+    }
+
+    pub fn backtrace(&self) -> String {
+        match self.cpu.backtrace() {
+            Some(backtrace) => backtrace.to_string(),
+            None => String::from("Backtraces are disabled."),
+        }
+    }
+
+    /// Starts profiling. With `per_frame` set, `profile` reports the last
+    /// complete frame instead of everything since profiling started.
+    pub fn enable_profiler(&mut self, per_frame: bool) {
+        let mut profiler = Profiler::new();
+        profiler.per_frame = per_frame;
+        self.cpu.set_profiler(Some(profiler));
+    }
+
+    pub fn disable_profiler(&mut self) {
+        self.cpu.set_profiler(None);
+    }
+
+    pub fn reset_profiler(&mut self) {
+        if let Some(profiler) = self.cpu.profiler_mut() {
+            profiler.reset();
+        }
+    }
+
+    /// The profile in folded-stack format, ready to be saved for flamegraph tools.
+    pub fn profile(&self) -> String {
+        let (profiler, callstack) = match (self.cpu.profiler(), self.cpu.callstack()) {
+            (Some(profiler), Some(callstack)) => (profiler, callstack),
+            _ => return String::new(),
+        };
+
+        let profile = match profiler.per_frame {
+            true => profiler.last_frame(),
+            false => Some(profiler.profile()),
+        };
+
+        profile
+            .map(|profile| profile.folded(&callstack.symbols))
+            .unwrap_or_default()
+    }
+
+    /// Starts recording which ROM and cartridge RAM bytes are executed or read.
+    pub fn enable_code_data_log(&mut self) {
+        let cartridge = &self.cpu.mmu.cartridge;
+        let cdl = CodeDataLog::new(cartridge.rom_size(), cartridge.ram_size());
+        self.cpu.mmu.cdl = Some(cdl);
+    }
+
+    pub fn disable_code_data_log(&mut self) {
+        self.cpu.mmu.cdl = None;
+    }
+
+    /// The contents of a `.cdl` file for the log recorded so far.
+    pub fn code_data_log(&self) -> Vec<u8> {
+        let mut file = vec![];
+        if let Some(cdl) = &self.cpu.mmu.cdl {
+            // Writing to a Vec can't fail.
+            cdl.export(&mut file).unwrap();
+        }
+        file
+    }
+
+    /// Starts counting memory accesses and logging I/O register writes.
+    pub fn enable_heatmap(&mut self) {
+        self.cpu.mmu.heatmap = Some(Heatmap::new());
+    }
+
+    pub fn disable_heatmap(&mut self) {
+        self.cpu.mmu.heatmap = None;
+    }
+
+    /// Per-address read counts for the whole 64 KiB map.
+    pub fn heatmap_reads(&self) -> Vec<u32> {
+        self.heatmap_counts(|c| c.reads)
+    }
+
+    pub fn heatmap_writes(&self) -> Vec<u32> {
+        self.heatmap_counts(|c| c.writes)
+    }
+
+    pub fn heatmap_executes(&self) -> Vec<u32> {
+        self.heatmap_counts(|c| c.executes)
+    }
+
+    /// The logged I/O register writes, one per line.
+    pub fn io_write_log(&self) -> String {
+        match &self.cpu.mmu.heatmap {
+            Some(heatmap) => heatmap
+                .io_log()
+                .map(|write| format!("{}\n", write))
+                .collect(),
+            None => String::new(),
+        }
+    }
+
+    /// Keeps a snapshot every `interval` steps for the last `window` steps so that
+    /// execution can be reversed.
+    pub fn enable_rewind(&mut self, interval: u32, window: u32) {
+        let history = History::new(interval as u64, window as u64);
+        self.cpu.set_history(Some(history));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.cpu.set_history(None);
+    }
+
+    pub fn step_back(&mut self) -> bool {
+        self.cpu.step_back()
+    }
+
+    pub fn continue_back(&mut self) -> bool {
+        self.cpu.continue_back()
+    }
+
+    pub fn continue_forward(&mut self, max_steps: usize) -> bool {
+        self.cpu.continue_forward(max_steps)
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.cpu.add_breakpoint(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.cpu.remove_breakpoint(addr);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+}
+
+impl Emulator {
+    fn heatmap_counts(&self, count: fn(&Counters) -> u32) -> Vec<u32> {
+        match &self.cpu.mmu.heatmap {
+            Some(heatmap) => heatmap.all_counters().iter().map(count).collect(),
+            None => vec![],
+        }
+    }
+}
+
+fn apply_input(cpu: &mut Cpu, input: &Input) {
+    for player in 0..MAX_PLAYERS {
+        cpu.set_buttons(player, input.buttons(player));
     }
 }
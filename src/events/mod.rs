@@ -2,4 +2,6 @@ pub enum Event {
     VBlank,
     AudioBufferFull(Vec<f32>, Vec<f32>),
     MaxCycles,
+    /// The CPU hung after executing an unused opcode.
+    CpuLocked,
 }
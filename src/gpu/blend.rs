@@ -0,0 +1,143 @@
+// LCD ghosting.
+//
+// The DMG LCD is slow: a pixel takes several frames to reach a new shade, so
+// moving objects leave trails and objects drawn every other frame look
+// transparent. Some games rely on the latter for effects like shadows and
+// fog. Blending completed frames with the ones before simulates this, into an
+// output buffer separate from the frame the PPU draws.
+//
+// - Mix averages each frame with the last one, which is enough for flicker
+//   transparency.
+// - Decay keeps a running average of all frames, each frame fading away
+//   exponentially, for the trails of a real LCD.
+//
+// The strength is the weight of the previous frames, from 0 (no blending) to
+// 255.
+
+use crate::utils::named_enum;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum BlendMode {
+    #[default]
+    Off,
+    Mix,
+    Decay,
+}
+
+named_enum!(BlendMode, "blend mode", {
+    Off => "off",
+    Mix => "mix",
+    Decay => "decay",
+});
+
+#[derive(Clone, Default)]
+pub struct FrameBlend {
+    mode: BlendMode,
+    strength: u8,
+    /// The blended frame, as RGBA like the LCD.
+    output: Vec<u8>,
+    /// Mix: the last frame. Decay: the running average, with 8 fraction bits.
+    history: Vec<u16>,
+}
+
+impl FrameBlend {
+    pub fn set(&mut self, mode: BlendMode, strength: u8) {
+        self.mode = mode;
+        self.strength = strength;
+        // Start over from the next frame rather than blending with whatever
+        // was kept for another mode.
+        self.output.clear();
+        self.history.clear();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.mode != BlendMode::Off && self.strength != 0
+    }
+
+    /// The blended frame, or None while blending is off or no frame has been
+    /// completed yet.
+    pub fn output(&self) -> Option<&[u8]> {
+        if self.enabled() && !self.output.is_empty() {
+            Some(&self.output)
+        } else {
+            None
+        }
+    }
+
+    /// Blends a completed frame into the output.
+    pub fn finish(&mut self, frame: &[u8]) {
+        if !self.enabled() {
+            return;
+        }
+
+        if self.history.len() != frame.len() {
+            self.output = frame.to_vec();
+            self.history = match self.mode {
+                BlendMode::Decay => frame.iter().map(|&c| (c as u16) << 8).collect(),
+                _ => frame.iter().map(|&c| c as u16).collect(),
+            };
+            return;
+        }
+
+        let old = self.strength as u32;
+        let new = 256 - old;
+        let pixels = frame.iter().zip(&mut self.output).zip(&mut self.history);
+        match self.mode {
+            BlendMode::Off => {}
+            BlendMode::Mix => {
+                for ((&c, out), last) in pixels {
+                    *out = ((c as u32 * new + *last as u32 * old + 128) >> 8) as u8;
+                    *last = c as u16;
+                }
+            }
+            BlendMode::Decay => {
+                for ((&c, out), avg) in pixels {
+                    *avg = ((((c as u32) << 8) * new + *avg as u32 * old + 128) >> 8) as u16;
+                    *out = ((*avg as u32 + 128) >> 8).min(255) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        let mut blend = FrameBlend::default();
+        blend.finish(&[0, 255]);
+        assert_eq!(blend.output(), None);
+
+        blend.set(BlendMode::Mix, 128);
+        blend.finish(&[0, 255]);
+        assert_eq!(blend.output(), Some(&[0, 255][..]));
+        blend.finish(&[255, 255]);
+        assert_eq!(blend.output(), Some(&[128, 255][..]));
+        // Only the last frame counts.
+        blend.finish(&[255, 255]);
+        assert_eq!(blend.output(), Some(&[255, 255][..]));
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut blend = FrameBlend::default();
+        blend.set(BlendMode::Decay, 128);
+        blend.finish(&[0]);
+        let mut shades = vec![];
+        for _ in 0..4 {
+            blend.finish(&[255]);
+            shades.push(blend.output().unwrap()[0]);
+        }
+        assert_eq!(shades, [128, 191, 223, 239]);
+
+        for _ in 0..20 {
+            blend.finish(&[255]);
+        }
+        assert_eq!(blend.output(), Some(&[255][..]));
+
+        blend.set(BlendMode::Decay, 0);
+        assert!(!blend.enabled());
+    }
+}
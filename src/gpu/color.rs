@@ -0,0 +1,140 @@
+// LCD colour correction.
+//
+// CGB colours are 15 bit BGR. Expanding each channel linearly to 8 bits, as
+// the raw mode does, looks far more saturated than the real screens, whose
+// colours bleed into each other and which are much darker in the midtones.
+// The other modes model that:
+//
+// - Cgb mixes the channels in linear light with the weights of the CGB LCD
+//   and gamma corrects the result.
+// - ReduceContrast is Cgb squeezed into a narrower range, for the washed out
+//   look of the CGB screen without a strong backlight.
+// - Agb models the darker GBA screen, which CGB games are often played on.
+//
+// Every mode is a lookup table indexed by the colour, built the first time it
+// is used and shared by all emulators, so correction costs nothing per pixel.
+
+use crate::utils::named_enum;
+use std::sync::OnceLock;
+
+pub const COLORS: usize = 0x8000;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ColorCorrection {
+    #[default]
+    Raw,
+    Cgb,
+    ReduceContrast,
+    Agb,
+}
+
+named_enum!(ColorCorrection, "colour correction", {
+    Raw => "raw",
+    Cgb => "cgb",
+    ReduceContrast => "reduce-contrast",
+    Agb => "agb",
+});
+
+impl ColorCorrection {
+    /// The RGB colour of every 15 bit colour.
+    pub fn table(self) -> &'static [[u8; 3]] {
+        static RAW: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+        static CGB: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+        static REDUCE_CONTRAST: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+        static AGB: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+
+        let table = match self {
+            ColorCorrection::Raw => &RAW,
+            ColorCorrection::Cgb => &CGB,
+            ColorCorrection::ReduceContrast => &REDUCE_CONTRAST,
+            ColorCorrection::Agb => &AGB,
+        };
+        table.get_or_init(|| {
+            (0..COLORS as u16)
+                .map(|color| self.correct(color))
+                .collect()
+        })
+    }
+
+    fn correct(self, color: u16) -> [u8; 3] {
+        let r = (color & 0x1F) as u8;
+        let g = ((color >> 5) & 0x1F) as u8;
+        let b = ((color >> 10) & 0x1F) as u8;
+
+        match self {
+            ColorCorrection::Raw => [expand(r), expand(g), expand(b)],
+            ColorCorrection::Cgb => mix(r, g, b, 2.2, &CGB_MATRIX, 1.0),
+            ColorCorrection::ReduceContrast => {
+                let [r, g, b] = mix(r, g, b, 2.2, &CGB_MATRIX, 1.0);
+                let squeeze = |c: u8| 0x20 + (c as u16 * (0xD8 - 0x20) / 0xFF) as u8;
+                [squeeze(r), squeeze(g), squeeze(b)]
+            }
+            ColorCorrection::Agb => mix(r, g, b, 4.0, &AGB_MATRIX, 255.0 / 280.0),
+        }
+    }
+}
+
+/// How much of the red, green and blue input ends up in each output channel.
+const CGB_MATRIX: [[f32; 3]; 3] = [
+    [26.0 / 32.0, 4.0 / 32.0, 2.0 / 32.0],
+    [0.0, 24.0 / 32.0, 8.0 / 32.0],
+    [6.0 / 32.0, 4.0 / 32.0, 22.0 / 32.0],
+];
+
+const AGB_MATRIX: [[f32; 3]; 3] = [
+    [1.0, 50.0 / 255.0, 0.0],
+    [10.0 / 255.0, 230.0 / 255.0, 30.0 / 255.0],
+    [50.0 / 255.0, 10.0 / 255.0, 220.0 / 255.0],
+];
+
+/// The display gamma the output is encoded for.
+const OUTPUT_GAMMA: f32 = 2.2;
+
+fn expand(c: u8) -> u8 {
+    (c << 3) | (c >> 2)
+}
+
+/// Mixes the channels through `matrix` in linear light, with `lcd_gamma` the
+/// response of the emulated screen.
+fn mix(r: u8, g: u8, b: u8, lcd_gamma: f32, matrix: &[[f32; 3]; 3], brightness: f32) -> [u8; 3] {
+    let linear = [r, g, b].map(|c| (c as f32 / 31.0).powf(lcd_gamma));
+
+    matrix.map(|weights| {
+        let mixed: f32 = weights.iter().zip(&linear).map(|(w, c)| w * c).sum();
+        let encoded = mixed.min(1.0).powf(1.0 / OUTPUT_GAMMA) * brightness;
+        (encoded * 255.0).round() as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_matches_linear_expansion() {
+        let table = ColorCorrection::Raw.table();
+        assert_eq!(table.len(), COLORS);
+        assert_eq!(table[0x7FFF], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(table[0x001F], [0xFF, 0x00, 0x00]);
+        assert_eq!(table[0x0210], [0x84, 0x84, 0x00]);
+    }
+
+    #[test]
+    fn test_corrected_modes() {
+        let cgb = ColorCorrection::Cgb.table();
+        assert_eq!(cgb[0x7FFF], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(cgb[0x0000], [0x00, 0x00, 0x00]);
+        // Pure red bleeds into blue.
+        let [r, g, b] = cgb[0x001F];
+        assert!(r > b && b > g, "{:?}", cgb[0x001F]);
+
+        let reduced = ColorCorrection::ReduceContrast.table();
+        assert_eq!(reduced[0x0000], [0x20, 0x20, 0x20]);
+        assert_eq!(reduced[0x7FFF], [0xD8, 0xD8, 0xD8]);
+
+        // The GBA screen is darker than the CGB's in the midtones.
+        let agb = ColorCorrection::Agb.table();
+        assert!(agb[0x4210][1] < cgb[0x4210][1]);
+        assert!(agb[0x7FFF][0] < 0xFF);
+    }
+}
@@ -0,0 +1,401 @@
+// DMG compatibility palettes.
+//
+// When the CGB boot ROM finds a cartridge without CGB support it colourises it
+// with one of a fixed set of palettes: one for the background and one for each
+// of the two object palettes, indexed by the DMG shade. Games licensed by
+// Nintendo are looked up by the checksum of their title, using the 4th title
+// letter to tell apart games whose checksums collide. Everything else gets the
+// default palette, unless the user holds one of the 12 button combinations
+// while the logo is shown.
+//
+// The tables below are those of the boot ROM: the title checksums, the 4th
+// letters of the titles whose checksums collide and the palette ID of every
+// checksum. The boot ROM turns a palette ID into a BG, OBJ0 and OBJ1 palette
+// through a table of palette offsets and shuffling flags; like SameBoy's
+// reimplementation, the result is stored directly as one triple per ID.
+//
+// References:
+// - https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+// - https://github.com/LIJI32/SameBoy/blob/master/BootROMs/cgb_boot.asm
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const OLD_LICENSEE: usize = 0x14B;
+const NEW_LICENSEE: usize = 0x144;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatPalette {
+    /// The palettes of palette ID `id`.
+    fn from_id(id: u8) -> Self {
+        let [obj0, obj1, bg] = COMBINATIONS[id as usize];
+        CompatPalette {
+            bg: PALETTES[bg as usize],
+            obj0: PALETTES[obj0 as usize],
+            obj1: PALETTES[obj1 as usize],
+        }
+    }
+}
+
+impl Default for CompatPalette {
+    fn default() -> Self {
+        Self::from_id(DEFAULT_ID)
+    }
+}
+
+/// The button combinations that can be held while the boot logo is shown.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PaletteCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl PaletteCombo {
+    pub const ALL: [PaletteCombo; 12] = [
+        PaletteCombo::Up,
+        PaletteCombo::UpA,
+        PaletteCombo::UpB,
+        PaletteCombo::Left,
+        PaletteCombo::LeftA,
+        PaletteCombo::LeftB,
+        PaletteCombo::Down,
+        PaletteCombo::DownA,
+        PaletteCombo::DownB,
+        PaletteCombo::Right,
+        PaletteCombo::RightA,
+        PaletteCombo::RightB,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            // Brown
+            PaletteCombo::Up => 5,
+            // Red
+            PaletteCombo::UpA => 43,
+            // Dark brown
+            PaletteCombo::UpB => 28,
+            // Blue
+            PaletteCombo::Left => 48,
+            // Dark blue
+            PaletteCombo::LeftA => 40,
+            // Grayscale
+            PaletteCombo::LeftB => 7,
+            // Pastel mix
+            PaletteCombo::Down => 8,
+            // Orange
+            PaletteCombo::DownA => 3,
+            // Yellow
+            PaletteCombo::DownB => 49,
+            // Green
+            PaletteCombo::Right => 1,
+            // Dark green, the default
+            PaletteCombo::RightA => DEFAULT_ID,
+            // Inverted
+            PaletteCombo::RightB => 6,
+        }
+    }
+
+    pub fn palette(self) -> CompatPalette {
+        CompatPalette::from_id(self.id())
+    }
+}
+
+/// Used for games without a table entry and games not licensed by Nintendo.
+const DEFAULT_ID: u8 = 0;
+
+/// Checksums from this index on are shared by several games and also need the
+/// 4th letter of the title to match.
+const FIRST_DUPLICATE: usize = 65;
+
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, // default
+    0x88, // ALLEY WAY
+    0x16, // YAKUMAN
+    0x36, // BASEBALL
+    0xD1, // TENNIS
+    0xDB, // TETRIS
+    0xF2, // QIX
+    0x3C, // DR.MARIO
+    0x8C, // RADARMISSION
+    0x92, // F1RACE
+    0x3D, // YOSSY NO TAMAGO
+    0x5C,
+    0x58, // X
+    0xC9, // MARIOLAND2
+    0x3E, // YOSSY NO COOKIE
+    0x70, // ZELDA
+    0x1D,
+    0x59,
+    0x69, // TETRIS FLASH
+    0x19, // DONKEY KONG
+    0x35, // MARIO'S PICROSS
+    0xA8,
+    0x14, // POKEMON RED
+    0xAA, // POKEMON GREEN
+    0x75, // PICROSS 2
+    0x95, // YOSSY NO PANEPON
+    0x99, // KIRAKIRA KIDS
+    0x34, // GAMEBOY GALLERY
+    0x6F, // POCKETCAMERA
+    0x15,
+    0xFF, // BALLOON KID
+    0x97, // KINGOFTHEZOO
+    0x4B, // DMG FOOTBALL
+    0x90, // WORLD CUP
+    0x17, // OTHELLO
+    0x10, // SUPER RC PRO-AM
+    0x39, // DYNABLASTER
+    0xF7, // BOY AND BLOB GB2
+    0xF6, // MEGAMAN
+    0xA2, // STAR WARS-NOA
+    0x49,
+    0x4E, // WAVERACE
+    0x43,
+    0x68, // LOLO2
+    0xE0, // YOSHI'S COOKIE
+    0x8B, // MYSTIC QUEST
+    0xF0,
+    0xCE, // TOPRANKINGTENNIS
+    0x0C, // MANSELL
+    0x29, // MEGAMAN3
+    0xE8, // SPACE INVADERS
+    0xB7, // GAME&WATCH
+    0x86, // DONKEYKONGLAND95
+    0x9A, // ASTEROIDS/MISCMD
+    0x52, // STREET FIGHTER 2
+    0x01, // DEFENDER/JOUST
+    0x9D, // KILLERINSTINCT95
+    0x71, // TETRIS BLAST
+    0x9C, // PINOCCHIO
+    0xBD,
+    0x5D, // BA.TOSHINDEN
+    0x6D, // NETTOU KOF 95
+    0x67,
+    0x3F, // TETRIS PLUS
+    0x6B, // DONKEYKONGLAND 3
+    // Shared checksums, told apart by FOURTH_LETTERS.
+    0xB3,
+    0x46, // SUPER MARIOLAND
+    0x28, // GOLF
+    0xA5, // SOLARSTRIKER
+    0xC6, // GBWARS
+    0xD3, // KAERUNOTAMENI
+    0x27,
+    0x61, // POKEMON BLUE
+    0x18, // DONKEYKONGLAND
+    0x66, // GAMEBOY GALLERY2
+    0x6A, // DONKEYKONGLAND 2
+    0xBF, // KID ICARUS
+    0x0D, // TETRIS2
+    0xF4,
+    0xB3, // MOGURANYA
+    0x46,
+    0x28, // GALAGA&GALAXIAN
+    0xA5, // BT2RAGNAROKWORLD
+    0xC6, // KEN GRIFFEY JR
+    0xD3,
+    0x27, // MAGNETIC SOCCER
+    0x61, // VEGAS STAKES
+    0x18,
+    0x66, // MILLI/CENTI/PEDE
+    0x6A, // MARIO & YOSHI
+    0xBF, // SOCCER
+    0x0D, // POKEBOM
+    0xF4, // G&W GALLERY
+    0xB3, // TETRIS ATTACK
+];
+
+/// The 4th title letter of every checksum from FIRST_DUPLICATE on.
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// The palette ID of every entry in TITLE_CHECKSUMS.
+#[rustfmt::skip]
+const PALETTE_IDS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46,
+    6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+/// The OBJ0, OBJ1 and BG palettes of every palette ID, as indices into PALETTES.
+#[rustfmt::skip]
+const COMBINATIONS: [[u8; 3]; 51] = [
+    [4, 4, 29], [18, 18, 18], [20, 20, 20], [24, 24, 24],
+    [9, 9, 9], [0, 0, 0], [27, 27, 27], [5, 5, 5],
+    [12, 12, 12], [26, 26, 26], [16, 8, 8], [4, 28, 28],
+    [4, 2, 2], [3, 4, 4], [4, 29, 29], [28, 4, 28],
+    [2, 17, 2], [16, 16, 8], [4, 4, 7], [4, 4, 18],
+    [4, 4, 20], [19, 19, 9], [3, 3, 11], [17, 17, 2],
+    [4, 4, 2], [4, 4, 3], [28, 28, 0], [3, 3, 0],
+    [0, 0, 1], [18, 22, 18], [20, 22, 20], [24, 22, 24],
+    [16, 22, 8], [17, 4, 13], [28, 0, 14], [28, 4, 15],
+    [19, 22, 9], [16, 28, 10], [4, 23, 28], [17, 22, 2],
+    [4, 0, 2], [4, 28, 3], [28, 3, 0], [3, 28, 4],
+    [21, 28, 4], [3, 28, 0], [25, 3, 28], [0, 28, 8],
+    [4, 3, 28], [28, 3, 6], [4, 28, 29],
+];
+
+/// Colours in the CGB's 15 bit BGR format, lightest shade first.
+#[rustfmt::skip]
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+fn title_checksum(title: &[u8]) -> u8 {
+    title.iter().fold(0u8, |sum, &c| sum.wrapping_add(c))
+}
+
+/// Looks the title up like the boot ROM: the first entry with the same
+/// checksum wins, except that shared checksums also need the 4th letter to
+/// match.
+fn palette_id(title: &[u8]) -> u8 {
+    let checksum = title_checksum(title);
+
+    TITLE_CHECKSUMS
+        .iter()
+        .enumerate()
+        .find(|&(i, &entry)| {
+            entry == checksum
+                && (i < FIRST_DUPLICATE || FOURTH_LETTERS[i - FIRST_DUPLICATE] == title[3])
+        })
+        .map_or(DEFAULT_ID, |(i, _)| PALETTE_IDS[i])
+}
+
+fn licensed_by_nintendo(header: &[u8]) -> bool {
+    match header[OLD_LICENSEE] {
+        0x01 => true,
+        0x33 => &header[NEW_LICENSEE..NEW_LICENSEE + 2] == b"01",
+        _ => false,
+    }
+}
+
+/// Picks the palette the boot ROM would choose for the cartridge with the
+/// given header (at least the first 0x150 bytes of the ROM).
+pub fn title_palette(header: &[u8]) -> CompatPalette {
+    if !licensed_by_nintendo(header) {
+        return CompatPalette::default();
+    }
+
+    CompatPalette::from_id(palette_id(&header[TITLE_START..TITLE_END]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &[u8], licensee: u8) -> Vec<u8> {
+        let mut header = vec![0; 0x150];
+        header[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        header[OLD_LICENSEE] = licensee;
+        header
+    }
+
+    #[test]
+    fn test_title_palette() {
+        let red = title_palette(&header(b"POKEMON RED", 0x01));
+        assert_eq!(red.bg, PALETTES[4]);
+        assert_eq!(red.obj0, PALETTES[3]);
+
+        let mut new_licensee = header(b"ZELDA", 0x33);
+        new_licensee[NEW_LICENSEE..NEW_LICENSEE + 2].copy_from_slice(b"01");
+        assert_eq!(title_palette(&new_licensee), CompatPalette::from_id(44));
+
+        assert_eq!(
+            title_palette(&header(b"HELLO WORLD", 0x01)),
+            CompatPalette::default()
+        );
+    }
+
+    #[test]
+    fn test_other_licensees() {
+        // TETRIS by anyone but Nintendo doesn't get Tetris' palette.
+        assert_eq!(
+            title_palette(&header(b"TETRIS", 0x01)),
+            CompatPalette::from_id(3)
+        );
+        assert_eq!(
+            title_palette(&header(b"TETRIS", 0x08)),
+            CompatPalette::default()
+        );
+
+        let mut new_licensee = header(b"TETRIS", 0x33);
+        new_licensee[NEW_LICENSEE..NEW_LICENSEE + 2].copy_from_slice(b"08");
+        assert_eq!(title_palette(&new_licensee), CompatPalette::default());
+    }
+
+    #[test]
+    fn test_checksum_collisions() {
+        // Both titles add up to 0x61.
+        let mut blue = [0; 16];
+        blue[..12].copy_from_slice(b"POKEMON BLUE");
+        let mut vegas = [0; 16];
+        vegas[..12].copy_from_slice(b"VEGAS STAKES");
+        assert_eq!(title_checksum(&blue), title_checksum(&vegas));
+
+        assert_eq!(palette_id(&blue), 11);
+        assert_eq!(palette_id(&vegas), 41);
+
+        // The same letters, so the same checksum, but a 4th letter nobody has.
+        let mut other = [0; 16];
+        other[..12].copy_from_slice(b"POKMEON BLUE");
+        assert_eq!(title_checksum(&other), 0x61);
+        assert_eq!(palette_id(&other), DEFAULT_ID);
+    }
+
+    #[test]
+    fn test_button_combos() {
+        let up_a = PaletteCombo::UpA.palette();
+        assert_eq!(up_a.bg, [0x7FFF, 0x421F, 0x1CF2, 0x0000]);
+        assert_eq!(up_a.obj0, [0x7FFF, 0x1BEF, 0x0200, 0x0000]);
+        assert_eq!(up_a.obj1, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+        assert_eq!(PaletteCombo::RightA.palette(), CompatPalette::default());
+    }
+}
@@ -8,10 +8,18 @@
 // in sameboy_pixel_pipeline.md. I also consulted LIJI and got advice/help from him and
 // several others.
 
+pub mod blend;
+pub mod color;
+pub mod compat;
+pub mod palette;
 pub mod registers;
 pub mod tiles;
 
 use crate::cpu::EmulationMode;
+use crate::gpu::blend::{BlendMode, FrameBlend};
+use crate::gpu::color::ColorCorrection;
+use crate::gpu::compat::CompatPalette;
+use crate::gpu::palette::DmgPalette;
 use crate::gpu::registers::{ColorPalette, LcdControl, LcdPosition, LcdStatus, MonochromePalette};
 use crate::gpu::tiles::Sprite;
 use std::collections::VecDeque;
@@ -55,7 +63,7 @@ impl From<&GpuMode> for u8 {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct PixelFifoItem {
     pub value: u8,
     pub palette_num: u8,
@@ -64,6 +72,7 @@ pub struct PixelFifoItem {
     pub bg_to_oam_prio: u8,
 }
 
+#[derive(Clone)]
 pub struct BgFifo {
     pub q: VecDeque<PixelFifoItem>,
 }
@@ -117,6 +126,7 @@ impl BgFifo {
     }
 }
 
+#[derive(Clone)]
 pub struct ObjFifo {
     pub q: VecDeque<PixelFifoItem>,
 }
@@ -175,7 +185,7 @@ impl ObjFifo {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum FetcherState {
     Sleep0,
     ReadTileMap,
@@ -202,6 +212,7 @@ impl From<&FetcherState> for usize {
     }
 }
 
+#[derive(Clone)]
 pub struct Fetcher {
     pub state: FetcherState,
     pub x: u8,
@@ -241,7 +252,7 @@ impl Fetcher {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum SpriteFetchState {
     AdvanceFetcher0,
     AdvanceFetcher1,
@@ -251,6 +262,7 @@ enum SpriteFetchState {
     SpriteOverlay,
 }
 
+#[derive(Clone)]
 pub struct Gpu {
     pub lcd: Vec<u8>,
     pub vram0: Vec<u8>,
@@ -304,6 +316,19 @@ pub struct Gpu {
 
     pub vblank_event: bool,
     pub hdma_flag: bool,
+
+    dot: usize,
+    frame: u64,
+    /// DMG compatibility mode on a CGB, where DMG shades index the palette RAM.
+    compat: bool,
+    /// The DMG shade of every pixel on screen, for the SGB.
+    shades: Vec<u8>,
+    /// The colours DMG shades are shown as.
+    dmg_palette: DmgPalette,
+    /// The RGB colour of every CGB colour, for the selected colour correction.
+    colors: &'static [[u8; 3]],
+    /// LCD ghosting, blending completed frames into a separate buffer.
+    blend: FrameBlend,
 }
 
 impl Gpu {
@@ -362,6 +387,14 @@ impl Gpu {
 
             vblank_event: false,
             hdma_flag: false,
+
+            dot: 0,
+            frame: 0,
+            compat: false,
+            shades: vec![0; SCREEN_HEIGHT * SCREEN_WIDTH],
+            dmg_palette: DmgPalette::default(),
+            colors: ColorCorrection::default().table(),
+            blend: FrameBlend::default(),
         }
     }
 
@@ -369,8 +402,9 @@ impl Gpu {
         &self.stat.mode
     }
 
+    /// The frame to show, after LCD ghosting if it is enabled.
     pub fn screen(&self) -> *const u8 {
-        self.lcd.as_ptr()
+        self.blend.output().unwrap_or(&self.lcd).as_ptr()
     }
 
     pub fn tick(&mut self, mut cycles: usize) {
@@ -378,6 +412,8 @@ impl Gpu {
             return;
         }
 
+        self.dot += cycles;
+
         if self.first_line0 {
             cycles = self.line0_tick(cycles);
         }
@@ -740,7 +776,27 @@ impl Gpu {
                 }
             }
 
-            let (r, g, b) = self.get_rgb(value, palette);
+            if self.emu_mode == EmulationMode::Dmg {
+                let i = self.position.ly as usize * SCREEN_WIDTH + self.lx as usize;
+                self.shades[i] = ((palette >> (2 * value)) & 0x3) as u8;
+            }
+
+            let (r, g, b) = if self.compat {
+                let shade = ((palette >> (2 * value)) & 0x3) as u8;
+                let color = if draw_sprite {
+                    self.cgb_obj_palette(spx, shade)
+                } else {
+                    self.cgb_bg_palette(px, shade)
+                };
+                self.cgb_rgb(color)
+            } else {
+                let colors = match (draw_sprite, spx.palette_num) {
+                    (false, _) => &self.dmg_palette.bg,
+                    (true, 0) => &self.dmg_palette.obj0,
+                    (true, _) => &self.dmg_palette.obj1,
+                };
+                self.get_rgb(value, palette, colors)
+            };
             self.write_lcd(r, g, b);
 
             self.lx += 1;
@@ -897,6 +953,7 @@ impl Gpu {
 
         if self.clock + cycles >= hblank_clocks {
             let cycles_left = self.clock + cycles - hblank_clocks;
+            self.dot = cycles_left;
             self.position.ly += 1;
             self.update_stat_int_signal();
 
@@ -910,6 +967,8 @@ impl Gpu {
                 self.next_mode = GpuMode::VBlank;
                 // self.request_vblank_interrupt();
                 self.vblank_event = true;
+                self.frame += 1;
+                self.blend.finish(&self.lcd);
             } else {
                 self.next_mode = GpuMode::OamSearch;
             }
@@ -931,6 +990,7 @@ impl Gpu {
         if self.clock + cycles >= 456 {
             let cycles_left = self.clock + cycles - 456;
             self.clock = 0;
+            self.dot = cycles_left;
             self.position.ly += 1;
 
             if self.position.ly > 1 {
@@ -1044,28 +1104,19 @@ impl Gpu {
         self.request_lcd_int = true;
     }
 
-    fn get_rgb(&self, value: u8, palette: u16) -> (u8, u8, u8) {
+    fn get_rgb(&self, value: u8, palette: u16, colors: &[u32; 4]) -> (u8, u8, u8) {
         match self.emu_mode {
-            EmulationMode::Dmg => match (palette >> (2 * value)) & 0x3 {
-                0 => (224, 247, 208),
-                1 => (136, 192, 112),
-                2 => (52, 104, 86),
-                _ => (8, 23, 33),
-            },
-            EmulationMode::Cgb => {
-                let r = (palette & 0x001F) >> 0;
-                let g = (palette & 0x03E0) >> 5;
-                let b = (palette & 0x7C00) >> 10;
-
-                (
-                    ((r << 3) | (r >> 2)) as u8,
-                    ((g << 3) | (g >> 2)) as u8,
-                    ((b << 3) | (b >> 2)) as u8,
-                )
-            }
+            EmulationMode::Dmg => palette::rgb(colors[((palette >> (2 * value)) & 0x3) as usize]),
+            EmulationMode::Cgb => self.cgb_rgb(palette),
         }
     }
 
+    #[inline]
+    fn cgb_rgb(&self, color: u16) -> (u8, u8, u8) {
+        let [r, g, b] = self.colors[(color & 0x7FFF) as usize];
+        (r, g, b)
+    }
+
     #[inline]
     fn write_lcd(&mut self, r: u8, g: u8, b: u8) {
         let ly = self.position.ly as usize;
@@ -1077,10 +1128,64 @@ impl Gpu {
         self.lcd[ly * SCREEN_WIDTH * SCREEN_DEPTH + lx * SCREEN_DEPTH + 3] = 255;
     }
 
+    /// Switches a CGB to DMG compatibility mode, as happens when the boot ROM
+    /// hands over after writing 0x04 to KEY0. From then on the GPU ignores the
+    /// CGB tile attributes and colours DMG shades through the palette RAM.
+    pub fn enter_compat_mode(&mut self) {
+        self.emu_mode = EmulationMode::Dmg;
+        self.compat = true;
+    }
+
+    /// The shades of the last frame, after applying BGP/OBP0/OBP1.
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    pub fn set_color_correction(&mut self, mode: ColorCorrection) {
+        self.colors = mode.table();
+    }
+
+    pub fn set_frame_blend(&mut self, mode: BlendMode, strength: u8) {
+        self.blend.set(mode, strength);
+    }
+
+    /// Replaces the LCD ghosting settings and buffers, returning the old ones.
+    pub fn swap_frame_blend(&mut self, blend: FrameBlend) -> FrameBlend {
+        std::mem::replace(&mut self.blend, blend)
+    }
+
+    pub fn compat(&self) -> bool {
+        self.compat
+    }
+
+    /// Writes the background palette 0 and object palettes 0 and 1, like the
+    /// boot ROM does for DMG games.
+    pub fn load_compat_palette(&mut self, palette: &CompatPalette) {
+        for (i, color) in palette.bg.iter().enumerate() {
+            self.bgp_ram[i * 2] = *color as u8;
+            self.bgp_ram[i * 2 + 1] = (*color >> 8) as u8;
+        }
+        for (i, color) in palette.obj0.iter().chain(&palette.obj1).enumerate() {
+            self.obp_ram[i * 2] = *color as u8;
+            self.obp_ram[i * 2 + 1] = (*color >> 8) as u8;
+        }
+    }
+
+    /// Shows a blank screen, as happens when the system clock is stopped.
+    pub fn blank(&mut self) {
+        self.clear_screen();
+        self.vblank_event = true;
+    }
+
     fn clear_screen(&mut self) {
         for i in 0..self.lcd.len() {
             self.lcd[i] = 255;
         }
+        self.blend.finish(&self.lcd);
     }
 
     pub fn set_byte(&mut self, addr: u16, value: u8) {
@@ -1254,6 +1359,24 @@ impl Gpu {
         }
     }
 
+    pub fn ly(&self) -> u8 {
+        self.position.ly
+    }
+
+    /// The number of dots elapsed on the current line.
+    pub fn dot(&self) -> usize {
+        self.dot
+    }
+
+    /// The number of frames that have been completed since power on.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
     #[inline]
     fn request_vblank_interrupt(&mut self) {
         self.request_vblank_int = true;
@@ -1264,6 +1387,7 @@ impl Gpu {
         self.stat.mode = GpuMode::HBlank;
 
         self.position.ly = 0;
+        self.dot = 0;
 
         self.wx_triggered = false;
         self.win_counter = -1;
@@ -1294,3 +1418,15 @@ impl Gpu {
         self.first_line0 = true;
     }
 }
+
+pub(crate) fn rgb555(color: u16) -> (u8, u8, u8) {
+    let r = (color & 0x001F) >> 0;
+    let g = (color & 0x03E0) >> 5;
+    let b = (color & 0x7C00) >> 10;
+
+    (
+        ((r << 3) | (r >> 2)) as u8,
+        ((g << 3) | (g >> 2)) as u8,
+        ((b << 3) | (b >> 2)) as u8,
+    )
+}
@@ -0,0 +1,184 @@
+// DMG palettes.
+//
+// On a DMG the four shades picked by BGP, OBP0 and OBP1 are whatever colour
+// the LCD shows them as. Like the CGB compatibility palettes, a palette here
+// has separate colours for the background and both object palettes, lightest
+// shade first.
+//
+// Palettes can also be loaded from files, either JASC-PAL files as written by
+// Paint Shop Pro and most pixel art tools, or plain lists of hex colours. Both
+// hold 4 colours used for all three layers, or 12 for BG, OBJ0 and OBJ1 in that
+// order.
+
+/// Colours as 0xRRGGBB.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DmgPalette {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+const fn same(colors: [u32; 4]) -> DmgPalette {
+    DmgPalette {
+        bg: colors,
+        obj0: colors,
+        obj1: colors,
+    }
+}
+
+/// The built in palettes, by name.
+pub const PRESETS: &[(&str, DmgPalette)] = &[
+    ("dmg", same([0xE0F7D0, 0x88C070, 0x346856, 0x081721])),
+    ("pocket", same([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F])),
+    ("light", same([0x00B581, 0x009A71, 0x00694A, 0x004F3B])),
+    ("greyscale", same([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000])),
+    ("kirokaze", same([0xE2F3E4, 0x94E344, 0x46878F, 0x332C50])),
+    ("ice-cream", same([0xFFF6D3, 0xF9A875, 0xEB6B6F, 0x7C3F58])),
+    ("demichrome", same([0xE9EFEC, 0xA0A08B, 0x555568, 0x211E20])),
+    ("mist", same([0xC4F0C2, 0x5AB9A8, 0x1E606E, 0x2D1B00])),
+    ("rustic", same([0xEDB4A1, 0xA96868, 0x764462, 0x2C2137])),
+];
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        PRESETS[0].1.clone()
+    }
+}
+
+impl DmgPalette {
+    pub fn preset(name: &str) -> Option<DmgPalette> {
+        PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|(_, palette)| palette.clone())
+    }
+
+    /// Reads a JASC-PAL file or a list of hex colours.
+    pub fn parse(text: &str) -> Result<DmgPalette, String> {
+        let colors = if text.trim_start().starts_with("JASC-PAL") {
+            parse_jasc(text)?
+        } else {
+            parse_hex_list(text)?
+        };
+
+        let layer = |i: usize| [colors[i], colors[i + 1], colors[i + 2], colors[i + 3]];
+        match colors.len() {
+            4 => Ok(same(layer(0))),
+            12 => Ok(DmgPalette {
+                bg: layer(0),
+                obj0: layer(4),
+                obj1: layer(8),
+            }),
+            n => Err(format!("expected 4 or 12 colours, got {}", n)),
+        }
+    }
+
+    /// Writes the palette as a list of hex colours, the format `parse` reads.
+    pub fn to_hex_list(&self) -> String {
+        [self.bg, self.obj0, self.obj1]
+            .iter()
+            .map(|layer| {
+                let colors: Vec<_> = layer.iter().map(|c| format!("#{:06X}", c)).collect();
+                colors.join(" ") + "\n"
+            })
+            .collect()
+    }
+}
+
+/// Splits 0xRRGGBB into its channels.
+pub fn rgb(color: u32) -> (u8, u8, u8) {
+    ((color >> 16) as u8, (color >> 8) as u8, color as u8)
+}
+
+fn parse_jasc(text: &str) -> Result<Vec<u32>, String> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    lines.next();
+    if lines.next() != Some("0100") {
+        return Err("unsupported JASC-PAL version".to_string());
+    }
+
+    let count: usize = lines
+        .next()
+        .and_then(|count| count.parse().ok())
+        .ok_or("missing JASC-PAL colour count")?;
+
+    let colors = lines
+        .take(count)
+        .map(|line| {
+            let channels: Vec<u32> = line
+                .split_whitespace()
+                .map(|channel| channel.parse::<u8>().map(u32::from))
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid colour {:?}", line))?;
+            match channels.as_slice() {
+                [r, g, b] => Ok(r << 16 | g << 8 | b),
+                _ => Err(format!("invalid colour {:?}", line)),
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if colors.len() != count {
+        return Err(format!("expected {} colours, got {}", count, colors.len()));
+    }
+    Ok(colors)
+}
+
+/// Colours as RRGGBB, #RRGGBB or 0xRRGGBB, separated by whitespace or commas.
+/// Anything after a ';' on a line is a comment.
+fn parse_hex_list(text: &str) -> Result<Vec<u32>, String> {
+    text.lines()
+        .map(|line| line.split(';').next().unwrap())
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let hex = token
+                .strip_prefix('#')
+                .or_else(|| token.strip_prefix("0x"))
+                .unwrap_or(token);
+            match hex.len() {
+                6 => {
+                    u32::from_str_radix(hex, 16).map_err(|_| format!("invalid colour {:?}", token))
+                }
+                _ => Err(format!("invalid colour {:?}", token)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(DmgPalette::preset("Pocket").unwrap().bg[3], 0x1F1F1F);
+        assert_eq!(DmgPalette::preset("vaporwave"), None);
+        assert_eq!(DmgPalette::default().bg[0], 0xE0F7D0);
+        assert_eq!(rgb(0xE0F7D0), (224, 247, 208));
+    }
+
+    #[test]
+    fn test_hex_list() {
+        let palette = DmgPalette::parse("; greys\n#FFFFFF, #AAAAAA\n0x555555 000000\n").unwrap();
+        assert_eq!(palette, DmgPalette::preset("greyscale").unwrap());
+
+        let mut twelve = DmgPalette::preset("mist").unwrap();
+        twelve.obj1 = [0xFF0000, 0x00FF00, 0x0000FF, 0x000000];
+        assert_eq!(DmgPalette::parse(&twelve.to_hex_list()), Ok(twelve));
+
+        assert!(DmgPalette::parse("#FFFFFF #AAAAAA #555555").is_err());
+        assert!(DmgPalette::parse("#FFFFFF #AAAAAA #555555 #GGGGGG").is_err());
+    }
+
+    #[test]
+    fn test_jasc() {
+        let palette = DmgPalette::parse(
+            "JASC-PAL\r\n0100\r\n4\r\n255 246 211\r\n249 168 117\r\n235 107 111\r\n124 63 88\r\n",
+        )
+        .unwrap();
+        assert_eq!(palette, DmgPalette::preset("ice-cream").unwrap());
+
+        assert!(DmgPalette::parse("JASC-PAL\n0100\n4\n255 255 255\n").is_err());
+        assert!(DmgPalette::parse("JASC-PAL\n0100\n1\n256 0 0\n").is_err());
+    }
+}
@@ -9,7 +9,7 @@ use crate::gpu::GpuMode;
 // Bit 2 - OBJ (Sprite) Size              (0=8x8, 1=8x16)
 // Bit 1 - OBJ (Sprite) Display Enable    (0=Off, 1=On)
 // Bit 0 - BG/Window Display/Priority     (0=Off, 1=On)
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct LcdControl {
     pub display_enable: u8,
     pub win_tilemap_sel: u8,
@@ -73,6 +73,7 @@ impl From<&LcdControl> for u8 {
 // Bit 3 - Mode 0 H-Blank Interrupt     (1=Enable) (Read/Write)
 // Bit 2 - Coincidence Flag  (0:LYC<>LY, 1:LYC=LY) (Read Only)
 // Bit 1-0 - Mode Flag       (Mode 0-3, see below) (Read Only)
+#[derive(Clone)]
 pub struct LcdStatus {
     pub lyc_int: u8,
     pub oam_int: u8,
@@ -106,7 +107,7 @@ impl From<&LcdStatus> for u8 {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct LcdPosition {
     pub scy: u8,
     pub scx: u8,
@@ -116,14 +117,14 @@ pub struct LcdPosition {
     pub wx: u8,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct MonochromePalette {
     pub bgp: u8,
     pub obp0: u8,
     pub obp1: u8,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ColorPalette {
     pub bgp_idx: u8,
     pub bgp_auto_incr: bool,
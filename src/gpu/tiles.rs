@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct Sprite {
     pub y: u8,
     pub x: u8,
@@ -0,0 +1,187 @@
+// Infrared port.
+//
+// The CGB has an IR LED and a light sensor, both driven through the RP
+// register at FF56:
+//
+//   Bit 0   - LED (0=Off, 1=On) (Read/Write)
+//   Bit 1   - Signal (0=Receiving IR light, 1=Normal) (Read Only)
+//   Bit 6-7 - Read enable (0=Disable, 3=Enable) (Read/Write)
+//
+// The bits in between always read 1. Games send data by timing how long the
+// LED stays on, so both sides have to run in lockstep for a link to work.
+//
+// Reference: https://gbdev.io/pandocs/CGB_Registers.html
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Whatever the IR port is pointed at.
+pub trait InfraredDevice {
+    /// The Game Boy turned its LED on or off.
+    fn set_led(&mut self, on: bool);
+
+    /// Whether IR light reaches the sensor.
+    fn receiving(&mut self) -> bool;
+}
+
+/// Nothing in front of the sensor.
+pub struct NoSignal;
+
+impl InfraredDevice for NoSignal {
+    fn set_led(&mut self, _on: bool) {}
+
+    fn receiving(&mut self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone)]
+pub struct Infrared {
+    rp: u8,
+    /// Shared so that both ends of a link can hold on to it, like the serial
+    /// port's device, and for the same reason keeps the history from going
+    /// back over steps during which it was plugged in.
+    device: Option<Rc<RefCell<dyn InfraredDevice>>>,
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Self {
+            rp: 0,
+            device: None,
+        }
+    }
+
+    pub fn connect(&mut self, device: Rc<RefCell<dyn InfraredDevice>>) {
+        device.borrow_mut().set_led(self.rp & 0x01 != 0);
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.device = None;
+    }
+
+    pub fn connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    pub fn get_byte(&self) -> u8 {
+        let receiving = self.rp & 0xC0 == 0xC0
+            && self
+                .device
+                .as_ref()
+                .is_some_and(|device| device.borrow_mut().receiving());
+
+        0x3C | (self.rp & 0xC1) | if receiving { 0x00 } else { 0x02 }
+    }
+
+    pub fn set_byte(&mut self, value: u8) {
+        let led_changed = (self.rp ^ value) & 0x01 != 0;
+        self.rp = value & 0xC1;
+
+        if led_changed {
+            if let Some(device) = &self.device {
+                device.borrow_mut().set_led(value & 0x01 != 0);
+            }
+        }
+    }
+}
+
+/// One side of two Game Boys pointed at each other.
+pub struct InfraredLink {
+    leds: Rc<RefCell<[bool; 2]>>,
+    side: usize,
+}
+
+impl InfraredLink {
+    pub fn pair() -> (Rc<RefCell<InfraredLink>>, Rc<RefCell<InfraredLink>>) {
+        let leds = Rc::new(RefCell::new([false; 2]));
+        let end = |side| {
+            Rc::new(RefCell::new(InfraredLink {
+                leds: leds.clone(),
+                side,
+            }))
+        };
+
+        (end(0), end(1))
+    }
+}
+
+impl InfraredDevice for InfraredLink {
+    fn set_led(&mut self, on: bool) {
+        self.leds.borrow_mut()[self.side] = on;
+    }
+
+    fn receiving(&mut self) -> bool {
+        self.leds.borrow()[1 - self.side]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Cpu, Model};
+
+    #[test]
+    fn test_no_signal() {
+        let mut port = Infrared::new();
+        assert_eq!(port.get_byte(), 0x3E);
+
+        port.set_byte(0xFF);
+        assert_eq!(port.get_byte(), 0xFF);
+
+        port.connect(Rc::new(RefCell::new(NoSignal)));
+        assert_eq!(port.get_byte(), 0xFF);
+    }
+
+    #[test]
+    fn test_link() {
+        let (a, b) = InfraredLink::pair();
+        let mut left = Infrared::new();
+        let mut right = Infrared::new();
+        left.connect(a);
+        right.connect(b);
+
+        right.set_byte(0xC0);
+        assert_eq!(right.get_byte() & 0x02, 0x02);
+
+        left.set_byte(0x01);
+        assert_eq!(right.get_byte() & 0x02, 0x00);
+        // The LED doesn't shine into its own sensor.
+        left.set_byte(0xC1);
+        assert_eq!(left.get_byte() & 0x02, 0x02);
+
+        // Reading is off until both enable bits are set.
+        right.set_byte(0x40);
+        assert_eq!(right.get_byte() & 0x02, 0x02);
+
+        left.set_byte(0x00);
+        right.set_byte(0xC0);
+        assert_eq!(right.get_byte() & 0x02, 0x02);
+    }
+
+    #[test]
+    fn test_register_is_cgb_only() {
+        let rom = vec![0; 0x8000];
+        let mut dmg = Cpu::with_model(rom.clone(), Model::Dmg);
+        dmg.mmu.set_byte(0xFF56, 0xC1);
+        assert_eq!(dmg.mmu.get_byte(0xFF56), 0xFF);
+
+        let mut a = Cpu::with_model(rom.clone(), Model::Cgb);
+        let mut b = Cpu::with_model(rom, Model::Cgb);
+        let (left, right) = InfraredLink::pair();
+        a.mmu.infrared.connect(left);
+        b.mmu.infrared.connect(right);
+
+        b.mmu.set_byte(0xFF56, 0xC0);
+        a.mmu.set_byte(0xFF56, 0x01);
+        assert_eq!(b.mmu.get_byte(0xFF56), 0xFC);
+        assert_eq!(a.mmu.get_byte(0xFF56), 0x3F);
+    }
+}
@@ -0,0 +1,181 @@
+// Host input bindings.
+//
+// A keymap ties the names of host inputs to the buttons of one of the four
+// controllers. The names are opaque to the emulator: the browser frontend uses
+// `KeyboardEvent.code` values like "ArrowUp" and "Pad1.Button0" for gamepad
+// buttons, but any name without whitespace or '=' works, so native frontends
+// can share the same format.
+//
+// A keymap is stored as text, one binding per line:
+//
+//     ArrowUp = up
+//     KeyZ = 2:a
+//
+// The optional number before the button is the controller, starting at 1.
+// Empty lines and everything after a '#' are ignored.
+
+use crate::joypad::{Key, MAX_PLAYERS};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Binding {
+    pub host: String,
+    pub player: usize,
+    pub key: Key,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyMap {
+    bindings: Vec<Binding>,
+}
+
+impl KeyMap {
+    pub fn empty() -> Self {
+        Self { bindings: vec![] }
+    }
+
+    /// Binds the host input `host` to `key` on controller `player`, replacing
+    /// whatever it was bound to before.
+    pub fn bind(&mut self, host: &str, player: usize, key: Key) -> Result<(), String> {
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '=' || c == '#') {
+            return Err(format!("invalid host input name {:?}", host));
+        }
+        if player >= MAX_PLAYERS {
+            return Err(format!("invalid controller {}", player + 1));
+        }
+
+        let binding = Binding {
+            host: host.to_string(),
+            player,
+            key,
+        };
+        match self.bindings.iter_mut().find(|b| b.host == host) {
+            Some(existing) => *existing = binding,
+            None => self.bindings.push(binding),
+        }
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, host: &str) {
+        self.bindings.retain(|b| b.host != host);
+    }
+
+    /// The controller and key bound to the host input `host`.
+    pub fn lookup(&self, host: &str) -> Option<(usize, Key)> {
+        self.bindings
+            .iter()
+            .find(|b| b.host == host)
+            .map(|b| (b.player, b.key))
+    }
+}
+
+impl Default for KeyMap {
+    /// The keyboard layout the browser frontend has always used.
+    fn default() -> Self {
+        let mut keymap = Self::empty();
+        for (host, key) in [
+            ("ArrowRight", Key::Right),
+            ("ArrowLeft", Key::Left),
+            ("ArrowUp", Key::Up),
+            ("ArrowDown", Key::Down),
+            ("KeyA", Key::BtnA),
+            ("KeyS", Key::BtnB),
+            ("Space", Key::Select),
+            ("Enter", Key::Start),
+        ] {
+            keymap.bind(host, 0, key).unwrap();
+        }
+        keymap
+    }
+}
+
+impl fmt::Display for KeyMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for binding in &self.bindings {
+            match binding.player {
+                0 => writeln!(f, "{} = {}", binding.host, binding.key)?,
+                player => writeln!(f, "{} = {}:{}", binding.host, player + 1, binding.key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for KeyMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keymap = Self::empty();
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let (host, button) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected \"input = button\", got {:?}", line)))?;
+            let (player, key) = match button.trim().split_once(':') {
+                Some((player, key)) => match player.trim().parse::<usize>() {
+                    Ok(player @ 1..=MAX_PLAYERS) => (player - 1, key),
+                    _ => return Err(error(format!("invalid controller {:?}", player.trim()))),
+                },
+                None => (0, button),
+            };
+            let key = key.trim().parse().map_err(error)?;
+            keymap.bind(host.trim(), player, key).map_err(error)?;
+        }
+
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let keymap: KeyMap = "
+            # Player one
+            ArrowUp = up
+            KeyZ=a   # trailing comment
+            Pad2.Button0 = 2:START
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(keymap.lookup("ArrowUp"), Some((0, Key::Up)));
+        assert_eq!(keymap.lookup("KeyZ"), Some((0, Key::BtnA)));
+        assert_eq!(keymap.lookup("Pad2.Button0"), Some((1, Key::Start)));
+        assert_eq!(keymap.lookup("KeyX"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut keymap = KeyMap::default();
+        keymap.bind("Pad4.Button9", 3, Key::Select).unwrap();
+        keymap.bind("ArrowUp", 1, Key::Up).unwrap();
+        keymap.unbind("Space");
+
+        let text = keymap.to_string();
+        assert!(text.contains("ArrowUp = 2:up\n"));
+        assert!(!text.contains("Space"));
+        assert_eq!(text.parse(), Ok(keymap));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            "ArrowUp = up\nKeyZ".parse::<KeyMap>(),
+            Err("line 2: expected \"input = button\", got \"KeyZ\"".to_string())
+        );
+        assert!("KeyZ = 5:a".parse::<KeyMap>().is_err());
+        assert!("KeyZ = turbo".parse::<KeyMap>().is_err());
+        assert!("Key Z = a".parse::<KeyMap>().is_err());
+        assert!(KeyMap::empty().bind("KeyZ", 4, Key::BtnA).is_err());
+    }
+}
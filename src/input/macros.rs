@@ -0,0 +1,162 @@
+// Input macros.
+//
+// A macro is a scripted sequence of button states, each held for a number of
+// frames. It is written as comma separated steps of '+' joined buttons, with
+// '_' for no buttons and an optional frame count after a '*':
+//
+//     down*8, a+b*2, _*4, start
+//
+// Playback only depends on the number of frames run, so a macro triggered on
+// the same frame always produces the same input.
+
+use crate::joypad::Key;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Step {
+    pub state: u8,
+    pub frames: u32,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Macro {
+    steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    /// The button state `frame` frames after the start, or None once the
+    /// macro is over.
+    pub fn state(&self, frame: u32) -> Option<u8> {
+        let mut start = 0;
+        for step in &self.steps {
+            start += step.frames;
+            if frame < start {
+                return Some(step.state);
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+
+            let keys: Vec<_> = Key::ALL
+                .iter()
+                .filter(|key| step.state & key.mask() != 0)
+                .map(|key| key.name())
+                .collect();
+            match keys.as_slice() {
+                [] => f.write_str("_")?,
+                keys => f.write_str(&keys.join("+"))?,
+            }
+
+            if step.frames != 1 {
+                write!(f, "*{}", step.frames)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Macro {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .map(|step| {
+                let (keys, frames) = match step.split_once('*') {
+                    Some((keys, frames)) => {
+                        let frames = frames
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("invalid frame count {:?}", frames.trim()))?;
+                        (keys, frames)
+                    }
+                    None => (step, 1),
+                };
+
+                let state = match keys.trim() {
+                    "_" => 0,
+                    keys => keys.split('+').try_fold(0, |state, key| {
+                        key.trim().parse().map(|key: Key| state | key.mask())
+                    })?,
+                };
+
+                Ok(Step { state, frames })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self::new(steps))
+    }
+}
+
+/// A macro being played back on one of the controllers.
+#[derive(Clone)]
+pub struct Playback {
+    pub player: usize,
+    pub frame: u32,
+    pub script: Macro,
+}
+
+impl Playback {
+    pub fn new(player: usize, script: Macro) -> Self {
+        Self {
+            player,
+            frame: 0,
+            script,
+        }
+    }
+
+    pub fn state(&self) -> Option<u8> {
+        self.script.state(self.frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script: Macro = "down*3, a + b*2, _*2, START".parse().unwrap();
+        let down = Key::Down.mask();
+        let ab = Key::BtnA.mask() | Key::BtnB.mask();
+
+        let states: Vec<_> = (0..9).map(|frame| script.state(frame)).collect();
+        assert_eq!(
+            states,
+            vec![
+                Some(down),
+                Some(down),
+                Some(down),
+                Some(ab),
+                Some(ab),
+                Some(0),
+                Some(0),
+                Some(Key::Start.mask()),
+                None
+            ]
+        );
+
+        assert_eq!(script.to_string(), "down*3, a+b*2, _*2, start");
+        assert_eq!(script.to_string().parse(), Ok(script));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!("a*x".parse::<Macro>().is_err());
+        assert!("a+turbo".parse::<Macro>().is_err());
+        assert!("a,,b".parse::<Macro>().is_err());
+    }
+}
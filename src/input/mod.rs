@@ -0,0 +1,135 @@
+pub mod keymap;
+pub mod macros;
+pub mod turbo;
+
+use crate::input::macros::{Macro, Playback};
+use crate::input::turbo::Turbo;
+use crate::joypad::{Key, MAX_PLAYERS};
+use std::collections::HashMap;
+
+/// The buttons the game sees, combining the keys held on the host with
+/// autofire and running macros. The state only changes with host input and
+/// once per frame in `advance`, so playback is deterministic.
+#[derive(Clone, Default)]
+pub struct Input {
+    held: [u8; MAX_PLAYERS],
+    pub turbo: Turbo,
+    macros: HashMap<String, (usize, Macro)>,
+    playing: Vec<Playback>,
+}
+
+impl Input {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn press(&mut self, player: usize, key: Key) {
+        self.held[player % MAX_PLAYERS] |= key.mask();
+    }
+
+    pub fn release(&mut self, player: usize, key: Key) {
+        self.held[player % MAX_PLAYERS] &= !key.mask();
+    }
+
+    /// Sets all keys held on the host for a controller, in the format of
+    /// `Joypad::buttons`.
+    pub fn set_held(&mut self, player: usize, state: u8) {
+        self.held[player % MAX_PLAYERS] = state;
+    }
+
+    /// Makes the host input `host` start `script` on controller `player`.
+    pub fn bind_macro(&mut self, host: &str, player: usize, script: Macro) {
+        self.macros
+            .insert(host.to_string(), (player % MAX_PLAYERS, script));
+    }
+
+    pub fn unbind_macro(&mut self, host: &str) {
+        self.macros.remove(host);
+    }
+
+    /// Starts the macro bound to `host`, returning false if there is none.
+    pub fn trigger(&mut self, host: &str) -> bool {
+        match self.macros.get(host) {
+            Some((player, script)) => {
+                self.play(*player, script.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn play(&mut self, player: usize, script: Macro) {
+        self.playing
+            .push(Playback::new(player % MAX_PLAYERS, script));
+    }
+
+    pub fn stop_macros(&mut self) {
+        self.playing.clear();
+    }
+
+    pub fn playing(&self) -> bool {
+        !self.playing.is_empty()
+    }
+
+    /// The buttons pressed on controller `player` for the current frame.
+    pub fn buttons(&self, player: usize) -> u8 {
+        let player = player % MAX_PLAYERS;
+
+        self.playing
+            .iter()
+            .filter(|playback| playback.player == player)
+            .filter_map(Playback::state)
+            .fold(
+                self.turbo.apply(player, self.held[player]),
+                |state, macro_state| state | macro_state,
+            )
+    }
+
+    /// Moves on to the next frame.
+    pub fn advance(&mut self) {
+        for (player, &held) in self.held.iter().enumerate() {
+            self.turbo.advance(player, held);
+        }
+
+        for playback in &mut self.playing {
+            playback.frame += 1;
+        }
+        self.playing.retain(|playback| playback.state().is_some());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_macro_with_turbo() {
+        let mut input = Input::new();
+        input.turbo.set(1, Key::BtnA, 2);
+        input.bind_macro("KeyM", 1, "up*2, _, select".parse().unwrap());
+        input.press(1, Key::BtnA);
+
+        assert!(!input.trigger("KeyN"));
+        assert!(input.trigger("KeyM"));
+
+        let up = Key::Up.mask();
+        let a = Key::BtnA.mask();
+        let mut states = vec![];
+        for _ in 0..5 {
+            states.push((input.buttons(0), input.buttons(1)));
+            input.advance();
+        }
+
+        assert_eq!(
+            states,
+            vec![
+                (0, up | a),
+                (0, up),
+                (0, a),
+                (0, Key::Select.mask()),
+                (0, a)
+            ]
+        );
+        assert!(!input.playing());
+    }
+}
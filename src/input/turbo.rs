@@ -0,0 +1,96 @@
+// Autofire.
+//
+// A turbo button alternates between pressed and released while it is held on
+// the host. The period is the number of frames of one press and release cycle:
+// the button is pressed for the first half of it, rounded up, so that it always
+// goes down on the first frame it is held.
+
+use crate::joypad::{Key, MAX_PLAYERS};
+
+#[derive(Clone, Default)]
+pub struct Turbo {
+    /// The period of each key, 0 for keys without autofire.
+    periods: [[u32; 8]; MAX_PLAYERS],
+    /// The number of frames each key has been held for.
+    held_frames: [[u32; 8]; MAX_PLAYERS],
+}
+
+impl Turbo {
+    /// Enables autofire for `key` with a period of `period` frames, or
+    /// disables it if `period` is 0. Periods below 2 can't alternate and are
+    /// raised to 2.
+    pub fn set(&mut self, player: usize, key: Key, period: u32) {
+        self.periods[player % MAX_PLAYERS][key.index()] = match period {
+            0 => 0,
+            period => period.max(2),
+        };
+    }
+
+    /// Applies autofire to the keys `held` on the host for the current frame.
+    pub fn apply(&self, player: usize, held: u8) -> u8 {
+        let player = player % MAX_PLAYERS;
+
+        Key::ALL.iter().fold(0, |state, &key| {
+            let period = self.periods[player][key.index()];
+            let frame = self.held_frames[player][key.index()];
+            let pressed =
+                held & key.mask() != 0 && (period == 0 || frame % period < period.div_ceil(2));
+
+            if pressed {
+                state | key.mask()
+            } else {
+                state
+            }
+        })
+    }
+
+    /// Moves on to the next frame with the keys in `held` still held.
+    pub fn advance(&mut self, player: usize, held: u8) {
+        for key in Key::ALL {
+            let frames = &mut self.held_frames[player % MAX_PLAYERS][key.index()];
+            *frames = match held & key.mask() {
+                0 => 0,
+                _ => frames.wrapping_add(1),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(turbo: &mut Turbo, held: u8, frames: usize) -> Vec<u8> {
+        (0..frames)
+            .map(|_| {
+                let state = turbo.apply(0, held);
+                turbo.advance(0, held);
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_autofire() {
+        let a = Key::BtnA.mask();
+        let b = Key::BtnB.mask();
+        let mut turbo = Turbo::default();
+        turbo.set(0, Key::BtnA, 4);
+
+        assert_eq!(
+            run(&mut turbo, a | b, 6),
+            vec![a | b, a | b, b, b, a | b, a | b]
+        );
+
+        // Letting go restarts the cycle.
+        assert_eq!(run(&mut turbo, 0, 1), vec![0]);
+        assert_eq!(run(&mut turbo, a, 3), vec![a, a, 0]);
+
+        // A period of 1 alternates every frame like a period of 2.
+        turbo.set(0, Key::BtnA, 1);
+        assert_eq!(run(&mut turbo, 0, 1), vec![0]);
+        assert_eq!(run(&mut turbo, a, 3), vec![a, 0, a]);
+        turbo.set(0, Key::BtnA, 0);
+        assert_eq!(run(&mut turbo, a, 3), vec![a, a, a]);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::utils::named_enum;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Key {
     Up,
     Down,
@@ -9,26 +12,61 @@ pub enum Key {
     Select,
 }
 
+// Listed in the order of their bits in a button state.
+named_enum!(Key, "button", {
+    Right => "right",
+    Left => "left",
+    Up => "up",
+    Down => "down",
+    BtnA => "a",
+    BtnB => "b",
+    Select => "select",
+    Start => "start",
+});
+
+impl Key {
+    pub fn from_index(index: usize) -> Option<Key> {
+        Self::ALL.get(index).copied()
+    }
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|&key| key == self).unwrap()
+    }
+
+    /// The bit of this key in a button state byte.
+    pub fn mask(self) -> u8 {
+        1 << self.index()
+    }
+}
+
+/// The most controllers an SGB can request with MLT_REQ.
+pub const MAX_PLAYERS: usize = 4;
+
+#[derive(Clone)]
 pub struct Joypad {
     pub request_joypad_int: bool,
     joyp: u8,
-    btn_keys: u8,
-    dir_keys: u8,
+    btn_keys: [u8; MAX_PLAYERS],
+    dir_keys: [u8; MAX_PLAYERS],
+    players: u8,
+    player: u8,
 }
 
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
             request_joypad_int: false,
-            joyp: 0xF0,
-            btn_keys: 0x0F,
-            dir_keys: 0x0F,
+            joyp: 0x30,
+            btn_keys: [0x0F; MAX_PLAYERS],
+            dir_keys: [0x0F; MAX_PLAYERS],
+            players: 1,
+            player: 0,
         }
     }
 
     pub fn get_byte(&mut self, addr: u16) -> u8 {
         match addr {
-            0xFF00 => self.joyp(),
+            0xFF00 => 0xC0 | self.joyp | self.joyp(),
             _ => unreachable!(),
         }
     }
@@ -37,68 +75,184 @@ impl Joypad {
         match addr {
             0xFF00 => {
                 let old_signal = self.joyp();
-
                 self.joyp = value & 0x30;
 
-                // if old_signal & !self.joyp() != 0 {
-                //     self.request_joypad_int = true;
-                // }
+                // Selecting a line with a key held pulls an input low too.
+                self.check_interrupt(old_signal);
             }
             _ => unreachable!(),
         }
     }
 
     pub fn press_key(&mut self, key: Key) {
+        self.press_player_key(0, key);
+    }
+
+    pub fn release_key(&mut self, key: Key) {
+        self.release_player_key(0, key);
+    }
+
+    /// Presses `key` on one of the controllers of an SGB multiplayer setup.
+    pub fn press_player_key(&mut self, player: usize, key: Key) {
         let old_signal = self.joyp();
+        let (keys, mask) = self.key_bit(player, key);
+        *keys &= !mask;
+
+        self.check_interrupt(old_signal);
+    }
+
+    pub fn release_player_key(&mut self, player: usize, key: Key) {
+        let (keys, mask) = self.key_bit(player, key);
+        *keys |= mask;
+    }
+
+    /// The keys held on a controller, one bit per key in the order of
+    /// `Key::ALL` (1=Pressed).
+    pub fn buttons(&mut self, player: usize) -> u8 {
+        Key::ALL
+            .iter()
+            .filter(|&&key| {
+                let (keys, mask) = self.key_bit(player, key);
+                *keys & mask == 0
+            })
+            .fold(0, |state, key| state | key.mask())
+    }
+
+    fn key_bit(&mut self, player: usize, key: Key) -> (&mut u8, u8) {
+        let player = player % MAX_PLAYERS;
         // Bit 3 - P13 Input Down  or Start    (0=Pressed) (Read Only)
         // Bit 2 - P12 Input Up    or Select   (0=Pressed) (Read Only)
         // Bit 1 - P11 Input Left  or Button B (0=Pressed) (Read Only)
         // Bit 0 - P10 Input Right or Button A (0=Pressed) (Read Only)
         match key {
-            Key::BtnA => self.btn_keys = self.btn_keys & 0x0E,
-            Key::BtnB => self.btn_keys = self.btn_keys & 0x0D,
-            Key::Select => self.btn_keys = self.btn_keys & 0x0B,
-            Key::Start => self.btn_keys = self.btn_keys & 0x07,
-            Key::Right => self.dir_keys = self.dir_keys & 0x0E,
-            Key::Left => self.dir_keys = self.dir_keys & 0x0D,
-            Key::Up => self.dir_keys = self.dir_keys & 0x0B,
-            Key::Down => self.dir_keys = self.dir_keys & 0x07,
+            Key::BtnA => (&mut self.btn_keys[player], 0x01),
+            Key::BtnB => (&mut self.btn_keys[player], 0x02),
+            Key::Select => (&mut self.btn_keys[player], 0x04),
+            Key::Start => (&mut self.btn_keys[player], 0x08),
+            Key::Right => (&mut self.dir_keys[player], 0x01),
+            Key::Left => (&mut self.dir_keys[player], 0x02),
+            Key::Up => (&mut self.dir_keys[player], 0x04),
+            Key::Down => (&mut self.dir_keys[player], 0x08),
         }
+    }
 
-        // if old_signal & !self.joyp() != 0 {
-        //     self.request_joypad_int = true;
-        // }
+    /// The joypad interrupt fires when any of P10-P13 goes from high to low.
+    fn check_interrupt(&mut self, old_signal: u8) {
+        if old_signal & !self.joyp() & 0x0F != 0 {
+            self.request_joypad_int = true;
+        }
     }
 
-    pub fn release_key(&mut self, key: Key) {
-        let old_signal = self.joyp();
-        // Bit 3 - P13 Input Down  or Start    (0=Pressed) (Read Only)
-        // Bit 2 - P12 Input Up    or Select   (0=Pressed) (Read Only)
-        // Bit 1 - P11 Input Left  or Button B (0=Pressed) (Read Only)
-        // Bit 0 - P10 Input Right or Button A (0=Pressed) (Read Only)
-        match key {
-            Key::BtnA => self.btn_keys = self.btn_keys | 0x01,
-            Key::BtnB => self.btn_keys = self.btn_keys | 0x02,
-            Key::Select => self.btn_keys = self.btn_keys | 0x04,
-            Key::Start => self.btn_keys = self.btn_keys | 0x08,
-            Key::Right => self.dir_keys = self.dir_keys | 0x01,
-            Key::Left => self.dir_keys = self.dir_keys | 0x02,
-            Key::Up => self.dir_keys = self.dir_keys | 0x04,
-            Key::Down => self.dir_keys = self.dir_keys | 0x08,
+    /// Sets the number of controllers requested by the SGB's MLT_REQ.
+    pub fn set_players(&mut self, players: u8) {
+        if players != self.players {
+            self.players = players;
+            self.player = 0;
         }
+    }
+
+    /// Moves on to the next of several SGB controllers.
+    pub fn next_player(&mut self) {
+        self.player = (self.player + 1) % self.players;
+    }
 
-        // if old_signal & !self.joyp() != 0 {
-        //     self.request_joypad_int = true;
-        // }
+    pub fn players(&self) -> u8 {
+        self.players
+    }
+
+    /// The controller currently read, 0 unless several were requested.
+    pub fn player(&self) -> u8 {
+        self.player
     }
 
     fn joyp(&self) -> u8 {
-        if (self.joyp & 0x10) == 0 {
-            self.dir_keys
-        } else if (self.joyp & 0x20) == 0 {
-            self.btn_keys
-        } else {
-            0xCF
+        let player = self.player as usize;
+
+        match self.joyp & 0x30 {
+            0x00 => self.dir_keys[player] & self.btn_keys[player],
+            0x10 => self.btn_keys[player],
+            0x20 => self.dir_keys[player],
+            // With both lines deselected the SGB returns the controller ID.
+            _ => 0x0F - self.player,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt() {
+        let mut joypad = Joypad::new();
+
+        // Nothing selected, so a key press doesn't reach the lines.
+        joypad.press_key(Key::BtnA);
+        assert!(!joypad.request_joypad_int);
+
+        // Selecting the buttons with A held pulls P10 low.
+        joypad.set_byte(0xFF00, 0x10);
+        assert!(joypad.request_joypad_int);
+        assert_eq!(joypad.get_byte(0xFF00), 0xDE);
+
+        joypad.request_joypad_int = false;
+        joypad.press_key(Key::Right);
+        assert!(!joypad.request_joypad_int);
+        joypad.press_key(Key::Start);
+        assert!(joypad.request_joypad_int);
+
+        // Releasing is a low to high transition.
+        joypad.request_joypad_int = false;
+        joypad.release_key(Key::Start);
+        joypad.release_key(Key::BtnA);
+        assert!(!joypad.request_joypad_int);
+    }
+
+    #[test]
+    fn test_players() {
+        let mut joypad = Joypad::new();
+        joypad.set_players(4);
+        joypad.press_player_key(2, Key::Down);
+
+        let read = |joypad: &mut Joypad| {
+            joypad.set_byte(0xFF00, 0x20);
+            let keys = joypad.get_byte(0xFF00) & 0x0F;
+            joypad.set_byte(0xFF00, 0x30);
+            let id = joypad.get_byte(0xFF00) & 0x0F;
+            (keys, id)
+        };
+
+        // Writing the register alone doesn't change controllers, the SGB
+        // decides when a read is over.
+        assert_eq!(read(&mut joypad), (0x0F, 0x0F));
+        joypad.set_byte(0xFF00, 0x10);
+        assert_eq!(read(&mut joypad), (0x0F, 0x0F));
+        joypad.next_player();
+        assert_eq!(read(&mut joypad), (0x0F, 0x0E));
+        joypad.next_player();
+        assert_eq!(read(&mut joypad), (0x07, 0x0D));
+        joypad.next_player();
+        joypad.next_player();
+        assert_eq!(read(&mut joypad), (0x0F, 0x0F));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.press_player_key(1, Key::BtnB);
+        joypad.press_player_key(1, Key::Up);
+        assert_eq!(joypad.buttons(0), 0);
+        assert_eq!(joypad.buttons(1), Key::BtnB.mask() | Key::Up.mask());
+    }
+
+    #[test]
+    fn test_key_names() {
+        for key in Key::ALL {
+            assert_eq!(key.name().parse(), Ok(key));
+            assert_eq!(Key::from_index(key.index()), Some(key));
         }
+        assert_eq!("START".parse(), Ok(Key::Start));
+        assert!("turbo".parse::<Key>().is_err());
+        assert_eq!(Key::from_index(8), None);
     }
 }
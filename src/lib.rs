@@ -4,8 +4,12 @@ pub mod cpu;
 pub mod emulator;
 mod events;
 mod gpu;
+pub mod infrared;
+mod input;
 mod joypad;
 mod memory;
+pub mod serial;
+mod sgb;
 mod timer;
 mod utils;
 
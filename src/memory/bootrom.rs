@@ -1,25 +1,225 @@
+use crate::cpu::Model;
+use crate::utils::crc32;
+use std::fmt;
+
+/// Size of the DMG, MGB and SGB boot ROMs.
+pub const DMG_BOOTROM_SIZE: usize = 0x100;
+/// Size of the CGB and AGB boot ROMs. The image covers 0000-08FF, but
+/// 0100-01FF is never mapped so the cartridge header stays visible.
+pub const CGB_BOOTROM_SIZE: usize = 0x900;
+
+// CRC32s of the known good dumps.
+const DMG0_CRC32: u32 = 0xC2F5_CC97;
+const DMG_CRC32: u32 = 0x59C8_598E;
+const MGB_CRC32: u32 = 0xE692_0754;
+const SGB_CRC32: u32 = 0xEC8A_83B9;
+const SGB2_CRC32: u32 = 0x53D0_DD63;
+const CGB_CRC32: u32 = 0x4188_4E46;
+const AGB_CRC32: u32 = 0xFFD6_B0F1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BootromError {
+    InvalidSize { model: Model, size: usize },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    UnknownDump { model: Model, actual: u32 },
+}
+
+impl fmt::Display for BootromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootromError::InvalidSize { model, size } => write!(
+                f,
+                "invalid {} boot ROM size {}, expected {} bytes",
+                model,
+                size,
+                Bootrom::size(*model)
+            ),
+            BootromError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "boot ROM checksum mismatch, expected {:08X}, got {:08X}",
+                expected, actual
+            ),
+            BootromError::UnknownDump { model, actual } => write!(
+                f,
+                "not a known {} boot ROM dump, got checksum {:08X}",
+                model, actual
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Bootrom {
     pub bootrom: Vec<u8>,
     pub is_active: bool,
+    model: Model,
 }
 
-impl Bootrom {
-    pub fn new() -> Self {
+impl Default for Bootrom {
+    fn default() -> Self {
         Bootrom {
-            bootrom: vec![], // Placeholder for Boot ROM data
+            bootrom: vec![],
+            is_active: false,
+            model: Model::Dmg,
+        }
+    }
+}
+
+impl Bootrom {
+    /// Takes a boot ROM image dumped from `model`. The image size only tells
+    /// the DMG family and the CGB family apart, so the model has to be given.
+    pub fn new(data: Vec<u8>, model: Model) -> Result<Self, BootromError> {
+        if data.len() != Self::size(model) {
+            return Err(BootromError::InvalidSize {
+                model,
+                size: data.len(),
+            });
+        }
+
+        Ok(Bootrom {
+            bootrom: data,
             is_active: false,
+            model,
+        })
+    }
+
+    /// Like `new`, but also checks the CRC32 of the image, for frontends that
+    /// know which dump they expect.
+    pub fn with_crc32(data: Vec<u8>, model: Model, expected: u32) -> Result<Self, BootromError> {
+        let actual = crc32(&data);
+        if actual != expected {
+            return Err(BootromError::ChecksumMismatch { expected, actual });
         }
+
+        Self::new(data, model)
+    }
+
+    /// Like `new`, but only takes one of the known dumps of the boot ROM of
+    /// `model`.
+    pub fn verified(data: Vec<u8>, model: Model) -> Result<Self, BootromError> {
+        let actual = crc32(&data);
+        if !Self::known_crc32s(model).contains(&actual) {
+            return Err(BootromError::UnknownDump { model, actual });
+        }
+
+        Self::new(data, model)
+    }
+
+    /// The CRC32s of the known dumps of the boot ROM of `model`.
+    pub fn known_crc32s(model: Model) -> &'static [u32] {
+        match model {
+            Model::Dmg => &[DMG_CRC32, DMG0_CRC32],
+            Model::Mgb => &[MGB_CRC32],
+            Model::Sgb => &[SGB_CRC32],
+            Model::Sgb2 => &[SGB2_CRC32],
+            Model::Cgb => &[CGB_CRC32],
+            Model::Agb => &[AGB_CRC32],
+        }
+    }
+
+    /// The size of the boot ROM of `model`.
+    pub fn size(model: Model) -> usize {
+        if model.is_cgb() {
+            CGB_BOOTROM_SIZE
+        } else {
+            DMG_BOOTROM_SIZE
+        }
+    }
+
+    /// The model the boot ROM was dumped from.
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.bootrom)
+    }
+
+    /// Whether this is a CGB or AGB boot ROM.
+    pub fn is_cgb(&self) -> bool {
+        self.model.is_cgb()
     }
 
     pub fn activate(&mut self) {
-        self.is_active = true;
+        self.is_active = !self.bootrom.is_empty();
     }
 
     pub fn deactivate(&mut self) {
         self.is_active = false;
     }
 
+    /// Whether `addr` currently reads from the boot ROM instead of the cartridge.
+    pub fn maps(&self, addr: u16) -> bool {
+        match addr {
+            0x0000..=0x00FF => self.is_active,
+            0x0200..=0x08FF => self.is_active && self.is_cgb(),
+            _ => false,
+        }
+    }
+
     pub fn get_byte(&self, addr: usize) -> u8 {
         self.bootrom[addr]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(
+            Bootrom::new(vec![0; 0x200], Model::Dmg).err(),
+            Some(BootromError::InvalidSize {
+                model: Model::Dmg,
+                size: 0x200
+            })
+        );
+        // A CGB sized image can't be an MGB boot ROM.
+        assert!(Bootrom::new(vec![0; CGB_BOOTROM_SIZE], Model::Mgb).is_err());
+
+        let mut dmg = Bootrom::new(vec![0; DMG_BOOTROM_SIZE], Model::Dmg).unwrap();
+        assert!(!dmg.maps(0x0000));
+        dmg.activate();
+        assert!(dmg.maps(0x00FF));
+        assert!(!dmg.maps(0x0200));
+
+        let mut cgb = Bootrom::new(vec![0; CGB_BOOTROM_SIZE], Model::Agb).unwrap();
+        assert_eq!(cgb.model(), Model::Agb);
+        cgb.activate();
+        assert!(cgb.maps(0x00FF));
+        assert!(!cgb.maps(0x0100));
+        assert!(!cgb.maps(0x01FF));
+        assert!(cgb.maps(0x0200));
+        assert!(cgb.maps(0x08FF));
+        assert!(!cgb.maps(0x0900));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let data = vec![0; DMG_BOOTROM_SIZE];
+        let actual = crc32(&data);
+        assert!(Bootrom::with_crc32(data.clone(), Model::Sgb, actual).is_ok());
+        assert_eq!(
+            Bootrom::with_crc32(data.clone(), Model::Sgb, !actual).err(),
+            Some(BootromError::ChecksumMismatch {
+                expected: !actual,
+                actual
+            })
+        );
+
+        assert_eq!(
+            Bootrom::verified(data.clone(), Model::Dmg).err(),
+            Some(BootromError::UnknownDump {
+                model: Model::Dmg,
+                actual
+            })
+        );
+        assert!(Bootrom::new(data, Model::Dmg).is_ok());
+        for model in Model::ALL.iter() {
+            assert!(!Bootrom::known_crc32s(*model).is_empty());
+        }
+    }
+}
@@ -0,0 +1,8 @@
+/// A memory bus the CPU can be attached to in place of the `Mmu`, e.g. a flat
+/// 64 KiB memory for running CPU conformance tests in isolation.
+pub trait Bus {
+    fn get_byte(&mut self, addr: u16) -> u8;
+    fn set_byte(&mut self, addr: u16, value: u8);
+    /// Called whenever the CPU advances by `cycles` clocks.
+    fn tick(&mut self, _cycles: usize) {}
+}
@@ -0,0 +1,229 @@
+// Code/Data Logger.
+//
+// Keeps one flag byte per ROM and cartridge RAM byte that records how the byte
+// has been accessed so far. The exported `.cdl` file holds the ROM flags,
+// followed by the cartridge RAM flags, one byte per byte. Only bits 0 and 1
+// match FCEUX, meaning "code" and "data", so tools that look at nothing else
+// can read the file. The other bits are specific to this emulator and mean
+// something different to FCEUX:
+//
+//   bit 0   executed, either as an opcode or as an operand
+//   bit 1   read as data by the CPU
+//   bit 6   read by OAM DMA or HDMA
+//   bit 7   executed as the first byte of an instruction
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const CDL_CODE: u8 = 0x01;
+pub const CDL_DATA: u8 = 0x02;
+pub const CDL_DMA: u8 = 0x40;
+pub const CDL_OPCODE: u8 = 0x80;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Opcode,
+    Operand,
+    Data,
+    Dma,
+}
+
+impl Access {
+    fn flags(self) -> u8 {
+        match self {
+            Access::Opcode => CDL_CODE | CDL_OPCODE,
+            Access::Operand => CDL_CODE,
+            Access::Data => CDL_DATA,
+            Access::Dma => CDL_DMA,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CdlStats {
+    pub code: usize,
+    pub data: usize,
+    pub both: usize,
+    pub unaccessed: usize,
+}
+
+#[derive(Clone)]
+pub struct CodeDataLog {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize, ram_size: usize) -> Self {
+        Self {
+            rom: vec![0; rom_size],
+            ram: vec![0; ram_size],
+        }
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn mark_rom(&mut self, offset: usize, access: Access) {
+        if let Some(flags) = self.rom.get_mut(offset) {
+            *flags |= access.flags();
+        }
+    }
+
+    pub fn mark_ram(&mut self, offset: usize, access: Access) {
+        if let Some(flags) = self.ram.get_mut(offset) {
+            *flags |= access.flags();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rom.iter_mut().for_each(|flags| *flags = 0);
+        self.ram.iter_mut().for_each(|flags| *flags = 0);
+    }
+
+    pub fn rom_stats(&self) -> CdlStats {
+        let mut stats = CdlStats::default();
+
+        for flags in &self.rom {
+            match (flags & CDL_CODE != 0, flags & (CDL_DATA | CDL_DMA) != 0) {
+                (true, true) => stats.both += 1,
+                (true, false) => stats.code += 1,
+                (false, true) => stats.data += 1,
+                (false, false) => stats.unaccessed += 1,
+            }
+        }
+
+        stats
+    }
+
+    pub fn export<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.rom)?;
+        writer.write_all(&self.ram)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        self.export(&mut file)
+    }
+
+    /// Adds the flags of a previously exported log, so that several sessions can
+    /// be combined. The log must have been made for a cartridge of the same size.
+    pub fn merge<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        if data.len() != self.rom.len() + self.ram.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected a {} byte log, got {} bytes",
+                    self.rom.len() + self.ram.len(),
+                    data.len()
+                ),
+            ));
+        }
+
+        let (rom, ram) = data.split_at(self.rom.len());
+        for (flags, other) in self.rom.iter_mut().zip(rom) {
+            *flags |= other;
+        }
+        for (flags, other) in self.ram.iter_mut().zip(ram) {
+            *flags |= other;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn test_export_and_merge() {
+        let mut cdl = CodeDataLog::new(4, 2);
+        cdl.mark_rom(0, Access::Opcode);
+        cdl.mark_rom(1, Access::Operand);
+        cdl.mark_rom(2, Access::Data);
+        cdl.mark_rom(2, Access::Dma);
+        cdl.mark_ram(1, Access::Data);
+        cdl.mark_rom(100, Access::Data);
+
+        let mut file = vec![];
+        cdl.export(&mut file).unwrap();
+        assert_eq!(file, vec![0x81, 0x01, 0x42, 0x00, 0x00, 0x02]);
+
+        let mut other = CodeDataLog::new(4, 2);
+        other.mark_rom(3, Access::Opcode);
+        other.merge(&mut file.as_slice()).unwrap();
+        assert_eq!(other.rom(), &[0x81, 0x01, 0x42, 0x81]);
+        assert_eq!(
+            other.rom_stats(),
+            CdlStats {
+                code: 3,
+                data: 1,
+                both: 0,
+                unaccessed: 0
+            }
+        );
+
+        assert!(other.merge(&mut [0u8; 3].as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_cpu_logging() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD A,($4000); LD A,$12; JR -2
+        rom[0x100..0x107].copy_from_slice(&[0xFA, 0x00, 0x40, 0x3E, 0x12, 0x18, 0xFE]);
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.mmu.cdl = Some(CodeDataLog::new(0x8000, 0x2000));
+
+        for _ in 0..4 {
+            cpu.tick();
+        }
+
+        // Debug reads don't count.
+        cpu.mmu.peek(0x200);
+
+        let cdl = cpu.mmu.cdl.as_ref().unwrap();
+        assert_eq!(
+            &cdl.rom()[0x100..0x107],
+            &[0x81, 0x01, 0x01, 0x81, 0x01, 0x81, 0x01]
+        );
+        assert_eq!(cdl.rom()[0x4000], CDL_DATA);
+        assert_eq!(cdl.rom()[0x200], 0);
+    }
+
+    #[test]
+    fn test_ram_size_follows_header() {
+        let size = |mbc: u8, ram: u8| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x147] = mbc;
+            rom[0x149] = ram;
+            let cartridge = Cartridge::new(rom);
+            let mut file = vec![];
+            CodeDataLog::new(cartridge.rom_size(), cartridge.ram_size())
+                .export(&mut file)
+                .unwrap();
+            file.len() - 0x8000
+        };
+
+        // MBC5 with 8 KiB of RAM, and without any.
+        assert_eq!(size(0x1B, 0x02), 0x2000);
+        assert_eq!(size(0x19, 0x00), 0);
+        assert_eq!(size(0x1B, 0x03), 0x8000);
+        // ROM only, with and without RAM.
+        assert_eq!(size(0x09, 0x02), 0x2000);
+        assert_eq!(size(0x00, 0x00), 0);
+    }
+}
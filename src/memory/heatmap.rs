@@ -0,0 +1,288 @@
+// Memory access heatmap.
+//
+// Counts reads, writes and opcode fetches for every address of the 64 KiB map.
+// The banked regions (ROM, VRAM, cartridge RAM and WRAM) are additionally
+// counted per bank, so that e.g. accesses to 4000-7FFF can be told apart by the
+// ROM bank that was mapped in at the time. Operand fetches and DMA reads count
+// as reads.
+//
+// Writes to the I/O registers are kept in a log together with the frame, LY and
+// dot at which they happened.
+
+use crate::memory::cdl::Access;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+const DEFAULT_IO_LOG_CAPACITY: usize = 0x10000;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub reads: u32,
+    pub writes: u32,
+    pub executes: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Region {
+    Rom,
+    Vram,
+    Sram,
+    Wram,
+}
+
+impl Region {
+    /// The size of a single bank.
+    pub fn bank_size(self) -> usize {
+        match self {
+            Region::Rom => 0x4000,
+            Region::Vram => 0x2000,
+            Region::Sram => 0x2000,
+            Region::Wram => 0x1000,
+        }
+    }
+}
+
+/// Where an access landed inside a banked region.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BankedAddr {
+    pub region: Region,
+    pub bank: u16,
+    pub offset: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IoWrite {
+    pub frame: u64,
+    pub ly: u8,
+    pub dot: u16,
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl fmt::Display for IoWrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {} LY {:3} dot {:3}: [{:04X}] <- {:02X}",
+            self.frame, self.ly, self.dot, self.addr, self.value
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct Heatmap {
+    counters: Vec<Counters>,
+    banked: HashMap<(Region, u16), Vec<Counters>>,
+    io_log: VecDeque<IoWrite>,
+    /// The maximum number of I/O writes kept, older writes are dropped first.
+    pub io_log_capacity: usize,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self {
+            counters: vec![Counters::default(); 0x10000],
+            banked: HashMap::new(),
+            io_log: VecDeque::new(),
+            io_log_capacity: DEFAULT_IO_LOG_CAPACITY,
+        }
+    }
+
+    pub fn counters(&self, addr: u16) -> Counters {
+        self.counters[addr as usize]
+    }
+
+    /// The counters of all 65536 addresses, indexed by address.
+    pub fn all_counters(&self) -> &[Counters] {
+        &self.counters
+    }
+
+    pub fn banked_counters(&self, region: Region, bank: u16) -> Option<&[Counters]> {
+        self.banked.get(&(region, bank)).map(|c| c.as_slice())
+    }
+
+    /// The banks of `region` that have been accessed, in ascending order.
+    pub fn banks(&self, region: Region) -> Vec<u16> {
+        let mut banks: Vec<u16> = self
+            .banked
+            .keys()
+            .filter(|(r, _)| *r == region)
+            .map(|(_, bank)| *bank)
+            .collect();
+        banks.sort_unstable();
+        banks
+    }
+
+    pub fn io_log(&self) -> impl Iterator<Item = &IoWrite> {
+        self.io_log.iter()
+    }
+
+    pub fn take_io_log(&mut self) -> Vec<IoWrite> {
+        self.io_log.drain(..).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.counters
+            .iter_mut()
+            .for_each(|c| *c = Counters::default());
+        self.banked.clear();
+        self.io_log.clear();
+    }
+
+    fn banked_mut(&mut self, banked: BankedAddr) -> &mut Counters {
+        let counters = self
+            .banked
+            .entry((banked.region, banked.bank))
+            .or_insert_with(|| vec![Counters::default(); banked.region.bank_size()]);
+        &mut counters[banked.offset as usize]
+    }
+
+    pub fn record_read(&mut self, addr: u16, banked: Option<BankedAddr>, access: Access) {
+        let update = |counters: &mut Counters| match access {
+            Access::Opcode => counters.executes = counters.executes.saturating_add(1),
+            _ => counters.reads = counters.reads.saturating_add(1),
+        };
+
+        update(&mut self.counters[addr as usize]);
+        if let Some(banked) = banked {
+            update(self.banked_mut(banked));
+        }
+    }
+
+    pub fn record_write(&mut self, addr: u16, banked: Option<BankedAddr>) {
+        let counters = &mut self.counters[addr as usize];
+        counters.writes = counters.writes.saturating_add(1);
+
+        if let Some(banked) = banked {
+            let counters = self.banked_mut(banked);
+            counters.writes = counters.writes.saturating_add(1);
+        }
+    }
+
+    pub fn record_io_write(&mut self, write: IoWrite) {
+        if self.io_log_capacity == 0 {
+            return;
+        }
+        if self.io_log.len() == self.io_log_capacity {
+            self.io_log.pop_front();
+        }
+        self.io_log.push_back(write);
+    }
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn test_banked_counters() {
+        let mut heatmap = Heatmap::new();
+        let bank = |bank| {
+            Some(BankedAddr {
+                region: Region::Rom,
+                bank,
+                offset: 0x10,
+            })
+        };
+
+        heatmap.record_read(0x4010, bank(1), Access::Opcode);
+        heatmap.record_read(0x4010, bank(2), Access::Data);
+        heatmap.record_read(0x4010, bank(2), Access::Dma);
+        heatmap.record_write(0x4010, bank(2));
+
+        assert_eq!(
+            heatmap.counters(0x4010),
+            Counters {
+                reads: 2,
+                writes: 1,
+                executes: 1
+            }
+        );
+        assert_eq!(heatmap.banks(Region::Rom), vec![1, 2]);
+        assert_eq!(
+            heatmap.banked_counters(Region::Rom, 2).unwrap()[0x10],
+            Counters {
+                reads: 2,
+                writes: 1,
+                executes: 0
+            }
+        );
+        assert!(heatmap.banked_counters(Region::Wram, 1).is_none());
+    }
+
+    #[test]
+    fn test_io_log_capacity() {
+        let mut heatmap = Heatmap::new();
+        heatmap.io_log_capacity = 2;
+
+        for value in 0..3 {
+            heatmap.record_io_write(IoWrite {
+                frame: 0,
+                ly: 0,
+                dot: 0,
+                addr: 0xFF42,
+                value,
+            });
+        }
+
+        let values: Vec<u8> = heatmap.io_log().map(|w| w.value).collect();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_mmu_instrumentation() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: LD A,$03; LD ($FF70),A; LD ($D000),A; LDH ($42),A; JR -2
+        rom[0x100..0x10C].copy_from_slice(&[
+            0x3E, 0x03, 0xEA, 0x70, 0xFF, 0xEA, 0x00, 0xD0, 0xE0, 0x42, 0x18, 0xFE,
+        ]);
+        // WRAM banking needs a CGB cartridge.
+        rom[0x143] = 0x80;
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.mmu.heatmap = Some(Heatmap::new());
+
+        for _ in 0..5 {
+            cpu.tick();
+        }
+
+        let heatmap = cpu.mmu.heatmap.as_ref().unwrap();
+        assert_eq!(heatmap.counters(0x100).executes, 1);
+        assert_eq!(heatmap.counters(0x101).reads, 1);
+        assert_eq!(heatmap.counters(0x10A).executes, 1);
+        assert_eq!(heatmap.counters(0xD000).writes, 1);
+        assert_eq!(heatmap.banks(Region::Wram), vec![3]);
+        assert_eq!(heatmap.banks(Region::Rom), vec![0]);
+
+        let writes: Vec<(u16, u8)> = heatmap.io_log().map(|w| (w.addr, w.value)).collect();
+        assert_eq!(writes, vec![(0xFF70, 0x03), (0xFF42, 0x03)]);
+    }
+
+    #[test]
+    fn test_interrupt_polling_is_not_counted() {
+        let mut rom = vec![0; 0x8000];
+        // 0100: NOP x4; HALT
+        rom[0x104] = 0x76;
+
+        let mut cpu = Cpu::new(rom);
+        cpu.pc = 0x100;
+        cpu.mmu.heatmap = Some(Heatmap::new());
+
+        for _ in 0..20 {
+            cpu.tick();
+        }
+
+        let heatmap = cpu.mmu.heatmap.as_ref().unwrap();
+        assert_eq!(heatmap.counters(0x104).executes, 1);
+        assert_eq!(heatmap.counters(0xFF0F).reads, 0);
+        assert_eq!(heatmap.counters(0xFFFF).reads, 0);
+    }
+}
@@ -1,10 +1,17 @@
+use crate::apu::queue::AudioQueue;
 use crate::apu::Apu;
 use crate::cartridge::Cartridge;
-use crate::cpu::{CgbMode, EmulationMode};
+use crate::cpu::{CgbMode, EmulationMode, Model};
+use crate::gpu::blend::FrameBlend;
 use crate::gpu::Gpu;
+use crate::infrared::Infrared;
 use crate::joypad::Joypad;
 use crate::memory::bootrom::Bootrom;
+use crate::memory::cdl::{Access, CodeDataLog};
+use crate::memory::heatmap::{BankedAddr, Heatmap, IoWrite, Region};
 use crate::memory::wram::Wram;
+use crate::serial::Serial;
+use crate::sgb::Sgb;
 use crate::timer::Timer;
 
 const HRAM_SIZE: usize = 0x007F;
@@ -12,6 +19,14 @@ const HRAM_OFFSET: u16 = 0xFF80;
 const WRAM_OFFSET: u16 = 0xC000;
 const ECHO_OFFSET: u16 = 0xE000;
 
+/// Audio waiting to be played and the LCD ghosting settings and buffers. They
+/// are output for the frontend rather than machine state.
+#[derive(Default)]
+pub struct Output {
+    samples: AudioQueue,
+    blend: FrameBlend,
+}
+
 #[derive(PartialEq)]
 pub enum AddrBus {
     Main,
@@ -20,6 +35,7 @@ pub enum AddrBus {
     Internal,
 }
 
+#[derive(Clone)]
 pub struct OamDma {
     pub active: bool,
     pub src_addr: u16,
@@ -40,19 +56,21 @@ impl Default for OamDma {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum HdmaType {
     NoHdma,
     HBlankDma,
     GPDma,
 }
 
+#[derive(Clone)]
 pub struct Hdma {
     pub hdma_type: HdmaType,
     pub new_hdma: bool,
     src: u16,
     dst: u16,
     blocks: u8,
+    block_progress: u8,
 }
 
 impl Default for Hdma {
@@ -63,10 +81,12 @@ impl Default for Hdma {
             src: 0,
             dst: 0,
             blocks: 0,
+            block_progress: 0,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Mmu {
     pub bootrom: Bootrom,
     pub cartridge: Cartridge,
@@ -77,34 +97,51 @@ pub struct Mmu {
     pub hdma: Hdma,
     pub oam_dma: OamDma,
     pub timer: Timer,
+    pub serial: Serial,
+    pub infrared: Infrared,
     wram: Wram,
     hram: [u8; HRAM_SIZE],
-    serial_out: u8,
     emu_mode: EmulationMode,
     pub cgb_mode: CgbMode,
-    request_serial_int: bool,
+    key0: u8,
     oam_dma_cycles: usize,
+    /// Records how ROM and cartridge RAM bytes are accessed while set.
+    pub cdl: Option<CodeDataLog>,
+    /// Counts accesses per address and logs I/O register writes while set.
+    pub heatmap: Option<Heatmap>,
+    /// The SNES side of a Super Game Boy.
+    pub sgb: Option<Sgb>,
 }
 
 impl Mmu {
-    pub fn new(data: Vec<u8>, emu_mode: EmulationMode) -> Self {
+    pub fn new(data: Vec<u8>, model: Model) -> Self {
+        let emu_mode = model.emu_mode();
+
         Mmu {
-            bootrom: Bootrom::new(),
+            bootrom: Bootrom::default(),
             cartridge: Cartridge::new(data),
             gpu: Gpu::new(emu_mode.clone()),
             joypad: Joypad::new(),
-            apu: Apu::new(emu_mode.clone()),
+            apu: Apu::new(model),
             ie: 0,
             hdma: Hdma::default(),
             oam_dma: OamDma::default(),
-            timer: Timer::new(emu_mode.clone()),
+            timer: Timer::new(model),
+            serial: Serial::new(emu_mode == EmulationMode::Cgb),
+            infrared: Infrared::new(),
             wram: Wram::new(),
             hram: [0; HRAM_SIZE],
-            serial_out: 0,
             emu_mode,
             cgb_mode: CgbMode::new(),
-            request_serial_int: false,
+            key0: 0,
             oam_dma_cycles: 0,
+            cdl: None,
+            heatmap: None,
+            sgb: if model.is_sgb() {
+                Some(Sgb::new())
+            } else {
+                None
+            },
         }
     }
 
@@ -127,37 +164,44 @@ impl Mmu {
         self.set_byte(0xFFFF, 0);
     }
 
-    pub fn gdma_tick(&mut self) {
-        self.hdma_transfer_block();
-
-        if self.hdma.blocks == 0 {
+    pub fn gdma_tick(&mut self, bytes: u16) {
+        if self.hdma_transfer(bytes) && self.hdma.blocks == 0 {
             self.hdma.hdma_type = HdmaType::NoHdma;
         }
     }
 
-    pub fn hdma_tick(&mut self) {
-        self.hdma_transfer_block();
+    pub fn hdma_tick(&mut self, bytes: u16) {
+        if self.hdma_transfer(bytes) {
+            if self.hdma.blocks == 0 {
+                self.hdma.hdma_type = HdmaType::NoHdma;
+            }
 
-        if self.hdma.blocks == 0 {
-            self.hdma.hdma_type = HdmaType::NoHdma;
+            self.gpu.hdma_flag = false;
         }
-
-        self.gpu.hdma_flag = false;
     }
 
-    fn hdma_transfer_block(&mut self) {
+    /// Copies the next `bytes` bytes of the current 16 byte block. Returns true once
+    /// the block is complete.
+    fn hdma_transfer(&mut self, bytes: u16) -> bool {
         if self.hdma.blocks == 0 {
-            return;
+            return true;
         }
 
-        for _ in 0..16 {
-            let value = self.get_byte(self.hdma.src);
+        for _ in 0..bytes {
+            let value = self.read(self.hdma.src, Access::Dma);
             self.set_byte(0x8000 | (self.hdma.dst & 0x1FFF), value);
             self.hdma.src += 1;
             self.hdma.dst += 1;
+            self.hdma.block_progress += 1;
         }
 
+        if self.hdma.block_progress < 16 {
+            return false;
+        }
+
+        self.hdma.block_progress = 0;
         self.hdma.blocks -= 1;
+        true
     }
 
     pub fn oam_dma_tick(&mut self, cycles: usize) {
@@ -182,10 +226,11 @@ impl Mmu {
             self.oam_dma_cycles -= 4;
 
             if self.oam_dma.src_addr < 0xE000 {
-                self.gpu.oam[self.oam_dma.i as usize] = self.get_byte(self.oam_dma.src_addr);
+                self.gpu.oam[self.oam_dma.i as usize] =
+                    self.read(self.oam_dma.src_addr, Access::Dma);
             } else {
                 self.gpu.oam[self.oam_dma.i as usize] =
-                    self.get_byte(self.oam_dma.src_addr & !0x2000);
+                    self.read(self.oam_dma.src_addr & !0x2000, Access::Dma);
             }
 
             self.oam_dma.i += 1;
@@ -197,30 +242,170 @@ impl Mmu {
         self.apu.tick(cycles);
     }
 
-    pub fn gpu_tick(&mut self, cycles: usize) {
+    /// Advances the PPU, returning whether it finished a frame. Finished
+    /// frames also go to the SGB, if there is one.
+    pub fn gpu_tick(&mut self, cycles: usize) -> bool {
+        let frame = self.gpu.frame();
         self.gpu.tick(cycles);
+        let finished = self.gpu.frame() != frame;
+        if finished {
+            self.sgb_frame();
+        }
+        finished
     }
 
     pub fn timer_tick(&mut self, cycles: usize) {
         self.timer.tick(cycles);
     }
 
+    pub fn serial_tick(&mut self, cycles: usize) {
+        self.serial.tick(cycles);
+    }
+
     pub fn screen(&self) -> *const u8 {
         self.gpu.screen()
     }
 
+    /// Hands a finished frame to the SGB, if there is one.
+    fn sgb_frame(&mut self) {
+        if let Some(sgb) = &mut self.sgb {
+            sgb.frame(self.gpu.shades());
+        }
+    }
+
+    /// Whether the cartridge header declares CGB support.
+    pub fn cgb_cartridge(&mut self) -> bool {
+        self.cartridge.get_byte(0x0143) & 0x80 != 0
+    }
+
+    /// The cartridge header, up to and including the global checksum.
+    pub fn header(&mut self) -> Vec<u8> {
+        (0..0x150)
+            .map(|addr| self.cartridge.get_byte(addr))
+            .collect()
+    }
+
+    /// Locks a CGB into DMG compatibility mode, hiding the CGB registers.
+    pub fn enter_compat_mode(&mut self) {
+        self.key0 = 0x04;
+        self.emu_mode = EmulationMode::Dmg;
+        self.gpu.enter_compat_mode();
+        self.serial.cgb = false;
+    }
+
+    /// IF, gathered from the interrupt requests of the components.
+    pub fn interrupt_flags(&self) -> u8 {
+        0xE0 | (self.joypad.request_joypad_int as u8) << 4
+            | (self.serial.request_serial_int as u8) << 3
+            | (self.timer.request_timer_int as u8) << 2
+            | (self.gpu.request_lcd_int as u8) << 1
+            | (self.gpu.request_vblank_int as u8)
+    }
+
+    pub fn set_interrupt_flags(&mut self, value: u8) {
+        self.gpu.request_vblank_int = (value & 0x01) != 0;
+        self.gpu.request_lcd_int = (value & 0x02) != 0;
+        self.timer.request_timer_int = (value & 0x04) != 0;
+        self.serial.request_serial_int = (value & 0x08) != 0;
+        self.joypad.request_joypad_int = (value & 0x10) != 0;
+    }
+
     pub fn get_byte(&mut self, addr: u16) -> u8 {
-        match addr {
-            // 0000-0100   256 byte Boot ROM
-            0x0000..=0x00FF => {
-                if self.bootrom.is_active {
-                    self.bootrom.get_byte(addr as usize)
-                } else {
-                    self.cartridge.get_byte(addr)
+        self.read(addr, Access::Data)
+    }
+
+    /// Reads `addr` and records the access in the code/data log and heatmap.
+    pub fn read(&mut self, addr: u16, access: Access) -> u8 {
+        if let Some(cdl) = &mut self.cdl {
+            match addr {
+                _ if self.bootrom.maps(addr) => (),
+                0x0000..=0x7FFF => cdl.mark_rom(self.cartridge.rom_offset(addr), access),
+                0xA000..=0xBFFF => {
+                    if let Some(offset) = self.cartridge.ram_offset(addr) {
+                        cdl.mark_ram(offset, access);
+                    }
                 }
+                _ => (),
+            }
+        }
+
+        if self.heatmap.is_some() {
+            let banked = self.banked_addr(addr);
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record_read(addr, banked, access);
+            }
+        }
+
+        self.peek(addr)
+    }
+
+    /// Replaces the output with `output`, returning the old one, so that
+    /// snapshots can leave it out.
+    pub fn swap_output(&mut self, output: Output) -> Output {
+        Output {
+            samples: self.apu.swap_samples(output.samples),
+            blend: self.gpu.swap_frame_blend(output.blend),
+        }
+    }
+
+    /// The bank `addr` is in, numbered like `.sym` files do. 0 for memory that
+    /// isn't banked, such as HRAM and the first WRAM bank.
+    pub fn bank(&self, addr: u16) -> u16 {
+        self.banked_addr(addr).map_or(0, |banked| banked.bank)
+    }
+
+    fn banked_addr(&self, addr: u16) -> Option<BankedAddr> {
+        let (region, bank, offset) = match addr {
+            _ if self.bootrom.maps(addr) => return None,
+            0x0000..=0x7FFF => (Region::Rom, self.cartridge.rom_bank(addr), addr & 0x3FFF),
+            0x8000..=0x9FFF => (Region::Vram, self.gpu.vram_bank() as u16, addr - 0x8000),
+            0xA000..=0xBFFF => {
+                let offset = self.cartridge.ram_offset(addr)?;
+                (
+                    Region::Sram,
+                    (offset / 0x2000) as u16,
+                    (offset % 0x2000) as u16,
+                )
             }
+            0xC000..=0xCFFF => (Region::Wram, 0, addr - 0xC000),
+            0xD000..=0xDFFF => (Region::Wram, self.wram.bank() as u16, addr - 0xD000),
+            0xE000..=0xFDFF => return self.banked_addr(addr - 0x2000),
+            _ => return None,
+        };
+
+        Some(BankedAddr {
+            region,
+            bank,
+            offset,
+        })
+    }
+
+    fn record_write(&mut self, addr: u16, value: u8) {
+        let banked = self.banked_addr(addr);
+        let write = IoWrite {
+            frame: self.gpu.frame(),
+            ly: self.gpu.ly(),
+            dot: self.gpu.dot() as u16,
+            addr,
+            value,
+        };
+
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.record_write(addr, banked);
+            if let 0xFF00..=0xFF7F = addr {
+                heatmap.record_io_write(write);
+            }
+        }
+    }
+
+    /// Reads `addr` without recording the access, for debuggers and loggers.
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            // 0000-00FF   256 byte Boot ROM
+            // 0200-08FF   2KB CGB Boot ROM
+            _ if self.bootrom.maps(addr) => self.bootrom.get_byte(addr as usize),
             // 0000-3FFF   16KB ROM Bank 0
-            0x0100..=0x7FFF => self.cartridge.get_byte(addr),
+            0x0000..=0x7FFF => self.cartridge.get_byte(addr),
             // 8000-9FFF   8KB Video RAM (VRAM)
             0x8000..=0x9FFF => self.gpu.get_byte(addr),
             // A000-BFFF   8KB External RAM
@@ -238,16 +423,9 @@ impl Mmu {
             // FF00-FF7F   I/O Ports
             0xFF00..=0xFF3F => match addr {
                 0xFF00 => self.joypad.get_byte(addr),
-                0xFF01 => self.serial_out,
-                0xFF02 => 0x7E,
+                0xFF01..=0xFF02 => self.serial.get_byte(addr),
                 0xFF04..=0xFF07 => self.timer.get_byte(addr),
-                0xFF0F => {
-                    0xE0 | (self.joypad.request_joypad_int as u8) << 4
-                        | (self.request_serial_int as u8) << 3
-                        | (self.timer.request_timer_int as u8) << 2
-                        | (self.gpu.request_lcd_int as u8) << 1
-                        | (self.gpu.request_vblank_int as u8)
-                }
+                0xFF0F => self.interrupt_flags(),
                 0xFF10..=0xFF1E => self.apu.get_byte(addr),
                 0xFF20..=0xFF26 => self.apu.get_byte(addr),
                 0xFF30..=0xFF3F => self.apu.get_byte(addr),
@@ -268,12 +446,18 @@ impl Mmu {
                 0xFF51..=0xFF54 => 0xFF,
                 0xFF55 => match self.emu_mode {
                     EmulationMode::Dmg => 0xFF,
+                    // The blocks left minus one, or FF once the transfer is done.
                     EmulationMode::Cgb => match self.hdma.hdma_type {
-                        HdmaType::GPDma => self.hdma.blocks,
-                        HdmaType::HBlankDma => self.hdma.blocks,
-                        HdmaType::NoHdma => 0x80,
+                        HdmaType::GPDma | HdmaType::HBlankDma => {
+                            self.hdma.blocks.wrapping_sub(1) & 0x7F
+                        }
+                        HdmaType::NoHdma => 0xFF,
                     },
                 },
+                0xFF56 => match self.emu_mode {
+                    EmulationMode::Dmg => 0xFF,
+                    EmulationMode::Cgb => self.infrared.get_byte(),
+                },
                 0xFF68..=0xFF6B => match self.emu_mode {
                     EmulationMode::Dmg => 0xFF,
                     EmulationMode::Cgb => self.gpu.get_byte(addr),
@@ -292,6 +476,10 @@ impl Mmu {
     }
 
     pub fn set_byte(&mut self, addr: u16, value: u8) {
+        if self.heatmap.is_some() {
+            self.record_write(addr, value);
+        }
+
         match addr {
             // 0000-3FFF   16KB ROM Bank 0
             0x0000..=0x7FFF => self.cartridge.set_byte(addr, value),
@@ -313,19 +501,19 @@ impl Mmu {
             0xFEA0..=0xFEFF => (),
             // FF00-FF7F   I/O Ports
             0xFF00..=0xFF3F => match addr {
-                0xFF00 => self.joypad.set_byte(addr, value),
-                0xFF01 => {
-                    println!("Serial out: {}", value as char);
-                    self.serial_out = value;
+                0xFF00 => {
+                    self.joypad.set_byte(addr, value);
+                    if let Some(sgb) = &mut self.sgb {
+                        let ends_read = sgb.write_joyp(value);
+                        self.joypad.set_players(sgb.players());
+                        if ends_read {
+                            self.joypad.next_player();
+                        }
+                    }
                 }
+                0xFF01..=0xFF02 => self.serial.set_byte(addr, value),
                 0xFF04..=0xFF07 => self.timer.set_byte(addr, value),
-                0xFF0F => {
-                    self.gpu.request_vblank_int = (value & 0x01) != 0;
-                    self.gpu.request_lcd_int = (value & 0x02) != 0;
-                    self.timer.request_timer_int = (value & 0x04) != 0;
-                    self.request_serial_int = (value & 0x08) != 0;
-                    self.joypad.request_joypad_int = (value & 0x10) != 0;
-                }
+                0xFF0F => self.set_interrupt_flags(value),
                 0xFF10..=0xFF1E => self.apu.set_byte(addr, value),
                 0xFF20..=0xFF26 => self.apu.set_byte(addr, value),
                 0xFF30..=0xFF3F => self.apu.set_byte(addr, value),
@@ -335,6 +523,10 @@ impl Mmu {
             0xFF46 => self.activate_oam_dma(value),
             0xFF47..=0xFF4B => self.gpu.set_byte(addr, value),
             0xFF4C..=0xFF4E => match addr {
+                // KEY0 can only be written by the boot ROM.
+                0xFF4C if self.bootrom.is_active && self.emu_mode == EmulationMode::Cgb => {
+                    self.key0 = value
+                }
                 0xFF4D if self.emu_mode == EmulationMode::Cgb => {
                     println!("Speed switch requested");
                     self.cgb_mode.prepare_speed_switch = value & 0x1;
@@ -347,8 +539,11 @@ impl Mmu {
                 }
             }
             0xFF50 => {
-                if self.bootrom.is_active && value == 1 {
+                if self.bootrom.is_active && value & 1 != 0 {
                     self.bootrom.deactivate();
+                    if self.emu_mode == EmulationMode::Cgb && self.key0 & 0x0C == 0x04 {
+                        self.enter_compat_mode();
+                    }
                 } else {
                     println!("Write to io ports {:#X}", addr);
                 }
@@ -374,8 +569,11 @@ impl Mmu {
                             HdmaType::HBlankDma
                         }
                     };
-                    self.hdma.blocks = value & 0x7F;
+                    // The length is written as blocks minus one.
+                    self.hdma.blocks = (value & 0x7F) + 1;
+                    self.hdma.block_progress = 0;
                 }
+                0xFF56 if self.emu_mode == EmulationMode::Cgb => self.infrared.set_byte(value),
                 0xFF68..=0xFF6B if self.emu_mode == EmulationMode::Cgb => {
                     self.gpu.set_byte(addr, value)
                 }
@@ -1,3 +1,6 @@
 pub mod bootrom;
+pub mod bus;
+pub mod cdl;
+pub mod heatmap;
 pub mod mmu;
 pub mod wram;
@@ -2,6 +2,7 @@ const WRAM_BANK_SIZE: usize = 0x1000;
 const WRAM_OFFSET: usize = 0xC000;
 const WRAM_BANK1_OFFSET: usize = 0xD000;
 
+#[derive(Clone)]
 pub struct Wram {
     wram: Vec<u8>,
     bank: usize,
@@ -15,6 +16,10 @@ impl Wram {
         }
     }
 
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
     pub fn get_byte(&mut self, addr: u16) -> u8 {
         match addr {
             0xC000..=0xCFFF => self.wram[addr as usize - WRAM_OFFSET],
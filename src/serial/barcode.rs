@@ -0,0 +1,123 @@
+// Barcode Boy.
+//
+// The game first makes sure the reader is there by sending 10 07 10 07, which
+// it answers with FF FF 10 07. From then on the reader is the one driving the
+// clock: every swiped card is sent as 02, the 13 digits of its JAN-13 code in
+// ASCII, and 03, while the game waits with an external clock.
+//
+// Reference: https://shonumi.github.io/dandocs.html
+
+use crate::serial::SerialDevice;
+use std::collections::VecDeque;
+
+const HANDSHAKE: [u8; 4] = [0x10, 0x07, 0x10, 0x07];
+const HANDSHAKE_REPLY: [u8; 4] = [0xFF, 0xFF, 0x10, 0x07];
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+pub const DIGITS: usize = 13;
+
+/// Clocks between two bytes sent by the reader.
+const BYTE_CLOCKS: usize = 4096;
+
+#[derive(Clone, Default)]
+pub struct BarcodeBoy {
+    handshake: usize,
+    ready: bool,
+    queue: VecDeque<u8>,
+    clock: usize,
+}
+
+impl BarcodeBoy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the game has found the reader.
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Swipes a card with the 13 digit barcode `code`.
+    pub fn scan(&mut self, code: &str) -> Result<(), String> {
+        if code.len() != DIGITS || !code.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(format!(
+                "invalid barcode {:?}, expected {} digits",
+                code, DIGITS
+            ));
+        }
+
+        self.queue.push_back(STX);
+        self.queue.extend(code.bytes());
+        self.queue.push_back(ETX);
+        Ok(())
+    }
+}
+
+impl SerialDevice for BarcodeBoy {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        if byte != HANDSHAKE[self.handshake] {
+            self.handshake = 0;
+            return 0xFF;
+        }
+
+        let reply = HANDSHAKE_REPLY[self.handshake];
+        self.handshake += 1;
+        if self.handshake == HANDSHAKE.len() {
+            self.handshake = 0;
+            self.ready = true;
+        }
+        reply
+    }
+
+    fn external_clock(&mut self, _byte: u8, cycles: usize) -> Option<u8> {
+        if !self.ready || self.queue.is_empty() {
+            self.clock = 0;
+            return None;
+        }
+
+        self.clock += cycles;
+        if self.clock < BYTE_CLOCKS {
+            return None;
+        }
+        self.clock = 0;
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_and_scan() {
+        let mut reader = BarcodeBoy::new();
+        reader.scan("4902370501234").unwrap();
+
+        // Nothing is sent before the game found the reader.
+        assert_eq!(reader.external_clock(0x00, BYTE_CLOCKS * 2), None);
+
+        let replies: Vec<_> = HANDSHAKE.iter().map(|&b| reader.exchange(b)).collect();
+        assert_eq!(replies, HANDSHAKE_REPLY);
+        assert!(reader.ready());
+
+        let mut received = vec![];
+        for _ in 0..100 {
+            if let Some(byte) = reader.external_clock(0x00, BYTE_CLOCKS / 2) {
+                received.push(byte);
+            }
+        }
+        assert_eq!(received, b"\x024902370501234\x03");
+    }
+
+    #[test]
+    fn test_bad_handshake_and_barcode() {
+        let mut reader = BarcodeBoy::new();
+        assert_eq!(reader.exchange(0x10), 0xFF);
+        assert_eq!(reader.exchange(0x55), 0xFF);
+        assert_eq!(reader.exchange(0x10), 0xFF);
+        assert!(!reader.ready());
+
+        assert!(reader.scan("123").is_err());
+        assert!(reader.scan("49023705O1234").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+// Link cable between two emulators in the same process.
+//
+// Whichever side starts a transfer on the internal clock is the master. Its
+// byte is swapped with the one the other side has waiting in SB for an
+// external clock, and the other side's transfer completes when the master's
+// does. Either side can be the master for any transfer, as games like Pokémon
+// negotiate who drives the clock.
+//
+// Both cores have to advance together for that to land on the right cycle, so
+// `Lockstep` runs them in turns of `SLICE` clocks, which bounds how far one can
+// get ahead of the other.
+
+use crate::cpu::Cpu;
+use crate::events::Event;
+use crate::serial::SerialDevice;
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+/// The most clocks one side runs ahead of the other, not counting the
+/// instruction that crosses the end of a slice.
+pub const SLICE: usize = 64;
+
+#[derive(Default)]
+struct End {
+    /// SB while this side waits for the other one to clock a transfer.
+    waiting: Option<u8>,
+    /// The byte clocked in by the other side.
+    incoming: Option<u8>,
+    /// Whether the other side finished shifting `incoming`.
+    done: bool,
+}
+
+#[derive(Default)]
+struct Wire {
+    ends: [End; 2],
+}
+
+/// One end of a link cable.
+pub struct LinkPort {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl LinkPort {
+    /// Makes a cable and returns its two ends.
+    pub fn pair() -> (Rc<RefCell<LinkPort>>, Rc<RefCell<LinkPort>>) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let end = |side| {
+            Rc::new(RefCell::new(LinkPort {
+                wire: wire.clone(),
+                side,
+            }))
+        };
+
+        (end(0), end(1))
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = &mut wire.ends[1 - self.side];
+
+        match other.waiting.take() {
+            Some(theirs) => {
+                other.incoming = Some(byte);
+                other.done = false;
+                theirs
+            }
+            // Nobody is listening, so the line stays high.
+            None => 0xFF,
+        }
+    }
+
+    fn transfer_done(&mut self) {
+        self.wire.borrow_mut().ends[1 - self.side].done = true;
+    }
+
+    fn external_clock(&mut self, byte: u8, _cycles: usize) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let end = &mut wire.ends[self.side];
+
+        if end.incoming.is_none() {
+            end.waiting = Some(byte);
+            return None;
+        }
+        if !end.done {
+            return None;
+        }
+
+        end.done = false;
+        end.incoming.take()
+    }
+}
+
+/// Connects the serial ports of two cores.
+pub fn connect(a: &mut Cpu, b: &mut Cpu) {
+    let (port_a, port_b) = LinkPort::pair();
+    a.mmu.serial.connect(port_a);
+    b.mmu.serial.connect(port_b);
+}
+
+/// Where in a slice a `Slices` hook is called.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SliceEdge {
+    Start,
+    End,
+}
+
+/// Runs a core in slices of `SLICE` clocks, for keeping it in step with
+/// another one.
+#[derive(Clone, Default)]
+pub struct Slices {
+    /// Clocks run since the last `Event::MaxCycles`.
+    run: usize,
+}
+
+impl Slices {
+    /// Like `Cpu::run_till_event`, calling `on_edge` at the start and the end
+    /// of every slice. An event in the middle of a slice is returned right
+    /// away; the next call finishes the slice.
+    pub fn run_till_event<E>(
+        &mut self,
+        cpu: &mut Cpu,
+        max_cycles: usize,
+        mut on_edge: impl FnMut(SliceEdge) -> Result<(), E>,
+    ) -> Result<Event, E> {
+        while self.run < max_cycles {
+            on_edge(SliceEdge::Start)?;
+            match cpu.run_till_event(SLICE) {
+                Event::MaxCycles => (),
+                event => return Ok(event),
+            }
+            self.run += SLICE;
+            on_edge(SliceEdge::End)?;
+        }
+
+        self.run -= max_cycles;
+        Ok(Event::MaxCycles)
+    }
+}
+
+/// Runs two linked cores in lockstep.
+#[derive(Clone, Default)]
+pub struct Lockstep {
+    slices: Slices,
+}
+
+impl Lockstep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `Cpu::run_till_event` for `a`, with `b` kept within a slice of it.
+    /// Events of `b` are passed to `on_b_event`.
+    pub fn run_till_event(
+        &mut self,
+        a: &mut Cpu,
+        b: &mut Cpu,
+        max_cycles: usize,
+        mut on_b_event: impl FnMut(&mut Cpu, Event),
+    ) -> Event {
+        let result = self.slices.run_till_event(a, max_cycles, |edge| {
+            if edge == SliceEdge::End {
+                loop {
+                    match b.run_till_event(SLICE) {
+                        Event::MaxCycles => break,
+                        event => on_b_event(b, event),
+                    }
+                }
+            }
+            Ok::<(), Infallible>(())
+        });
+        result.unwrap_or_else(|never| match never {})
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn cpu(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+        let mut cpu = Cpu::new(rom);
+        cpu.simulate_bootrom();
+        cpu.pc = 0x100;
+        cpu
+    }
+
+    /// LD A,`sb`; LDH ($01),A; LD A,`sc`; LDH ($02),A; JR -2, after `delay`
+    /// NOPs.
+    pub fn transfer(delay: usize, sb: u8, sc: u8) -> Cpu {
+        let mut program = vec![0x00; delay];
+        program.extend_from_slice(&[0x3E, sb, 0xE0, 0x01, 0x3E, sc, 0xE0, 0x02, 0x18, 0xFE]);
+        cpu(&program)
+    }
+
+    fn run(a: &mut Cpu, b: &mut Cpu, cycles: usize) {
+        let mut lockstep = Lockstep::new();
+        while !matches!(
+            lockstep.run_till_event(a, b, cycles, |_, _| ()),
+            Event::MaxCycles
+        ) {}
+    }
+
+    #[test]
+    fn test_exchange() {
+        let mut master = transfer(16, 0x12, 0x81);
+        let mut slave = transfer(0, 0x34, 0x80);
+        connect(&mut master, &mut slave);
+        for cpu in [&mut master, &mut slave] {
+            cpu.mmu.set_byte(0xFF0F, 0);
+        }
+
+        // Not done until 8 bits at 8192 Hz have been shifted.
+        run(&mut master, &mut slave, 3000);
+        assert_eq!(slave.mmu.get_byte(0xFF0F) & 0x08, 0);
+        assert_eq!(slave.mmu.get_byte(0xFF02), 0xFE);
+
+        run(&mut master, &mut slave, 2000);
+        for (cpu, received) in [(&mut master, 0x34), (&mut slave, 0x12)] {
+            assert_eq!(cpu.mmu.get_byte(0xFF01), received);
+            assert_eq!(cpu.mmu.get_byte(0xFF0F) & 0x08, 0x08);
+            assert_eq!(cpu.mmu.get_byte(0xFF02) & 0x80, 0);
+        }
+    }
+
+    #[test]
+    fn test_nobody_listening() {
+        let mut master = transfer(0, 0x12, 0x81);
+        let mut other = cpu(&[0x18, 0xFE]);
+        connect(&mut master, &mut other);
+
+        run(&mut master, &mut other, 5000);
+        assert_eq!(master.mmu.get_byte(0xFF01), 0xFF);
+        assert_eq!(other.mmu.get_byte(0xFF0F) & 0x08, 0);
+    }
+
+    #[test]
+    fn test_skew() {
+        let mut a = cpu(&[0x18, 0xFE]);
+        let mut b = cpu(&[0x18, 0xFE]);
+        let mut lockstep = Lockstep::new();
+
+        for _ in 0..100 {
+            lockstep.run_till_event(&mut a, &mut b, 1000, |_, _| ());
+            let skew = a.total_cycles.abs_diff(b.total_cycles);
+            assert!(skew <= SLICE as u64 + 12, "skew of {} clocks", skew);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+// Mobile Adapter GB.
+//
+// The adapter connects a Game Boy to a mobile phone and through it to the
+// internet. The Game Boy always drives the clock. It sends packets of the magic
+// bytes 99 66, a header of the command, two zero bytes and the data length,
+// the data and a 16 bit big endian checksum of header and data, followed by
+// its device ID 81 and a zero byte. The adapter answers its own ID 88 and the
+// command with bit 7 set, then sends its reply packet the same way while the
+// Game Boy clocks idle bytes. Idle bytes are 4B from the Game Boy and D2 from
+// the adapter.
+//
+// This is a stand-in for the real network: everything the game asks for is
+// answered by a `MobileServer`, so the code paths can be tested offline.
+//
+// Reference: https://shonumi.github.io/dandocs.html
+
+use crate::serial::SerialDevice;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+const MAGIC: [u8; 2] = [0x99, 0x66];
+const GB_ID: u8 = 0x81;
+const ADAPTER_ID: u8 = 0x88;
+const ADAPTER_IDLE: u8 = 0xD2;
+/// Sent in place of the acknowledgement when the checksum is wrong.
+const CHECKSUM_ERROR: u8 = 0xF1;
+const CONFIG_SIZE: usize = 192;
+
+const CMD_BEGIN_SESSION: u8 = 0x10;
+const CMD_END_SESSION: u8 = 0x11;
+const CMD_DIAL: u8 = 0x12;
+const CMD_HANG_UP: u8 = 0x13;
+const CMD_TRANSFER: u8 = 0x15;
+const CMD_TELEPHONE_STATUS: u8 = 0x17;
+const CMD_READ_CONFIG: u8 = 0x19;
+const CMD_WRITE_CONFIG: u8 = 0x1A;
+const CMD_ISP_LOGIN: u8 = 0x21;
+const CMD_ISP_LOGOUT: u8 = 0x22;
+const CMD_OPEN_TCP: u8 = 0x23;
+const CMD_CLOSE_TCP: u8 = 0x24;
+const CMD_DNS_QUERY: u8 = 0x28;
+const CMD_ERROR: u8 = 0x6E;
+
+/// What the adapter connects to.
+pub trait MobileServer {
+    /// The address the ISP hands out on login.
+    fn address(&self) -> [u8; 4];
+    fn resolve(&mut self, name: &str) -> Option<[u8; 4]>;
+    /// Opens a TCP connection, returning false if nothing listens there.
+    fn open(&mut self, ip: [u8; 4], port: u16) -> bool;
+    /// Sends `data` over the open connection and returns what came back.
+    fn transfer(&mut self, data: &[u8]) -> Vec<u8>;
+    fn close(&mut self);
+}
+
+/// A fake network with a fixed set of hosts, which answers transfers with
+/// canned responses in order and keeps what it was sent.
+#[derive(Default)]
+pub struct LocalServer {
+    hosts: HashMap<String, [u8; 4]>,
+    responses: VecDeque<Vec<u8>>,
+    received: VecDeque<Vec<u8>>,
+    connected: Option<([u8; 4], u16)>,
+}
+
+impl LocalServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_host(&mut self, name: &str, ip: [u8; 4]) {
+        self.hosts.insert(name.to_string(), ip);
+    }
+
+    pub fn queue_response(&mut self, data: &[u8]) {
+        self.responses.push_back(data.to_vec());
+    }
+
+    /// Takes the oldest transfer the game sent, if any.
+    pub fn take_request(&mut self) -> Option<Vec<u8>> {
+        self.received.pop_front()
+    }
+}
+
+impl MobileServer for LocalServer {
+    fn address(&self) -> [u8; 4] {
+        [10, 0, 0, 2]
+    }
+
+    fn resolve(&mut self, name: &str) -> Option<[u8; 4]> {
+        self.hosts.get(name).copied()
+    }
+
+    fn open(&mut self, ip: [u8; 4], port: u16) -> bool {
+        let known = self.hosts.values().any(|&host| host == ip);
+        if known {
+            self.connected = Some((ip, port));
+        }
+        known
+    }
+
+    fn transfer(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.connected.is_none() {
+            return vec![];
+        }
+        self.received.push_back(data.to_vec());
+        self.responses.pop_front().unwrap_or_default()
+    }
+
+    fn close(&mut self) {
+        self.connected = None;
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Magic1,
+    Magic2,
+    Header,
+    Data,
+    Checksum,
+    DeviceId,
+    Acknowledge,
+    Replying,
+}
+
+pub struct MobileAdapter {
+    /// Shared so the caller can keep feeding and inspecting the server while
+    /// the adapter is plugged in.
+    server: Rc<RefCell<dyn MobileServer>>,
+    state: State,
+    packet: Vec<u8>,
+    checksum: Vec<u8>,
+    reply: VecDeque<u8>,
+    session: bool,
+    in_call: bool,
+    logged_in: bool,
+    connection: Option<u8>,
+    next_connection: u8,
+    config: [u8; CONFIG_SIZE],
+}
+
+impl MobileAdapter {
+    pub fn new(server: Rc<RefCell<dyn MobileServer>>) -> Self {
+        Self {
+            server,
+            state: State::Magic1,
+            packet: vec![],
+            checksum: vec![],
+            reply: VecDeque::new(),
+            session: false,
+            in_call: false,
+            logged_in: false,
+            connection: None,
+            next_connection: 0,
+            config: [0; CONFIG_SIZE],
+        }
+    }
+
+    fn data_length(&self) -> usize {
+        self.packet[3] as usize
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = ADAPTER_IDLE;
+
+        self.state = match self.state {
+            State::Magic1 if byte == MAGIC[0] => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == MAGIC[1] => {
+                self.packet.clear();
+                self.checksum.clear();
+                State::Header
+            }
+            State::Magic2 => State::Magic1,
+            State::Header => {
+                self.packet.push(byte);
+                match self.packet.len() {
+                    4 if self.data_length() == 0 => State::Checksum,
+                    4 => State::Data,
+                    _ => State::Header,
+                }
+            }
+            State::Data => {
+                self.packet.push(byte);
+                match self.packet.len() - 4 {
+                    len if len == self.data_length() => State::Checksum,
+                    _ => State::Data,
+                }
+            }
+            State::Checksum => {
+                self.checksum.push(byte);
+                match self.checksum.len() {
+                    2 => State::DeviceId,
+                    _ => State::Checksum,
+                }
+            }
+            State::DeviceId if byte == GB_ID => {
+                reply = ADAPTER_ID;
+                State::Acknowledge
+            }
+            State::DeviceId => State::Magic1,
+            State::Acknowledge => {
+                let sum = checksum(&self.packet);
+                if sum.to_be_bytes()[..] != self.checksum[..] {
+                    reply = CHECKSUM_ERROR;
+                    State::Magic1
+                } else {
+                    let command = self.packet[0];
+                    reply = command | 0x80;
+                    let data = self.packet[4..].to_vec();
+                    let (command, data) = self.execute(command, &data);
+                    self.queue_reply(command, &data);
+                    State::Replying
+                }
+            }
+            State::Replying => match self.reply.pop_front() {
+                Some(byte) if self.reply.is_empty() => {
+                    reply = byte;
+                    State::Magic1
+                }
+                Some(byte) => {
+                    reply = byte;
+                    State::Replying
+                }
+                None => State::Magic1,
+            },
+        };
+
+        reply
+    }
+
+    fn queue_reply(&mut self, command: u8, data: &[u8]) {
+        let mut packet = vec![command | 0x80, 0x00, 0x00, data.len() as u8];
+        packet.extend_from_slice(data);
+        let sum = checksum(&packet);
+
+        self.reply.extend(MAGIC);
+        self.reply.extend(packet);
+        self.reply.extend(sum.to_be_bytes());
+        self.reply.extend([ADAPTER_ID, 0x00]);
+    }
+
+    /// Runs a command and returns the command and data of the reply.
+    fn execute(&mut self, command: u8, data: &[u8]) -> (u8, Vec<u8>) {
+        let error = |code: u8| (CMD_ERROR, vec![command, code]);
+
+        if !self.session && command != CMD_BEGIN_SESSION {
+            return error(0x01);
+        }
+
+        match command {
+            CMD_BEGIN_SESSION if data == b"NINTENDO" => {
+                self.session = true;
+                (command, data.to_vec())
+            }
+            CMD_BEGIN_SESSION => error(0x01),
+            CMD_END_SESSION => {
+                self.close();
+                self.in_call = false;
+                self.logged_in = false;
+                self.session = false;
+                (command, vec![])
+            }
+            CMD_DIAL => {
+                self.in_call = true;
+                (command, vec![])
+            }
+            CMD_HANG_UP => {
+                self.close();
+                self.in_call = false;
+                self.logged_in = false;
+                (command, vec![])
+            }
+            CMD_TELEPHONE_STATUS => (command, vec![if self.in_call { 0x04 } else { 0x00 }]),
+            CMD_READ_CONFIG => match data {
+                &[offset, length] if offset as usize + length as usize <= CONFIG_SIZE => {
+                    let range = offset as usize..offset as usize + length as usize;
+                    let mut reply = vec![offset];
+                    reply.extend_from_slice(&self.config[range]);
+                    (command, reply)
+                }
+                _ => error(0x02),
+            },
+            CMD_WRITE_CONFIG => match data {
+                [offset, bytes @ ..] if *offset as usize + bytes.len() <= CONFIG_SIZE => {
+                    let start = *offset as usize;
+                    self.config[start..start + bytes.len()].copy_from_slice(bytes);
+                    (command, vec![*offset, bytes.len() as u8])
+                }
+                _ => error(0x02),
+            },
+            CMD_ISP_LOGIN if self.in_call => {
+                self.logged_in = true;
+                let mut reply = self.server.borrow().address().to_vec();
+                // Primary and secondary DNS.
+                reply.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 1]);
+                (command, reply)
+            }
+            CMD_ISP_LOGIN => error(0x01),
+            CMD_ISP_LOGOUT => {
+                self.close();
+                self.logged_in = false;
+                (command, vec![])
+            }
+            CMD_OPEN_TCP => match data {
+                &[a, b, c, d, port_high, port_low] if self.logged_in => {
+                    let port = u16::from_be_bytes([port_high, port_low]);
+                    if !self.server.borrow_mut().open([a, b, c, d], port) {
+                        return error(0x03);
+                    }
+                    let id = self.next_connection;
+                    self.next_connection = self.next_connection.wrapping_add(1);
+                    self.connection = Some(id);
+                    (command, vec![id])
+                }
+                _ => error(0x01),
+            },
+            CMD_CLOSE_TCP => match data {
+                &[id] if self.connection == Some(id) => {
+                    self.close();
+                    (command, vec![id])
+                }
+                _ => error(0x01),
+            },
+            CMD_TRANSFER => match data {
+                [id, payload @ ..] if self.connection == Some(*id) => {
+                    let mut reply = vec![*id];
+                    let response = self.server.borrow_mut().transfer(payload);
+                    // The reply length is a single byte.
+                    reply.extend(response.into_iter().take(0xFF - 1));
+                    (command, reply)
+                }
+                _ => error(0x01),
+            },
+            CMD_DNS_QUERY if self.logged_in => {
+                let name = String::from_utf8_lossy(data);
+                match self.server.borrow_mut().resolve(&name) {
+                    Some(ip) => (command, ip.to_vec()),
+                    None => error(0x03),
+                }
+            }
+            _ => error(0x00),
+        }
+    }
+
+    fn close(&mut self) {
+        if self.connection.take().is_some() {
+            self.server.borrow_mut().close();
+        }
+    }
+}
+
+impl SerialDevice for MobileAdapter {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB_IDLE: u8 = 0x4B;
+
+    /// Sends a packet like the Game Boy does and returns the adapter's
+    /// acknowledgement and reply packet.
+    fn send(adapter: &mut MobileAdapter, command: u8, data: &[u8]) -> (u8, Option<(u8, Vec<u8>)>) {
+        let mut packet = vec![command, 0x00, 0x00, data.len() as u8];
+        packet.extend_from_slice(data);
+        let sum = checksum(&packet);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(packet);
+        bytes.extend(sum.to_be_bytes());
+        for byte in bytes {
+            assert_eq!(adapter.exchange(byte), ADAPTER_IDLE);
+        }
+        assert_eq!(adapter.exchange(GB_ID), ADAPTER_ID);
+        let ack = adapter.exchange(0x00);
+        if ack == CHECKSUM_ERROR {
+            return (ack, None);
+        }
+
+        let mut reply = vec![];
+        while adapter.state == State::Replying {
+            reply.push(adapter.exchange(GB_IDLE));
+        }
+        assert_eq!(reply[..2], MAGIC);
+        let length = reply[5] as usize;
+        assert_eq!(reply.len(), 2 + 4 + length + 2 + 2);
+        let sum = checksum(&reply[2..6 + length]);
+        assert_eq!(reply[6 + length..8 + length], sum.to_be_bytes());
+        assert_eq!(reply[8 + length..], [ADAPTER_ID, 0x00]);
+
+        (ack, Some((reply[2], reply[6..6 + length].to_vec())))
+    }
+
+    fn adapter() -> (MobileAdapter, Rc<RefCell<LocalServer>>) {
+        let mut server = LocalServer::new();
+        server.add_host("gameboy.datacenter.ne.jp", [192, 168, 0, 7]);
+        server.queue_response(b"+OK hello");
+        let server = Rc::new(RefCell::new(server));
+        (MobileAdapter::new(server.clone()), server)
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut adapter, server) = adapter();
+
+        // Nothing works before the session starts.
+        let (_, reply) = send(&mut adapter, CMD_DIAL, b"0755311973");
+        assert_eq!(reply, Some((CMD_ERROR | 0x80, vec![CMD_DIAL, 0x01])));
+
+        let (ack, reply) = send(&mut adapter, CMD_BEGIN_SESSION, b"NINTENDO");
+        assert_eq!(ack, CMD_BEGIN_SESSION | 0x80);
+        assert_eq!(reply, Some((0x90, b"NINTENDO".to_vec())));
+
+        send(&mut adapter, CMD_DIAL, b"0755311973");
+        let (_, reply) = send(&mut adapter, CMD_TELEPHONE_STATUS, &[]);
+        assert_eq!(reply, Some((0x97, vec![0x04])));
+
+        let (_, reply) = send(&mut adapter, CMD_ISP_LOGIN, b"user\0pass");
+        assert_eq!(reply.unwrap().1[..4], [10, 0, 0, 2]);
+
+        let (_, reply) = send(&mut adapter, CMD_DNS_QUERY, b"gameboy.datacenter.ne.jp");
+        assert_eq!(reply, Some((0xA8, vec![192, 168, 0, 7])));
+        let (_, reply) = send(&mut adapter, CMD_DNS_QUERY, b"example.com");
+        assert_eq!(reply, Some((0xEE, vec![CMD_DNS_QUERY, 0x03])));
+
+        let (_, reply) = send(&mut adapter, CMD_OPEN_TCP, &[192, 168, 0, 7, 0x00, 110]);
+        let id = reply.unwrap().1[0];
+        let mut data = vec![id];
+        data.extend_from_slice(b"USER g\r\n");
+        let (_, reply) = send(&mut adapter, CMD_TRANSFER, &data);
+        let mut expected = vec![id];
+        expected.extend_from_slice(b"+OK hello");
+        assert_eq!(reply, Some((0x95, expected)));
+        assert_eq!(
+            server.borrow_mut().take_request(),
+            Some(b"USER g\r\n".to_vec())
+        );
+        assert_eq!(server.borrow_mut().take_request(), None);
+
+        send(&mut adapter, CMD_CLOSE_TCP, &[id]);
+        let (_, reply) = send(&mut adapter, CMD_TRANSFER, &data);
+        assert_eq!(reply.unwrap().0, 0xEE);
+
+        send(&mut adapter, CMD_END_SESSION, &[]);
+        let (_, reply) = send(&mut adapter, CMD_TELEPHONE_STATUS, &[]);
+        assert_eq!(reply.unwrap().0, 0xEE);
+    }
+
+    #[test]
+    fn test_config() {
+        let (mut adapter, _) = adapter();
+        send(&mut adapter, CMD_BEGIN_SESSION, b"NINTENDO");
+
+        let (_, reply) = send(&mut adapter, CMD_WRITE_CONFIG, &[0x10, 0xAA, 0xBB]);
+        assert_eq!(reply, Some((0x9A, vec![0x10, 2])));
+        let (_, reply) = send(&mut adapter, CMD_READ_CONFIG, &[0x0F, 4]);
+        assert_eq!(reply, Some((0x99, vec![0x0F, 0x00, 0xAA, 0xBB, 0x00])));
+
+        let (_, reply) = send(&mut adapter, CMD_READ_CONFIG, &[0xC0, 1]);
+        assert_eq!(reply.unwrap().0, 0xEE);
+    }
+
+    #[test]
+    fn test_checksum_error() {
+        let (mut adapter, _) = adapter();
+        for byte in [0x99, 0x66, CMD_BEGIN_SESSION, 0x00, 0x00, 0x00, 0x12, 0x34] {
+            adapter.exchange(byte);
+        }
+        assert_eq!(adapter.exchange(GB_ID), ADAPTER_ID);
+        assert_eq!(adapter.exchange(0x00), CHECKSUM_ERROR);
+        assert_eq!(adapter.exchange(GB_IDLE), ADAPTER_IDLE);
+    }
+}
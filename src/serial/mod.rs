@@ -0,0 +1,286 @@
+// Serial port.
+//
+// A transfer shifts the 8 bits of SB out, most significant bit first, while
+// shifting in the bits of whatever is connected to the link port. The side that
+// drives the clock sets the speed: on the internal clock one bit is shifted at
+// 8192 Hz, or at 262144 Hz with the CGB's fast clock, and both double along
+// with the CPU in double speed mode. On the external clock the transfer waits
+// until the other side clocks it. Either way the serial interrupt is requested
+// once all 8 bits have been shifted.
+//
+// Reference: https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+
+pub mod barcode;
+pub mod link;
+pub mod mobile;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod net;
+pub mod printer;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const SB: u16 = 0xFF01;
+const SC: u16 = 0xFF02;
+
+/// Clocks per bit for the normal and fast internal clock.
+const BIT_CLOCKS: usize = 512;
+const FAST_BIT_CLOCKS: usize = 16;
+
+/// Something plugged into the link port.
+pub trait SerialDevice {
+    /// The Game Boy, driving the clock, started sending `byte`. Returns the
+    /// byte the device sends back at the same time.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// The transfer started by the last `exchange` shifted its last bit.
+    fn transfer_done(&mut self) {}
+
+    /// Called while the Game Boy waits with `byte` in SB for a transfer on the
+    /// external clock, `cycles` clocks after the last call. Devices that drive
+    /// the clock return the byte they sent to complete the transfer.
+    fn external_clock(&mut self, _byte: u8, _cycles: usize) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct Serial {
+    pub request_serial_int: bool,
+    /// Whether SC has the CGB's clock speed bit.
+    pub cgb: bool,
+    sb: u8,
+    sc: u8,
+    /// The byte being shifted in by the current transfer.
+    incoming: u8,
+    bits: u8,
+    clock: usize,
+    /// Shared so that both ends of a cable can hold on to it. Snapshots share
+    /// the device rather than copying its state, which is why the history
+    /// can't go back over steps during which a device was plugged in.
+    device: Option<Rc<RefCell<dyn SerialDevice>>>,
+}
+
+impl Serial {
+    pub fn new(cgb: bool) -> Self {
+        Self {
+            request_serial_int: false,
+            cgb,
+            sb: 0,
+            sc: 0,
+            incoming: 0xFF,
+            bits: 0,
+            clock: 0,
+            device: None,
+        }
+    }
+
+    pub fn connect(&mut self, device: Rc<RefCell<dyn SerialDevice>>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.device = None;
+    }
+
+    pub fn connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    fn bit_clocks(&self) -> usize {
+        if self.cgb && self.sc & 0x02 != 0 {
+            FAST_BIT_CLOCKS
+        } else {
+            BIT_CLOCKS
+        }
+    }
+
+    /// Advances the serial clock by `cycles` CPU clocks.
+    pub fn tick(&mut self, cycles: usize) {
+        if !self.transferring() {
+            return;
+        }
+
+        if !self.internal_clock() {
+            let sent = self
+                .device
+                .as_ref()
+                .and_then(|device| device.borrow_mut().external_clock(self.sb, cycles));
+            if let Some(byte) = sent {
+                self.sb = byte;
+                self.finish();
+            }
+            return;
+        }
+
+        self.clock += cycles;
+        while self.transferring() && self.clock >= self.bit_clocks() {
+            self.clock -= self.bit_clocks();
+            self.shift();
+        }
+    }
+
+    fn shift(&mut self) {
+        let bit = (self.incoming >> (7 - self.bits)) & 1;
+        self.sb = self.sb << 1 | bit;
+        self.bits += 1;
+
+        if self.bits == 8 {
+            self.finish();
+            if let Some(device) = &self.device {
+                device.borrow_mut().transfer_done();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.sc &= 0x7F;
+        self.bits = 0;
+        self.request_serial_int = true;
+    }
+
+    fn start(&mut self) {
+        self.bits = 0;
+        self.clock = 0;
+
+        if self.internal_clock() {
+            // With nothing connected the input line is pulled high.
+            self.incoming = match &self.device {
+                Some(device) => device.borrow_mut().exchange(self.sb),
+                None => 0xFF,
+            };
+        }
+    }
+
+    pub fn get_byte(&self, addr: u16) -> u8 {
+        match addr {
+            SB => self.sb,
+            SC if self.cgb => 0x7C | self.sc,
+            SC => 0x7E | self.sc,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn set_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            SB => self.sb = value,
+            SC => {
+                self.sc = value & if self.cgb { 0x83 } else { 0x81 };
+                if self.transferring() {
+                    self.start();
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo {
+        received: Vec<u8>,
+        reply: u8,
+        ready: bool,
+    }
+
+    impl SerialDevice for Echo {
+        fn exchange(&mut self, byte: u8) -> u8 {
+            self.received.push(byte);
+            self.reply
+        }
+
+        fn external_clock(&mut self, byte: u8, _cycles: usize) -> Option<u8> {
+            if self.ready {
+                self.ready = false;
+                return Some(self.exchange(byte));
+            }
+            None
+        }
+    }
+
+    fn echo(reply: u8) -> Rc<RefCell<Echo>> {
+        Rc::new(RefCell::new(Echo {
+            received: vec![],
+            reply,
+            ready: false,
+        }))
+    }
+
+    #[test]
+    fn test_internal_clock() {
+        let device = echo(0x0F);
+        let mut serial = Serial::new(false);
+        serial.connect(device.clone());
+
+        serial.set_byte(SB, 0xA5);
+        serial.set_byte(SC, 0x81);
+        assert_eq!(serial.get_byte(SC), 0xFF);
+        assert_eq!(device.borrow().received, vec![0xA5]);
+
+        // Four bits in, SB holds the low half of what was sent and the high
+        // half of what was received.
+        serial.tick(BIT_CLOCKS * 4);
+        assert_eq!(serial.get_byte(SB), 0x50);
+        assert!(!serial.request_serial_int);
+
+        serial.tick(BIT_CLOCKS * 4 - 1);
+        assert!(!serial.request_serial_int);
+        serial.tick(1);
+        assert!(serial.request_serial_int);
+        assert_eq!(serial.get_byte(SB), 0x0F);
+        assert_eq!(serial.get_byte(SC), 0x7F);
+    }
+
+    #[test]
+    fn test_fast_clock() {
+        let mut serial = Serial::new(true);
+        serial.set_byte(SB, 0x00);
+        serial.set_byte(SC, 0x83);
+        assert_eq!(serial.get_byte(SC), 0xFF);
+
+        serial.tick(FAST_BIT_CLOCKS * 8);
+        assert!(serial.request_serial_int);
+        // Nothing connected, so only ones come in.
+        assert_eq!(serial.get_byte(SB), 0xFF);
+
+        // The DMG has no fast clock.
+        let mut serial = Serial::new(false);
+        serial.set_byte(SC, 0x83);
+        assert_eq!(serial.get_byte(SC), 0xFF);
+        serial.tick(FAST_BIT_CLOCKS * 8);
+        assert!(!serial.request_serial_int);
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new(false);
+        serial.set_byte(SB, 0x42);
+        serial.set_byte(SC, 0x80);
+
+        // Nobody drives the clock.
+        serial.tick(BIT_CLOCKS * 100);
+        assert!(!serial.request_serial_int);
+        assert_eq!(serial.get_byte(SC), 0xFE);
+
+        let device = echo(0x99);
+        serial.connect(device.clone());
+        serial.tick(4);
+        assert!(!serial.request_serial_int);
+
+        device.borrow_mut().ready = true;
+        serial.tick(4);
+        assert!(serial.request_serial_int);
+        assert_eq!(serial.get_byte(SB), 0x99);
+        assert_eq!(device.borrow().received, vec![0x42]);
+    }
+}
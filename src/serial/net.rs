@@ -0,0 +1,493 @@
+// Link cable over TCP.
+//
+// Each peer runs its own core and the two are kept in lockstep by exchanging
+// cycle timestamps: every message carries the sender's time, and a peer that
+// gets more than `max_skew` clocks ahead of the last time it heard of stalls
+// until the other one catches up. Serial traffic is sent as timestamped
+// events. Starting a transfer on the internal clock waits for the peer to reach
+// the same time, so that whether it is waiting for the transfer is known, and
+// the receiving side completes it once it reaches the time the sender's
+// transfer completed at, or right away if it is already past it.
+//
+// Messages are fixed 10 byte frames: a tag, a little endian u64 and a byte.
+// Both peers start by sending a hello with the protocol version and the CRC-32
+// of their ROM, and drop the connection if they don't match.
+
+use crate::cpu::Cpu;
+use crate::events::Event;
+use crate::serial::link::{SliceEdge, Slices, SLICE};
+use crate::serial::SerialDevice;
+use crate::utils::crc32;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+const VERSION: u32 = 1;
+const FRAME_SIZE: usize = 10;
+
+/// How far ahead of the peer a core may run by default, in clocks.
+pub const DEFAULT_MAX_SKEW: u64 = 2048;
+/// How often the time is sent when there is nothing else to send.
+const SYNC_INTERVAL: u64 = 512;
+/// How long to wait for a stalled peer before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    VersionMismatch { local: u32, remote: u32 },
+    RomMismatch { local: u32, remote: u32 },
+    Protocol(u8),
+    Disconnected,
+    TimedOut,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "{}", e),
+            NetError::VersionMismatch { local, remote } => write!(
+                f,
+                "link protocol version mismatch, ours is {}, the peer's is {}",
+                local, remote
+            ),
+            NetError::RomMismatch { local, remote } => write!(
+                f,
+                "ROM checksum mismatch, ours is {:08X}, the peer's is {:08X}",
+                local, remote
+            ),
+            NetError::Protocol(tag) => write!(f, "unexpected message {:#04X}", tag),
+            NetError::Disconnected => write!(f, "the peer disconnected"),
+            NetError::TimedOut => write!(f, "the peer stopped responding"),
+        }
+    }
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        NetError::Io(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Message {
+    Hello {
+        version: u32,
+        crc: u32,
+    },
+    /// The sender reached this time.
+    Sync(u64),
+    /// The sender waits for an external clock with this byte in SB.
+    Waiting(u64, u8),
+    /// The sender started a transfer on the internal clock with this byte.
+    Transfer(u64, u8),
+    /// The sender's transfer completed.
+    Done(u64),
+}
+
+impl Message {
+    fn encode(self) -> [u8; FRAME_SIZE] {
+        let (tag, value, byte) = match self {
+            Message::Hello { version, crc } => (b'H', (version as u64) << 32 | crc as u64, 0),
+            Message::Sync(time) => (b'S', time, 0),
+            Message::Waiting(time, byte) => (b'W', time, byte),
+            Message::Transfer(time, byte) => (b'T', time, byte),
+            Message::Done(time) => (b'D', time, 0),
+        };
+
+        let mut frame = [0; FRAME_SIZE];
+        frame[0] = tag;
+        frame[1..9].copy_from_slice(&value.to_le_bytes());
+        frame[9] = byte;
+        frame
+    }
+
+    fn decode(frame: [u8; FRAME_SIZE]) -> Result<Self, NetError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(&frame[1..9]);
+        let value = u64::from_le_bytes(value);
+        let byte = frame[9];
+
+        match frame[0] {
+            b'H' => Ok(Message::Hello {
+                version: (value >> 32) as u32,
+                crc: value as u32,
+            }),
+            b'S' => Ok(Message::Sync(value)),
+            b'W' => Ok(Message::Waiting(value, byte)),
+            b'T' => Ok(Message::Transfer(value, byte)),
+            b'D' => Ok(Message::Done(value)),
+            tag => Err(NetError::Protocol(tag)),
+        }
+    }
+
+    fn time(self) -> Option<u64> {
+        match self {
+            Message::Hello { .. } => None,
+            Message::Sync(time)
+            | Message::Waiting(time, _)
+            | Message::Transfer(time, _)
+            | Message::Done(time) => Some(time),
+        }
+    }
+}
+
+/// The link port of the local core, which also holds the connection.
+pub struct NetPort {
+    stream: TcpStream,
+    messages: Receiver<Result<Message, NetError>>,
+    /// How far ahead of the peer the local core may run, in clocks.
+    max_skew: u64,
+    /// The local time at the start of the current slice.
+    now: u64,
+    remote_time: u64,
+    last_sync: u64,
+    outgoing: Vec<Message>,
+    /// SB of the peer while it waits for us to clock a transfer.
+    remote_waiting: Option<u8>,
+    /// SB while we wait for the peer to clock a transfer, as last announced.
+    waiting: Option<u8>,
+    incoming: Option<u8>,
+    /// When the peer's transfer to us completed, if it still has to be
+    /// delivered.
+    done_at: Option<u64>,
+    done: bool,
+    /// The first error hit inside a `SerialDevice` call, which can't return
+    /// it.
+    error: Option<NetError>,
+}
+
+impl NetPort {
+    fn send(&mut self) -> Result<(), NetError> {
+        if self.outgoing.is_empty() && self.now >= self.last_sync + SYNC_INTERVAL {
+            self.outgoing.push(Message::Sync(self.now));
+        }
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+
+        self.last_sync = self.now;
+        let frames: Vec<u8> = self.outgoing.drain(..).flat_map(Message::encode).collect();
+        self.stream.write_all(&frames).map_err(|e| match e.kind() {
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset => NetError::Disconnected,
+            _ => NetError::Io(e),
+        })
+    }
+
+    /// Handles the messages that already arrived.
+    fn poll(&mut self) -> Result<(), NetError> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.receive(message?),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(NetError::Disconnected),
+            }
+        }
+    }
+
+    /// Blocks until the peer has reached `time`.
+    fn wait_for(&mut self, time: u64) -> Result<(), NetError> {
+        if self.remote_time < time {
+            // Tell the peer where we are, or it may be waiting on us too.
+            self.outgoing.push(Message::Sync(self.now));
+            self.send()?;
+        }
+
+        while self.remote_time < time {
+            match self.messages.recv_timeout(TIMEOUT) {
+                Ok(message) => self.receive(message?),
+                Err(RecvTimeoutError::Timeout) => return Err(NetError::TimedOut),
+                Err(RecvTimeoutError::Disconnected) => return Err(NetError::Disconnected),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, message: Message) {
+        if let Some(time) = message.time() {
+            self.remote_time = self.remote_time.max(time);
+        }
+
+        match message {
+            Message::Waiting(_, byte) => self.remote_waiting = Some(byte),
+            Message::Transfer(_, byte) => {
+                self.incoming = Some(byte);
+                self.done = false;
+            }
+            Message::Done(time) => self.done_at = Some(time),
+            Message::Hello { .. } | Message::Sync(_) => (),
+        }
+    }
+
+    /// Starts the slice beginning at `now`.
+    fn begin_slice(&mut self, now: u64) -> Result<(), NetError> {
+        self.now = now;
+        if now > self.max_skew {
+            self.wait_for(now - self.max_skew)?;
+        }
+
+        if let Some(done_at) = self.done_at {
+            if done_at <= now {
+                self.done_at = None;
+                self.done = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn end_slice(&mut self, now: u64) -> Result<(), NetError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        self.now = now;
+        self.send()?;
+        self.poll()
+    }
+}
+
+impl SerialDevice for NetPort {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        // Whether the peer is waiting for us can only be known once it got
+        // this far.
+        if let Err(e) = self.wait_for(self.now) {
+            self.error.get_or_insert(e);
+        }
+
+        self.outgoing.push(Message::Transfer(self.now, byte));
+        self.remote_waiting.take().unwrap_or(0xFF)
+    }
+
+    fn transfer_done(&mut self) {
+        self.outgoing.push(Message::Done(self.now));
+    }
+
+    fn external_clock(&mut self, byte: u8, _cycles: usize) -> Option<u8> {
+        if self.done {
+            self.done = false;
+            self.waiting = None;
+            return self.incoming.take();
+        }
+
+        if self.incoming.is_none() && self.waiting != Some(byte) {
+            self.waiting = Some(byte);
+            self.outgoing.push(Message::Waiting(self.now, byte));
+        }
+        None
+    }
+}
+
+/// Runs the local core linked to a peer over TCP.
+pub struct NetLink {
+    port: Rc<RefCell<NetPort>>,
+    /// Local time at the end of the last slice, in clocks.
+    time: u64,
+    slices: Slices,
+}
+
+impl NetLink {
+    /// Connects to a peer that is waiting in `accept`.
+    pub fn connect(addr: impl ToSocketAddrs, rom: &[u8]) -> Result<Self, NetError> {
+        Self::handshake(TcpStream::connect(addr)?, rom)
+    }
+
+    /// Waits for a peer to `connect`.
+    pub fn accept(listener: &TcpListener, rom: &[u8]) -> Result<Self, NetError> {
+        let (stream, _) = listener.accept()?;
+        Self::handshake(stream, rom)
+    }
+
+    fn handshake(mut stream: TcpStream, rom: &[u8]) -> Result<Self, NetError> {
+        stream.set_nodelay(true)?;
+
+        let crc = crc32(rom);
+        stream.write_all(
+            &Message::Hello {
+                version: VERSION,
+                crc,
+            }
+            .encode(),
+        )?;
+
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        match read_message(&mut stream)? {
+            Message::Hello { version, .. } if version != VERSION => {
+                return Err(NetError::VersionMismatch {
+                    local: VERSION,
+                    remote: version,
+                })
+            }
+            Message::Hello { crc: remote, .. } if remote != crc => {
+                return Err(NetError::RomMismatch { local: crc, remote })
+            }
+            Message::Hello { .. } => (),
+            message => return Err(NetError::Protocol(message.encode()[0])),
+        }
+        stream.set_read_timeout(None)?;
+
+        // Reads happen on their own thread so that the port can poll for
+        // messages and give up on a stalled peer.
+        let (sender, messages) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || loop {
+            let message = read_message(&mut reader);
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                break;
+            }
+        });
+
+        let port = NetPort {
+            stream,
+            messages,
+            max_skew: DEFAULT_MAX_SKEW,
+            now: 0,
+            remote_time: 0,
+            last_sync: 0,
+            outgoing: vec![],
+            remote_waiting: None,
+            waiting: None,
+            incoming: None,
+            done_at: None,
+            done: false,
+            error: None,
+        };
+
+        Ok(Self {
+            port: Rc::new(RefCell::new(port)),
+            time: 0,
+            slices: Slices::default(),
+        })
+    }
+
+    /// Plugs the cable into the serial port of `cpu`.
+    pub fn attach(&self, cpu: &mut Cpu) {
+        cpu.mmu.serial.connect(self.port.clone());
+    }
+
+    /// Sets how many clocks the local core may run ahead of the peer. Smaller
+    /// values keep the two closer together at the cost of more stalls.
+    pub fn set_max_skew(&mut self, max_skew: u64) {
+        self.port.borrow_mut().max_skew = max_skew;
+    }
+
+    /// Like `Cpu::run_till_event`, stalling whenever the local core gets too
+    /// far ahead of the peer.
+    pub fn run_till_event(&mut self, cpu: &mut Cpu, max_cycles: usize) -> Result<Event, NetError> {
+        let port = &self.port;
+        let time = &mut self.time;
+
+        self.slices
+            .run_till_event(cpu, max_cycles, |edge| match edge {
+                SliceEdge::Start => port.borrow_mut().begin_slice(*time),
+                SliceEdge::End => {
+                    *time += SLICE as u64;
+                    port.borrow_mut().end_slice(*time)
+                }
+            })
+    }
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Message, NetError> {
+    let mut frame = [0; FRAME_SIZE];
+    stream.read_exact(&mut frame).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => NetError::Disconnected,
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => NetError::TimedOut,
+        _ => NetError::Io(e),
+    })?;
+    Message::decode(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::link::tests::transfer;
+
+    #[test]
+    fn test_message_round_trip() {
+        for message in [
+            Message::Hello {
+                version: VERSION,
+                crc: 0xDEADBEEF,
+            },
+            Message::Sync(1 << 40),
+            Message::Waiting(3, 0x42),
+            Message::Transfer(4, 0x99),
+            Message::Done(5),
+        ] {
+            assert_eq!(Message::decode(message.encode()).unwrap(), message);
+        }
+        assert!(matches!(
+            Message::decode([b'?'; FRAME_SIZE]),
+            Err(NetError::Protocol(b'?'))
+        ));
+    }
+
+    #[test]
+    fn test_loopback_exchange() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The cores aren't Send, so each peer builds its own. They run
+        // different programs, so the handshake gets the same stand-in ROM.
+        let slave = thread::spawn(move || {
+            let mut cpu = transfer(0, 0x34, 0x80);
+            let mut link = NetLink::connect(addr, b"LINK TEST").unwrap();
+            link.attach(&mut cpu);
+            cpu.mmu.set_byte(0xFF0F, 0);
+            while !matches!(link.run_till_event(&mut cpu, 20000), Ok(Event::MaxCycles)) {}
+            (cpu.mmu.get_byte(0xFF01), cpu.mmu.get_byte(0xFF0F) & 0x08)
+        });
+
+        let mut cpu = transfer(16, 0x12, 0x81);
+        let mut link = NetLink::accept(&listener, b"LINK TEST").unwrap();
+        link.attach(&mut cpu);
+        cpu.mmu.set_byte(0xFF0F, 0);
+        while !matches!(link.run_till_event(&mut cpu, 20000), Ok(Event::MaxCycles)) {}
+        let skew = link.time.abs_diff(link.port.borrow().remote_time);
+        assert!(skew <= DEFAULT_MAX_SKEW + SYNC_INTERVAL);
+        let master = (cpu.mmu.get_byte(0xFF01), cpu.mmu.get_byte(0xFF0F) & 0x08);
+
+        assert_eq!(master, (0x34, 0x08));
+        assert_eq!(slave.join().unwrap(), (0x12, 0x08));
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peer = thread::spawn(move || NetLink::connect(addr, b"TETRIS").err());
+        let result = NetLink::accept(&listener, b"POKEMON RED");
+        assert!(matches!(result, Err(NetError::RomMismatch { .. })));
+        assert!(matches!(
+            peer.join().unwrap(),
+            Some(NetError::RomMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peer = thread::spawn(move || drop(NetLink::connect(addr, b"TETRIS").unwrap()));
+        let mut cpu = transfer(0, 0x00, 0x80);
+        let mut link = NetLink::accept(&listener, b"TETRIS").unwrap();
+        link.attach(&mut cpu);
+        peer.join().unwrap();
+
+        let result = loop {
+            match link.run_till_event(&mut cpu, 20000) {
+                Ok(_) => continue,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(result, NetError::Disconnected));
+    }
+}
@@ -0,0 +1,446 @@
+// Game Boy Printer.
+//
+// The game talks to the printer in packets: the magic bytes 88 33, a command,
+// a compression flag, a 16 bit little endian data length, the data, a 16 bit
+// checksum of everything from the command on, and two zero bytes. The printer
+// answers 00 to everything but those last two, where it sends 81 to say it is
+// there, then its status.
+//
+// Image data arrives in bands of 2 rows of 20 tiles, in the same format as
+// tiles in VRAM, optionally run length encoded. PRINT then puts everything
+// received since the last print on paper. The margins of a print are the paper
+// fed before and after it, so a print with a margin after it ends a page.
+//
+// Reference: https://gbdev.io/pandocs/Gameboy_Printer.html
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod png;
+
+use crate::serial::SerialDevice;
+
+pub const PAPER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PAPER_WIDTH / 8;
+const TILE_SIZE: usize = 16;
+/// The printer's memory holds up to 9 bands.
+const BUFFER_SIZE: usize = 9 * 2 * TILES_PER_ROW * TILE_SIZE;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+const STATUS_PACKET_ERROR: u8 = 0x10;
+
+/// Sent while the printer is connected, in place of the first trailing zero.
+const ALIVE: u8 = 0x81;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// A printed page, 160 pixels wide with one byte of grey per pixel.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PrintedImage {
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedImage {
+    pub fn width(&self) -> usize {
+        PAPER_WIDTH
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode_grey(PAPER_WIDTH, self.height, &self.pixels)
+    }
+}
+
+#[derive(Clone)]
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    status: u8,
+    /// Image data received since the last print.
+    buffer: Vec<u8>,
+    /// The page being printed, until a margin after a print ends it.
+    page: Vec<u8>,
+    pages: Vec<PrintedImage>,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            status: 0,
+            buffer: vec![],
+            page: vec![],
+            pages: vec![],
+        }
+    }
+
+    /// Takes the pages finished so far, oldest first.
+    pub fn take_pages(&mut self) -> Vec<PrintedImage> {
+        std::mem::take(&mut self.pages)
+    }
+
+    /// Ends the current page even though the game didn't feed the paper.
+    pub fn cut(&mut self) {
+        if !self.page.is_empty() {
+            let pixels = std::mem::take(&mut self.page);
+            self.pages.push(PrintedImage {
+                height: pixels.len() / PAPER_WIDTH,
+                pixels,
+            });
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+
+        self.state = match self.state {
+            State::Magic1 if byte == 0x88 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == 0x33 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                match self.length {
+                    0 => State::ChecksumLow,
+                    _ => State::Data,
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                match self.data.len() {
+                    len if len == self.length => State::ChecksumLow,
+                    _ => State::Data,
+                }
+            }
+            State::ChecksumLow => {
+                self.checksum ^= byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum ^= (byte as u16) << 8;
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                // A print is over by the time the game asks again.
+                if self.command == CMD_STATUS {
+                    self.status &= !STATUS_PRINTING;
+                }
+                State::Magic1
+            }
+        };
+
+        reply
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            CMD_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() == 4 => {
+                let margins = self.data[1];
+                // Palette 0 is sent by some games to mean the default one.
+                let palette = match self.data[2] {
+                    0 => 0xE4,
+                    palette => palette,
+                };
+                let exposure = self.data[3] & 0x7F;
+
+                if margins >> 4 != 0 {
+                    self.cut();
+                }
+                let buffer = std::mem::take(&mut self.buffer);
+                rasterize(&buffer, palette, exposure, &mut self.page);
+                if margins & 0x0F != 0 {
+                    self.cut();
+                }
+
+                self.status &= !(STATUS_UNPROCESSED | STATUS_FULL);
+                self.status |= STATUS_PRINTING;
+            }
+            CMD_STATUS => (),
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+}
+
+/// Expands the printer's run length encoding: a control byte with bit 7 set
+/// repeats the next byte (control & 0x7F) + 2 times, otherwise the next
+/// control + 1 bytes are copied.
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7F) + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+/// Draws the tile rows in `buffer` onto `page`. The palette maps colours to
+/// shades like BGP, and the exposure from 0 to 0x7F makes the ink 25% lighter
+/// to 25% darker.
+fn rasterize(buffer: &[u8], palette: u8, exposure: u8, page: &mut Vec<u8>) {
+    let darkness = 0.75 + 0.5 * exposure as f32 / 0x7F as f32;
+    let grey = |shade: u8| 255 - (shade as f32 * 85.0 * darkness).min(255.0) as u8;
+
+    let row_size = TILES_PER_ROW * TILE_SIZE;
+    for row in buffer.chunks_exact(row_size) {
+        for line in 0..8 {
+            for x in 0..PAPER_WIDTH {
+                let tile = &row[(x / 8) * TILE_SIZE..];
+                let bit = 7 - (x % 8);
+                let low = (tile[line * 2] >> bit) & 1;
+                let high = (tile[line * 2 + 1] >> bit) & 1;
+                let color = high << 1 | low;
+                page.push(grey((palette >> (color * 2)) & 0x03));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![command, compressed as u8];
+        body.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        body.extend_from_slice(data);
+        let checksum = body.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+        let mut packet = vec![0x88, 0x33];
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet
+    }
+
+    /// Sends a packet and returns the printer's last two replies.
+    fn send(printer: &mut Printer, packet: &[u8]) -> (u8, u8) {
+        let replies: Vec<_> = packet.iter().map(|&b| printer.exchange(b)).collect();
+        assert!(replies[..replies.len() - 2].iter().all(|&r| r == 0));
+        (replies[replies.len() - 2], replies[replies.len() - 1])
+    }
+
+    /// One band of tiles where every pixel has colour `color`.
+    fn band(color: u8) -> Vec<u8> {
+        let low = if color & 1 != 0 { 0xFF } else { 0x00 };
+        let high = if color & 2 != 0 { 0xFF } else { 0x00 };
+        [low, high].repeat(2 * TILES_PER_ROW * 8)
+    }
+
+    #[test]
+    fn test_print() {
+        let mut printer = Printer::new();
+        assert_eq!(
+            send(&mut printer, &packet(CMD_INIT, false, &[])),
+            (ALIVE, 0)
+        );
+
+        let (_, status) = send(&mut printer, &packet(CMD_DATA, false, &band(3)));
+        assert_eq!(status, STATUS_UNPROCESSED);
+        send(&mut printer, &packet(CMD_DATA, false, &[]));
+
+        // One sheet, no margin before, 3 after, palette E4, normal exposure.
+        let (_, status) = send(
+            &mut printer,
+            &packet(CMD_PRINT, false, &[1, 0x03, 0xE4, 0x40]),
+        );
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(
+            send(&mut printer, &packet(CMD_STATUS, false, &[])),
+            (ALIVE, STATUS_PRINTING)
+        );
+        assert_eq!(
+            send(&mut printer, &packet(CMD_STATUS, false, &[])),
+            (ALIVE, 0)
+        );
+
+        let pages = printer.take_pages();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].height, 16);
+        assert_eq!(pages[0].pixels.len(), PAPER_WIDTH * 16);
+        assert!(pages[0].pixels.iter().all(|&p| p == 0));
+        assert!(printer.take_pages().is_empty());
+    }
+
+    #[test]
+    fn test_pages_and_palette() {
+        let mut printer = Printer::new();
+
+        // Two prints without a margin in between end up on the same page.
+        for palette in [0xE4, 0x1B] {
+            send(&mut printer, &packet(CMD_DATA, false, &band(1)));
+            send(
+                &mut printer,
+                &packet(CMD_PRINT, false, &[1, 0x00, palette, 0x40]),
+            );
+        }
+        assert!(printer.take_pages().is_empty());
+        printer.cut();
+
+        let pages = printer.take_pages();
+        assert_eq!(pages[0].height, 32);
+        // Colour 1 is shade 1 with E4 and shade 2 with 1B.
+        let light = pages[0].pixels[0];
+        let dark = pages[0].pixels[PAPER_WIDTH * 16];
+        assert!(
+            light > dark && light < 255 && dark > 0,
+            "{} {}",
+            light,
+            dark
+        );
+    }
+
+    #[test]
+    fn test_compressed_data() {
+        let mut printer = Printer::new();
+        // Black everywhere: one band of FF, half of it as literal bytes and
+        // half as runs of 64.
+        let mut data = vec![];
+        for _ in 0..TILES_PER_ROW * 4 {
+            data.extend_from_slice(&[0x03, 0xFF, 0xFF, 0xFF, 0xFF]);
+        }
+        for _ in 0..5 {
+            data.extend_from_slice(&[0x80 | 62, 0xFF]);
+        }
+
+        send(&mut printer, &packet(CMD_DATA, true, &data));
+        send(
+            &mut printer,
+            &packet(CMD_PRINT, false, &[1, 0x01, 0xE4, 0x40]),
+        );
+
+        let mut expected = vec![];
+        rasterize(&band(3), 0xE4, 0x40, &mut expected);
+        assert_eq!(printer.take_pages()[0].pixels, expected);
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut out = vec![];
+        decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34, 0x80, 0x55], &mut out);
+        assert_eq!(out, vec![0xAA, 0xAA, 0xAA, 0x12, 0x34, 0x55, 0x55]);
+    }
+
+    #[test]
+    fn test_checksum_error() {
+        let mut printer = Printer::new();
+        let mut bad = packet(CMD_DATA, false, &band(3));
+        let len = bad.len();
+        bad[len - 4] ^= 0x01;
+
+        assert_eq!(send(&mut printer, &bad), (ALIVE, STATUS_CHECKSUM_ERROR));
+        // The data was dropped, and the next good packet clears the error.
+        assert_eq!(
+            send(&mut printer, &packet(CMD_STATUS, false, &[])),
+            (ALIVE, 0)
+        );
+    }
+
+    #[test]
+    fn test_exposure() {
+        let mut light = vec![];
+        let mut dark = vec![];
+        rasterize(&band(1), 0xE4, 0x00, &mut light);
+        rasterize(&band(1), 0xE4, 0x7F, &mut dark);
+        assert!(light[0] > dark[0]);
+    }
+}
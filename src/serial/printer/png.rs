@@ -0,0 +1,108 @@
+// Minimal PNG encoder for greyscale images.
+//
+// The image data is stored uncompressed in deflate "stored" blocks, which
+// keeps the encoder tiny at the cost of file size. Printouts are small, so
+// that doesn't matter much.
+
+use crate::utils::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// The most bytes a stored deflate block can hold.
+const MAX_STORED: usize = 0xFFFF;
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+/// Wraps `data` in a zlib stream of stored blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED).peekable();
+
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Encodes an 8 bit greyscale image, one byte per pixel in rows.
+pub fn encode_grey(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits, greyscale, deflate, no filtering, no interlacing
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    // Every row starts with its filter type, 0 for none.
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encode_grey() {
+        let png = encode_grey(2, 2, &[0x00, 0xFF, 0x80, 0x40]);
+
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..24], [0, 0, 0, 2, 0, 0, 0, 2]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // IDAT holds the rows after their filter byte, stored as is.
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let zlib = &idat[8..8 + 2 + 5 + 6 + 4];
+        assert_eq!(zlib[2..7], [0x01, 0x06, 0x00, 0xF9, 0xFF]);
+        assert_eq!(zlib[7..13], [0x00, 0x00, 0xFF, 0x00, 0x80, 0x40]);
+    }
+
+    #[test]
+    fn test_large_image_is_split_into_blocks() {
+        let pixels = vec![0x55; 300 * 300];
+        let zlib = zlib_stored(&pixels);
+        // Two blocks, each with a 5 byte header.
+        assert_eq!(zlib.len(), 2 + pixels.len() + 2 * 5 + 4);
+        assert_eq!(zlib[2], 0x00);
+        assert_eq!(zlib[2 + 5 + MAX_STORED], 0x01);
+    }
+}
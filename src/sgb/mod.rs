@@ -0,0 +1,625 @@
+// Super Game Boy.
+//
+// The SGB is a DMG running inside a SNES cartridge. The game talks to the SNES
+// with command packets sent through the joypad register, and bulk data is
+// passed by showing it on screen for a frame ("VRAM transfers"). The SNES
+// colourises the Game Boy picture through four palettes picked per 8x8 cell
+// and draws it in the middle of a 256x224 border.
+//
+// Reference: https://gbdev.io/pandocs/SGB_Functions.html
+
+pub mod packet;
+
+use crate::gpu::rgb555;
+use crate::sgb::packet::PacketReceiver;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+const GB_WIDTH: usize = 160;
+const GB_HEIGHT: usize = 144;
+const GB_X: usize = 48;
+const GB_Y: usize = 40;
+const CELLS_X: usize = GB_WIDTH / 8;
+const CELLS_Y: usize = GB_HEIGHT / 8;
+const TRANSFER_SIZE: usize = 0x1000;
+const ATTR_FILE_SIZE: usize = 90;
+const ATTR_FILES: usize = 45;
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Mask {
+    None,
+    /// Keep showing the last frame.
+    Freeze,
+    Black,
+    /// Fill the screen with colour 0.
+    Color0,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    Tiles(usize),
+    Border,
+}
+
+#[derive(Clone)]
+pub struct Sgb {
+    receiver: PacketReceiver,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attr_map: [u8; CELLS_X * CELLS_Y],
+    attr_files: Vec<[u8; ATTR_FILE_SIZE]>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+    players: u8,
+    transfer: Option<Transfer>,
+    lcd: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        let shades = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+        Self {
+            receiver: PacketReceiver::new(),
+            palettes: [shades; 4],
+            system_palettes: vec![shades; SYSTEM_PALETTES],
+            attr_map: [0; CELLS_X * CELLS_Y],
+            attr_files: vec![[0; ATTR_FILE_SIZE]; ATTR_FILES],
+            border_tiles: vec![0; BORDER_TILES * 32],
+            border_map: vec![0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            players: 1,
+            transfer: None,
+            lcd: vec![0; SGB_WIDTH * SGB_HEIGHT * 4],
+        }
+    }
+
+    /// The number of controllers requested with MLT_REQ.
+    pub fn players(&self) -> u8 {
+        self.players
+    }
+
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+
+    pub fn palettes(&self) -> &[[u16; 4]; 4] {
+        &self.palettes
+    }
+
+    pub fn attr_map(&self) -> &[u8] {
+        &self.attr_map
+    }
+
+    /// The 256x224 RGBA picture with the border around the Game Boy screen.
+    pub fn screen(&self) -> *const u8 {
+        self.lcd.as_ptr()
+    }
+
+    pub fn lcd(&self) -> &[u8] {
+        &self.lcd
+    }
+
+    /// Watches writes to the joypad register for command packets. Returns
+    /// whether the write ended a read of the buttons, after which the next
+    /// controller is selected when there are several.
+    pub fn write_joyp(&mut self, value: u8) -> bool {
+        let ends_read = self.receiver.ends_read(value);
+        if let Some(data) = self.receiver.write(value) {
+            self.command(&data);
+        }
+        ends_read
+    }
+
+    fn command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                }
+            }
+            CHR_TRN => self.transfer = Some(Transfer::Tiles((data[1] & 0x01) as usize)),
+            PCT_TRN => self.transfer = Some(Transfer::Border),
+            ATTR_TRN => self.transfer = Some(Transfer::Attributes),
+            ATTR_SET => {
+                self.apply_attr_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            // Sound, SNES code uploads and the like have no Game Boy side
+            // effects.
+            _ => (),
+        }
+    }
+
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]);
+
+        // Colour 0 is shared by all palettes.
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min((data.len() - 2) / 6);
+
+        for set in data[2..2 + sets * 6].chunks(6) {
+            let (control, palettes) = (set[0] & 0x07, set[1]);
+            let inside = palettes & 0x03;
+            let outside = (palettes >> 4) & 0x03;
+            // Changing only one side also changes the border to match.
+            let (border, control) = match control {
+                0x01 => (inside, 0x03),
+                0x04 => (outside, 0x06),
+                _ => ((palettes >> 2) & 0x03, control),
+            };
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let on_border = (x == x1 || x == x2) && (y1..=y2).contains(&y)
+                        || (y == y1 || y == y2) && (x1..=x2).contains(&x);
+                    let is_inside = x > x1 && x < x2 && y > y1 && y < y2;
+
+                    let palette = if on_border {
+                        (control & 0x02 != 0).then_some(border)
+                    } else if is_inside {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else {
+                        (control & 0x04 != 0).then_some(outside)
+                    };
+
+                    if let Some(palette) = palette {
+                        self.attr_map[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(data.len() - 2);
+
+        for &set in &data[2..2 + sets] {
+            let line = (set & 0x1F) as usize;
+            let palette = (set >> 5) & 0x03;
+
+            if set & 0x80 != 0 {
+                if line < CELLS_Y {
+                    self.attr_map[line * CELLS_X..(line + 1) * CELLS_X]
+                        .iter_mut()
+                        .for_each(|p| *p = palette);
+                }
+            } else if line < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attr_map[y * CELLS_X + line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let pos = if horizontal { y } else { x };
+                self.attr_map[y * CELLS_X + x] = match pos {
+                    _ if pos < line => before,
+                    _ if pos == line => on_line,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count.min((data.len() - 6) * 4) {
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+
+            let palette = (data[6 + i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+            self.attr_map[y * CELLS_X + x] = palette;
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let n = u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) as usize;
+            self.palettes[i] = self.system_palettes[n % SYSTEM_PALETTES];
+        }
+        // Like PALxx, the first palette's colour 0 is used by all of them.
+        for i in 1..4 {
+            self.palettes[i][0] = self.palettes[0][0];
+        }
+
+        if data[9] & 0x80 != 0 {
+            self.apply_attr_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: u8) {
+        if let Some(file) = self.attr_files.get(file as usize) {
+            for (i, palette) in self.attr_map.iter_mut().enumerate() {
+                *palette = (file[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+            }
+        }
+    }
+
+    /// Called once per frame with the DMG shades of the frame just drawn.
+    /// Completes pending VRAM transfers and redraws the bordered picture.
+    pub fn frame(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            self.vram_transfer(transfer, &capture(shades));
+        }
+
+        self.render(shades);
+    }
+
+    fn vram_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (i, color) in palette.iter_mut().enumerate() {
+                        *color = u16::from_le_bytes([colors[i * 2], colors[i * 2 + 1]]);
+                    }
+                }
+            }
+            Transfer::Attributes => {
+                for (file, bytes) in self.attr_files.iter_mut().zip(data.chunks(ATTR_FILE_SIZE)) {
+                    file.copy_from_slice(bytes);
+                }
+            }
+            Transfer::Tiles(half) => {
+                let start = half * TRANSFER_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(data);
+            }
+            Transfer::Border => {
+                for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        let offset = 0x800 + i * 32 + j * 2;
+                        *color = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn render(&mut self, shades: &[u8]) {
+        let backdrop = self.palettes[0][0];
+
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let color = self
+                    .border_pixel(x, y)
+                    .or_else(|| self.gb_pixel(x, y, shades));
+                self.set_pixel(x, y, color.unwrap_or(backdrop));
+            }
+        }
+    }
+
+    fn gb_pixel(&self, x: usize, y: usize, shades: &[u8]) -> Option<u16> {
+        if !(GB_X..GB_X + GB_WIDTH).contains(&x) || !(GB_Y..GB_Y + GB_HEIGHT).contains(&y) {
+            return None;
+        }
+        let (x, y) = (x - GB_X, y - GB_Y);
+
+        let color = match self.mask {
+            Mask::Freeze => return self.frozen_pixel(x, y),
+            Mask::Black => 0x0000,
+            Mask::Color0 => self.palettes[0][0],
+            Mask::None => {
+                let palette = self.attr_map[(y / 8) * CELLS_X + x / 8] as usize;
+                self.palettes[palette][shades[y * GB_WIDTH + x] as usize & 0x03]
+            }
+        };
+
+        Some(color)
+    }
+
+    /// Reads back the previous frame's colour, so that freezing keeps it.
+    fn frozen_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let i = ((y + GB_Y) * SGB_WIDTH + x + GB_X) * 4;
+        let (r, g, b) = (
+            self.lcd[i] as u16,
+            self.lcd[i + 1] as u16,
+            self.lcd[i + 2] as u16,
+        );
+        Some((b >> 3) << 10 | (g >> 3) << 5 | (r >> 3))
+    }
+
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07).saturating_sub(4) as usize;
+        let px = if entry & 0x4000 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let py = if entry & 0x8000 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+
+        // SNES 4bpp tiles: bitplanes 0 and 1 interleaved per row, then 2 and 3.
+        let base = tile * 32 + py * 2;
+        let bit = 7 - px;
+        let plane = |offset: usize, n: u8| ((self.border_tiles[base + offset] >> bit) & 1) << n;
+        let index = plane(0, 0) | plane(1, 1) | plane(16, 2) | plane(17, 3);
+
+        if index == 0 {
+            None
+        } else {
+            Some(self.border_palettes[palette & 0x03][index as usize])
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        let (r, g, b) = rgb555(color);
+        let i = (y * SGB_WIDTH + x) * 4;
+        self.lcd[i..i + 4].copy_from_slice(&[r, g, b, 255]);
+    }
+}
+
+/// Turns the first 256 tiles on screen back into 4KB of 2bpp tile data, which
+/// is what the SNES reads during a VRAM transfer.
+fn capture(shades: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+
+    for (tile, bytes) in data.chunks_mut(16).enumerate() {
+        let (tx, ty) = (tile % CELLS_X, tile / CELLS_X);
+        for row in 0..8 {
+            for col in 0..8 {
+                let shade = shades[(ty * 8 + row) * GB_WIDTH + tx * 8 + col];
+                bytes[row * 2] |= (shade & 0x01) << (7 - col);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 0x01) << (7 - col);
+            }
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::rewind::History;
+    use crate::cpu::{Cpu, Model};
+    use crate::sgb::packet::tests::encode;
+
+    fn send(sgb: &mut Sgb, data: &[u8]) {
+        for value in encode(data) {
+            sgb.write_joyp(value);
+        }
+    }
+
+    fn packet(command: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 16];
+        data[0] = command << 3 | 1;
+        data[1..1 + bytes.len()].copy_from_slice(bytes);
+        data
+    }
+
+    fn pixel(sgb: &Sgb, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * SGB_WIDTH + x) * 4;
+        (sgb.lcd[i], sgb.lcd[i + 1], sgb.lcd[i + 2])
+    }
+
+    #[test]
+    fn test_palettes_and_attributes() {
+        let mut sgb = Sgb::new();
+        send(
+            &mut sgb,
+            &packet(PAL01, &[0x00, 0x00, 0x1F, 0x00, 0, 0, 0, 0, 0xE0, 0x03]),
+        );
+        assert_eq!(sgb.palettes()[0][1], 0x001F);
+        assert_eq!(sgb.palettes()[1][1], 0x03E0);
+        assert_eq!(sgb.palettes()[3][0], 0x0000);
+
+        // Palette 1 inside and on the border of cells (2,2)-(4,4).
+        send(&mut sgb, &packet(ATTR_BLK, &[1, 0x01, 0x01, 2, 2, 4, 4]));
+        assert_eq!(sgb.attr_map()[2 * CELLS_X + 2], 1);
+        assert_eq!(sgb.attr_map()[3 * CELLS_X + 3], 1);
+        assert_eq!(sgb.attr_map()[5 * CELLS_X + 5], 0);
+
+        let shades = vec![1; GB_WIDTH * GB_HEIGHT];
+        sgb.frame(&shades);
+        assert_eq!(pixel(&sgb, GB_X, GB_Y), (255, 0, 0));
+        assert_eq!(pixel(&sgb, GB_X + 3 * 8, GB_Y + 3 * 8), (0, 255, 0));
+        assert_eq!(pixel(&sgb, 0, 0), (0, 0, 0));
+
+        send(&mut sgb, &packet(MASK_EN, &[2]));
+        sgb.frame(&shades);
+        assert_eq!(pixel(&sgb, GB_X, GB_Y), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_attr_div_and_lin() {
+        let mut sgb = Sgb::new();
+        // Palette 3 above row 9, 2 on it and 1 below.
+        send(&mut sgb, &packet(ATTR_DIV, &[0x40 | 0x20 | 0x0C | 0x01, 9]));
+        assert_eq!(sgb.attr_map()[8 * CELLS_X], 3);
+        assert_eq!(sgb.attr_map()[9 * CELLS_X], 2);
+        assert_eq!(sgb.attr_map()[10 * CELLS_X], 1);
+
+        // Column 4 gets palette 0.
+        send(&mut sgb, &packet(ATTR_LIN, &[1, 0x04]));
+        assert_eq!(sgb.attr_map()[9 * CELLS_X + 4], 0);
+        assert_eq!(sgb.attr_map()[9 * CELLS_X + 5], 2);
+    }
+
+    #[test]
+    fn test_pal_trn_and_pal_set() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &packet(PAL_TRN, &[]));
+
+        // Make 0x01 the first byte of the transfer, which is the low byte of
+        // colour 0 of system palette 0: shade 1 in the 8th pixel of row 0.
+        let mut shades = vec![0; GB_WIDTH * GB_HEIGHT];
+        for (i, value) in [0x01u8, 0x00].iter().enumerate() {
+            for (bit, shade) in shades[..8].iter_mut().enumerate() {
+                *shade |= ((value >> (7 - bit)) & 1) << i;
+            }
+        }
+        sgb.frame(&shades);
+
+        send(&mut sgb, &packet(PAL_SET, &[0, 0, 0, 0, 0, 0, 0, 0, 0x40]));
+        assert_eq!(sgb.palettes()[0][0], 0x0001);
+        assert_eq!(sgb.palettes()[2][0], 0x0001);
+    }
+
+    #[test]
+    fn test_mlt_req() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &packet(MLT_REQ, &[0x03]));
+        assert_eq!(sgb.players(), 4);
+        send(&mut sgb, &packet(MLT_REQ, &[0x00]));
+        assert_eq!(sgb.players(), 1);
+    }
+
+    /// Sends PAL_TRN and lets the following frame carry the transfer, with
+    /// VRAM blank so that system palette 0 becomes all black.
+    fn pal_trn(cpu: &mut Cpu) {
+        cpu.mmu.set_byte(0xFF40, 0x91);
+        for value in encode(&packet(PAL_TRN, &[])) {
+            cpu.mmu.set_byte(0xFF00, value);
+        }
+    }
+
+    fn pal_set(cpu: &mut Cpu) -> u16 {
+        for value in encode(&packet(PAL_SET, &[0, 0, 0, 0, 0, 0, 0, 0, 0x40])) {
+            cpu.mmu.set_byte(0xFF00, value);
+        }
+        cpu.mmu.sgb.as_ref().unwrap().palettes()[0][0]
+    }
+
+    #[test]
+    fn test_transfers_outside_run_till_event() {
+        let mut cpu = Cpu::with_model(vec![0; 0x8000], Model::Sgb);
+        pal_trn(&mut cpu);
+        cpu.frame();
+        assert_eq!(pal_set(&mut cpu), 0x0000);
+
+        // Going back re-executes the frame from a snapshot taken before the
+        // transfer, which has to happen again.
+        let mut cpu = Cpu::with_model(vec![0; 0x8000], Model::Sgb);
+        pal_trn(&mut cpu);
+        cpu.set_history(Some(History::new(1_000_000, 1_000_000)));
+        cpu.frame();
+        assert!(cpu.step_back());
+        assert_eq!(cpu.history().unwrap().snapshot_count(), 1);
+        assert_eq!(pal_set(&mut cpu), 0x0000);
+    }
+
+    #[test]
+    fn test_joypad_ids() {
+        let mut cpu = Cpu::with_model(vec![0; 0x8000], Model::Sgb);
+        for value in encode(&packet(MLT_REQ, &[0x01])) {
+            cpu.mmu.set_byte(0xFF00, value);
+        }
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0F);
+
+        // Pulsing P15 selects the second controller, then wraps around.
+        cpu.mmu.set_byte(0xFF00, 0x10);
+        cpu.mmu.set_byte(0xFF00, 0x30);
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0E);
+        cpu.mmu.set_byte(0xFF00, 0x10);
+        cpu.mmu.set_byte(0xFF00, 0x30);
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0F);
+
+        // The pulses of another packet don't.
+        for value in encode(&packet(MASK_EN, &[0x01])) {
+            cpu.mmu.set_byte(0xFF00, value);
+        }
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0F);
+        cpu.mmu.set_byte(0xFF00, 0x10);
+        cpu.mmu.set_byte(0xFF00, 0x30);
+        assert_eq!(cpu.mmu.get_byte(0xFF00) & 0x0F, 0x0E);
+    }
+}
@@ -0,0 +1,133 @@
+// SGB command packets.
+//
+// The game sends packets to the SNES by pulsing P14 and P15 through the joypad
+// register. Writing 0 to both starts a packet, a pulse on P15 alone (writing
+// 0x10) is a 1 bit and a pulse on P14 alone (writing 0x20) a 0 bit, each
+// followed by releasing both lines (writing 0x30). A
+// packet is 128 bits sent least significant bit first, then a 0 stop bit. The
+// low 3 bits of the first byte give how many packets make up the command.
+
+pub const PACKET_SIZE: usize = 16;
+
+#[derive(Clone, Default)]
+pub struct PacketReceiver {
+    packet: [u8; PACKET_SIZE],
+    bit: usize,
+    receiving: bool,
+    last: u8,
+    packets: Vec<u8>,
+}
+
+impl PacketReceiver {
+    pub fn new() -> Self {
+        Self {
+            last: 0x30,
+            ..Self::default()
+        }
+    }
+
+    /// Whether writing `value` to the joypad register ends a read of the
+    /// buttons, releasing both lines after selecting P15 alone. Packet bits
+    /// pulse the lines the same way and don't count.
+    pub fn ends_read(&self, value: u8) -> bool {
+        value & 0x30 == 0x30 && self.last == 0x10 && !self.receiving
+    }
+
+    /// Feeds a write to the joypad register. Returns the data of all packets
+    /// of a command once its last packet is complete.
+    pub fn write(&mut self, value: u8) -> Option<Vec<u8>> {
+        let lines = value & 0x30;
+        let last = self.last;
+        self.last = lines;
+
+        match lines {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.bit = 0;
+                self.receiving = true;
+                None
+            }
+            0x10 | 0x20 if last == 0x30 && self.receiving => self.receive_bit(lines == 0x10),
+            _ => None,
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<Vec<u8>> {
+        if self.bit < PACKET_SIZE * 8 {
+            if bit {
+                self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+            }
+            self.bit += 1;
+            return None;
+        }
+
+        self.receiving = false;
+        if bit {
+            // Missing stop bit, drop the whole command.
+            self.packets.clear();
+            return None;
+        }
+
+        self.packets.extend_from_slice(&self.packet);
+        let length = match self.packets[0] & 0x07 {
+            0 => 1,
+            n => n as usize,
+        };
+
+        if self.packets.len() >= length * PACKET_SIZE {
+            Some(std::mem::take(&mut self.packets))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The joypad writes that send `data` as one or more packets.
+    pub fn encode(data: &[u8]) -> Vec<u8> {
+        let mut writes = vec![];
+        for packet in data.chunks(PACKET_SIZE) {
+            writes.extend_from_slice(&[0x00, 0x30]);
+            for i in 0..PACKET_SIZE * 8 {
+                let bit = packet.get(i / 8).map_or(0, |b| (b >> (i % 8)) & 1);
+                writes.extend_from_slice(&[if bit == 1 { 0x10 } else { 0x20 }, 0x30]);
+            }
+            writes.extend_from_slice(&[0x20, 0x30]);
+        }
+        writes
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut data = vec![0; 32];
+        data[0] = 0x04 << 3 | 2;
+        data[1] = 0xA5;
+        data[31] = 0x80;
+
+        let mut receiver = PacketReceiver::new();
+        let mut received = vec![];
+        for value in encode(&data) {
+            // Repeated writes of the same level don't count as new bits.
+            for _ in 0..2 {
+                received.extend(receiver.write(value));
+            }
+        }
+
+        assert_eq!(received, vec![data]);
+    }
+
+    #[test]
+    fn test_missing_stop_bit() {
+        let mut writes = encode(&[0x11 << 3 | 1, 0x01]);
+        let len = writes.len();
+        writes[len - 2] = 0x10;
+
+        let mut receiver = PacketReceiver::new();
+        assert!(writes
+            .into_iter()
+            .all(|value| receiver.write(value).is_none()));
+    }
+}
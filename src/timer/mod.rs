@@ -1,18 +1,26 @@
-use crate::cpu::EmulationMode;
+use crate::cpu::Model;
 
 const COUNTER_SHIFT: [u16; 4] = [9, 3, 5, 7];
 const TRIGGER_CLOCKS: [u16; 4] = [512, 8, 32, 128];
 
+#[derive(Clone)]
 pub struct Divider {
     pub counter: u16,
 }
 
 impl Divider {
-    pub fn new(mode: EmulationMode) -> Self {
+    /// The divider keeps counting while the boot ROM runs, so it starts from
+    /// wherever the model's boot ROM leaves it.
+    pub fn new(model: Model) -> Self {
         Self {
-            counter: match mode {
-                EmulationMode::Dmg => 0xABCC,
-                EmulationMode::Cgb => 0x1EA0,
+            counter: match model {
+                Model::Dmg | Model::Mgb => 0xABCC,
+                // The SGB boot ROM's run time depends on the SNES side and
+                // hasn't been measured, so start like a DMG.
+                Model::Sgb | Model::Sgb2 => 0xABCC,
+                Model::Cgb => 0x1EA0,
+                // One more INC B than the CGB boot ROM.
+                Model::Agb => 0x1EA4,
             },
         }
     }
@@ -30,13 +38,14 @@ impl Divider {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 enum TimerState {
     Reloading,
     Reloaded,
     Running,
 }
 
+#[derive(Clone)]
 pub struct Timer {
     pub acc: u8,          // TIMA
     pub tma: u8,          // TMA
@@ -51,13 +60,13 @@ pub struct Timer {
 }
 
 impl Timer {
-    pub fn new(mode: EmulationMode) -> Self {
+    pub fn new(model: Model) -> Self {
         Self {
             acc: 0,
             tma: 0,
             timer_enable: 0,
             freq: 0,
-            divider: Divider::new(mode),
+            divider: Divider::new(model),
             request_timer_int: false,
             tima_bit: 9,
             state: TimerState::Running,
@@ -193,7 +202,7 @@ mod tests {
 
     #[test]
     fn test_div_trigger() {
-        let mut timer = Timer::new(EmulationMode::Dmg);
+        let mut timer = Timer::new(Model::Dmg);
 
         let mut a = 0;
         let b = 4;
@@ -216,7 +225,7 @@ mod tests {
 
     #[test]
     fn test_timer() {
-        let mut timer = Timer::new(EmulationMode::Dmg);
+        let mut timer = Timer::new(Model::Dmg);
 
         let mut a = 0;
         let b = 4;
@@ -9,3 +9,83 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// CRC-32 (IEEE 802.3) of `data`, as printed by `crc32` and zip tools.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Gives a fieldless enum a lowercase name per variant: `ALL` in the order
+/// listed, `name()`, `Display`, and a case-insensitive `FromStr` whose error
+/// reads "unknown <what> <input>".
+macro_rules! named_enum {
+    ($name:ident, $what:literal, { $($variant:ident => $text:literal),+ $(,)? }) => {
+        impl $name {
+            pub const ALL: [$name; [$($text),+].len()] = [$($name::$variant),+];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $text,)+
+                }
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::ALL
+                    .iter()
+                    .copied()
+                    .find(|value| value.name().eq_ignore_ascii_case(s))
+                    .ok_or_else(|| format!(concat!("unknown ", $what, " {:?}"), s))
+            }
+        }
+    };
+}
+
+pub(crate) use named_enum;
+
+#[cfg(test)]
+mod tests {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    enum Shade {
+        Light,
+        DarkGrey,
+    }
+
+    named_enum!(Shade, "shade", {
+        Light => "light",
+        DarkGrey => "dark-grey",
+    });
+
+    #[test]
+    fn test_named_enum() {
+        assert_eq!(Shade::ALL, [Shade::Light, Shade::DarkGrey]);
+        for shade in Shade::ALL {
+            assert_eq!(shade.to_string().parse(), Ok(shade));
+        }
+        assert_eq!("Dark-Grey".parse(), Ok(Shade::DarkGrey));
+        assert_eq!(
+            "black".parse::<Shade>(),
+            Err("unknown shade \"black\"".to_string())
+        );
+    }
+}
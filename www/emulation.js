@@ -8,6 +8,7 @@ const CHANNELS = 4;
 const EVENT_VBLANK = 0;
 const EVENT_AUDIO_BUFFER_FULL = 1;
 const EVENT_MAX_CYCLES = 2;
+const EVENT_CPU_LOCKED = 3;
 const PIXEL_SIZE = 1;
 
 // const AUDIO_BUFFER_SIZE = 736;
@@ -40,8 +41,14 @@ const audioCtx = new (window.AudioContext || window.webkitAudioContext)();
  **********************************************************/
 
 export class Emulation {
-  start(romData) {
-    this.gb = Emulator.new(romData);
+  start(romData, bootData, bootModel, customBoot) {
+    if (!bootData) {
+      this.gb = Emulator.new(romData);
+    } else if (customBoot) {
+      this.gb = Emulator.with_custom_bootrom(romData, bootData, bootModel);
+    } else {
+      this.gb = Emulator.with_bootrom(romData, bootData, bootModel);
+    }
 
     this.registerKeydownHandler();
     this.registerKeyupHandler();
@@ -87,6 +94,10 @@ export class Emulation {
         this.playAudio();
       }
 
+      if (event == EVENT_CPU_LOCKED) {
+        this.locked = true;
+      }
+
       if (event == EVENT_MAX_CYCLES) {
         break;
       }
@@ -96,6 +107,14 @@ export class Emulation {
   drawScreen() {
     this.imageData &&
       ctx.putImageData(this.imageData, 0, 0, 0, 0, WIDTH, HEIGHT);
+
+    if (this.locked) {
+      ctx.fillStyle = "rgba(0, 0, 0, 0.6)";
+      ctx.fillRect(0, HEIGHT / 2 - 12, WIDTH, 24);
+      ctx.fillStyle = "#ffffff";
+      ctx.textAlign = "center";
+      ctx.fillText("Game crashed", WIDTH / 2, HEIGHT / 2 + 4);
+    }
   }
 
   playAudio() {
@@ -145,78 +164,16 @@ export class Emulation {
 
   registerKeydownHandler() {
     window.addEventListener("keydown", (event) => {
-      switch (event.keyCode) {
-        case 39: {
-          this.gb.keydown(0);
-          break;
-        }
-        case 37: {
-          this.gb.keydown(1);
-          break;
-        }
-        case 38: {
-          this.gb.keydown(2);
-          break;
-        }
-        case 40: {
-          this.gb.keydown(3);
-          break;
-        }
-        case 65: {
-          this.gb.keydown(4);
-          break;
-        }
-        case 83: {
-          this.gb.keydown(5);
-          break;
-        }
-        case 32: {
-          this.gb.keydown(6);
-          break;
-        }
-        case 13: {
-          this.gb.keydown(7);
-          break;
-        }
+      if (this.gb.host_keydown(event.code)) {
+        event.preventDefault();
       }
     });
   }
 
   registerKeyupHandler() {
     window.addEventListener("keyup", (event) => {
-      switch (event.keyCode) {
-        case 39: {
-          this.gb.keyup(0);
-          break;
-        }
-        case 37: {
-          this.gb.keyup(1);
-          break;
-        }
-        case 38: {
-          this.gb.keyup(2);
-          break;
-        }
-        case 40: {
-          this.gb.keyup(3);
-          break;
-        }
-        case 65: {
-          this.gb.keyup(4);
-          break;
-        }
-        case 83: {
-          this.gb.keyup(5);
-          break;
-        }
-        case 32: {
-          this.gb.keyup(6);
-          break;
-        }
-        case 13: {
-          this.gb.keyup(7);
-          break;
-        }
+      if (this.gb.host_keyup(event.code)) {
+        event.preventDefault();
       }
     });
   }